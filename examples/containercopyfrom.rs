@@ -17,7 +17,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .containers()
         .get(&id)
         .copy_from(path::Path::new(&path))
-        .try_concat()
+        .try_fold(Vec::new(), |mut bytes, chunk| async move {
+            bytes.extend_from_slice(&chunk);
+            Ok(bytes)
+        })
         .await?;
 
     let mut archive = Archive::new(&bytes[..]);
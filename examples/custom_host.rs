@@ -4,7 +4,7 @@ use shiplift::Docker;
 async fn main() {
     let docker = Docker::host("http://yourhost".parse().unwrap());
     match docker.ping().await {
-        Ok(pong) => println!("Ping: {}", pong),
+        Ok(pong) => println!("Ping: {:?}", pong),
         Err(e) => eprintln!("Error: {}", e),
     }
 }
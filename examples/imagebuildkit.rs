@@ -0,0 +1,40 @@
+use futures::StreamExt;
+use shiplift::{rep::BuildEvent, BuildOptions, Docker, SessionOptions};
+use std::env;
+
+#[tokio::main]
+async fn main() {
+    let docker = Docker::new();
+    let path = env::args().nth(1).expect("You need to specify a path");
+
+    let options = BuildOptions::builder(path).tag("shiplift_test").build();
+    let mut session = SessionOptions::builder();
+    if let Some(socket) = env::var_os("SSH_AUTH_SOCK") {
+        session.ssh("default", socket);
+    }
+    if let Ok(token) = env::var("NPM_TOKEN") {
+        session.secret("npm", token);
+    }
+
+    let mut stream = docker
+        .images()
+        .build_with_buildkit(&options, &session.build());
+    while let Some(build_result) = stream.next().await {
+        match build_result {
+            Ok(BuildEvent::Trace(status)) => {
+                for vertex in status.vertexes {
+                    println!(
+                        "{}{}",
+                        vertex.name,
+                        if vertex.cached { " CACHED" } else { "" }
+                    );
+                }
+                for log in status.logs {
+                    print!("{}", String::from_utf8_lossy(&log.data));
+                }
+            }
+            Ok(output) => println!("{:?}", output),
+            Err(e) => eprintln!("Error: {}", e),
+        }
+    }
+}
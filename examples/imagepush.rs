@@ -0,0 +1,27 @@
+// cargo run --example imagepush registry.example.com/app:1.0
+// Authenticates with the credentials stored by `docker login`
+
+use futures::StreamExt;
+use shiplift::{Docker, PushOptions};
+use std::env;
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let docker = Docker::new();
+    let img = env::args()
+        .nth(1)
+        .expect("You need to specify an image name");
+
+    let mut stream = docker
+        .images()
+        .get(&img)
+        .push(&PushOptions::builder().build());
+
+    while let Some(push_result) = stream.next().await {
+        match push_result {
+            Ok(output) => println!("{:?}", output),
+            Err(e) => eprintln!("Error: {}", e),
+        }
+    }
+}
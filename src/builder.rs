@@ -1,22 +1,31 @@
 //! Interfaces for building various structures
 
 use crate::{
-    errors::Error,
+    credentials,
+    errors::{ConfigError, Error},
     rep::{EndpointSpec, Mode, NetworkAttachmentConfig, RollbackConfig, TaskSpec, UpdateConfig},
-    Result,
+    tarball::ContextOptions,
+    tls::{Pem, TlsConfig},
+    tty::StreamMode,
+    version::ApiVersion,
+    ClientSettings, Docker, Result, VersionMode,
 };
 use serde::Serialize;
 use serde_json::{self, json, map::Map, Value};
 use std::{
     cmp::Eq,
     collections::{BTreeMap, HashMap},
+    env,
+    future::Future,
     hash::Hash,
     iter::{IntoIterator, Iterator, Peekable},
+    path::PathBuf,
+    sync::Arc,
     time::Duration,
 };
 use url::form_urlencoded;
 
-#[derive(Clone, Serialize, Debug)]
+#[derive(Clone, Serialize, Debug, PartialEq)]
 #[serde(untagged)]
 pub enum RegistryAuth {
     Password {
@@ -115,14 +124,44 @@ impl RegistryAuthBuilder {
 
     pub fn build(&self) -> RegistryAuth {
         RegistryAuth::Password {
-            username: self.username.clone().unwrap_or_else(String::new),
-            password: self.password.clone().unwrap_or_else(String::new),
+            username: self.username.clone().unwrap_or_default(),
+            password: self.password.clone().unwrap_or_default(),
             email: self.email.clone(),
             server_address: self.server_address.clone(),
         }
     }
 }
 
+/// Where the credentials for requests without explicit `auth` come from
+#[derive(Clone, Debug, Default)]
+enum StoredAuth {
+    /// The `config.json` of `$DOCKER_CONFIG` or `~/.docker`
+    #[default]
+    Default,
+    /// The `config.json` in a directory
+    Dir(PathBuf),
+    /// Nowhere, such requests are anonymous
+    Skip,
+}
+
+/// Works out the `X-Registry-Auth` header of a request, preferring explicit credentials over
+/// the ones `docker login` stored for the registry of `image`
+async fn resolve_auth_header(
+    auth: Option<RegistryAuth>,
+    image: Option<String>,
+    stored: StoredAuth,
+) -> Result<Option<String>> {
+    let (image, config_dir) = match (auth, image, stored) {
+        (Some(auth), _, _) => return Ok(Some(auth.serialize())),
+        (None, None, _) | (None, _, StoredAuth::Skip) => return Ok(None),
+        (None, Some(image), StoredAuth::Default) => (image, None),
+        (None, Some(image), StoredAuth::Dir(dir)) => (image, Some(dir)),
+    };
+    Ok(credentials::for_image(image, config_dir)
+        .await?
+        .map(|auth| auth.serialize()))
+}
+
 #[derive(Default, Debug)]
 pub struct TagOptions {
     pub params: HashMap<&'static str, String>,
@@ -186,6 +225,7 @@ impl TagOptionsBuilder {
 #[derive(Default, Debug)]
 pub struct PullOptions {
     auth: Option<RegistryAuth>,
+    stored_auth: StoredAuth,
     params: HashMap<&'static str, String>,
 }
 
@@ -208,14 +248,16 @@ impl PullOptions {
         }
     }
 
-    pub(crate) fn auth_header(&self) -> Option<String> {
-        self.auth.clone().map(|a| a.serialize())
+    pub(crate) fn auth_header(&self) -> impl Future<Output = Result<Option<String>>> + 'static {
+        let image = self.params.get("fromImage").cloned();
+        resolve_auth_header(self.auth.clone(), image, self.stored_auth.clone())
     }
 }
 
 #[derive(Default)]
 pub struct PullOptionsBuilder {
     auth: Option<RegistryAuth>,
+    stored_auth: StoredAuth,
     params: HashMap<&'static str, String>,
 }
 
@@ -273,6 +315,7 @@ impl PullOptionsBuilder {
         self
     }
 
+    /// Credentials for the registry, by default the ones `docker login` stored for it
     pub fn auth(
         &mut self,
         auth: RegistryAuth,
@@ -281,18 +324,149 @@ impl PullOptionsBuilder {
         self
     }
 
+    /// Read the credentials `docker login` stored from the `config.json` in `dir` instead of
+    /// `$DOCKER_CONFIG` or `~/.docker`, as `docker --config` does
+    pub fn auth_from_config_dir<P>(
+        &mut self,
+        dir: P,
+    ) -> &mut Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.stored_auth = StoredAuth::Dir(dir.into());
+        self
+    }
+
+    /// Don't fall back on the credentials `docker login` stored for the image's registry
+    pub fn skip_stored_auth(&mut self) -> &mut Self {
+        self.stored_auth = StoredAuth::Skip;
+        self
+    }
+
     pub fn build(&mut self) -> PullOptions {
         PullOptions {
             auth: self.auth.take(),
+            stored_auth: self.stored_auth.clone(),
             params: self.params.clone(),
         }
     }
 }
 
+#[derive(Default, Debug)]
+pub struct PushOptions {
+    auth: Option<RegistryAuth>,
+    stored_auth: StoredAuth,
+    params: HashMap<&'static str, String>,
+}
+
+impl PushOptions {
+    /// return a new instance of a builder for options
+    pub fn builder() -> PushOptionsBuilder {
+        PushOptionsBuilder::default()
+    }
+
+    /// serialize options as a string. returns None if no options are defined
+    pub fn serialize(&self) -> Option<String> {
+        if self.params.is_empty() {
+            None
+        } else {
+            Some(
+                form_urlencoded::Serializer::new(String::new())
+                    .extend_pairs(&self.params)
+                    .finish(),
+            )
+        }
+    }
+
+    pub(crate) fn auth_header(
+        &self,
+        image: &str,
+    ) -> impl Future<Output = Result<Option<String>>> + 'static {
+        let image = Some(image.to_owned());
+        resolve_auth_header(self.auth.clone(), image, self.stored_auth.clone())
+    }
+}
+
+#[derive(Default)]
+pub struct PushOptionsBuilder {
+    auth: Option<RegistryAuth>,
+    stored_auth: StoredAuth,
+    params: HashMap<&'static str, String>,
+}
+
+impl PushOptionsBuilder {
+    /// Tag of the image to push. If empty, all tags of the image are pushed.
+    pub fn tag<T>(
+        &mut self,
+        t: T,
+    ) -> &mut Self
+    where
+        T: Into<String>,
+    {
+        self.params.insert("tag", t.into());
+        self
+    }
+
+    /// Credentials for the registry, by default the ones `docker login` stored for it
+    pub fn auth(
+        &mut self,
+        auth: RegistryAuth,
+    ) -> &mut Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Read the credentials `docker login` stored from the `config.json` in `dir` instead of
+    /// `$DOCKER_CONFIG` or `~/.docker`, as `docker --config` does
+    pub fn auth_from_config_dir<P>(
+        &mut self,
+        dir: P,
+    ) -> &mut Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.stored_auth = StoredAuth::Dir(dir.into());
+        self
+    }
+
+    /// Don't fall back on the credentials `docker login` stored for the image's registry
+    pub fn skip_stored_auth(&mut self) -> &mut Self {
+        self.stored_auth = StoredAuth::Skip;
+        self
+    }
+
+    pub fn build(&mut self) -> PushOptions {
+        PushOptions {
+            auth: self.auth.take(),
+            stored_auth: self.stored_auth.clone(),
+            params: self.params.clone(),
+        }
+    }
+}
+
+/// How the build context is compressed before it is sent to the daemon
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContextCompression {
+    /// Send a plain tarball, which is fastest when the daemon is local
+    None,
+    /// Gzip the tarball with a level from 0 to 9
+    Gzip(u32),
+}
+
+impl Default for ContextCompression {
+    fn default() -> Self {
+        ContextCompression::Gzip(6)
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct BuildOptions {
     pub path: String,
-    params: HashMap<&'static str, String>,
+    compression: ContextCompression,
+    excludes: Vec<String>,
+    deterministic: bool,
+    params: BTreeMap<&'static str, Vec<String>>,
+    params_json: BTreeMap<&'static str, Value>,
 }
 
 impl BuildOptions {
@@ -307,23 +481,77 @@ impl BuildOptions {
     }
 
     /// serialize options as a string. returns None if no options are defined
+    ///
+    /// parameters may be repeated, like `t` for each tag, and object or list valued parameters
+    /// are sent JSON encoded
     pub fn serialize(&self) -> Option<String> {
-        if self.params.is_empty() {
+        if self.params.is_empty() && self.params_json.is_empty() {
             None
         } else {
-            Some(
-                form_urlencoded::Serializer::new(String::new())
-                    .extend_pairs(&self.params)
-                    .finish(),
-            )
+            let mut serializer = form_urlencoded::Serializer::new(String::new());
+            for (key, values) in &self.params {
+                for value in values {
+                    serializer.append_pair(key, value);
+                }
+            }
+            for (key, value) in &self.params_json {
+                serializer.append_pair(key, &value.to_string());
+            }
+            Some(serializer.finish())
+        }
+    }
+
+    /// How the build context is tarred up
+    pub(crate) fn context_options(&self) -> ContextOptions {
+        ContextOptions {
+            dockerfile: self
+                .params
+                .get("dockerfile")
+                .and_then(|values| values.first())
+                .map(String::as_str)
+                .unwrap_or("Dockerfile")
+                .to_owned(),
+            excludes: self.excludes.clone(),
+            compression: self.compression,
+            deterministic: self.deterministic,
+        }
+    }
+
+    /// serialize options for a BuildKit build through `session`, which serves the directory of
+    /// the Dockerfile on its own
+    #[cfg(feature = "buildkit")]
+    pub(crate) fn serialize_buildkit(
+        &self,
+        session: &str,
+    ) -> String {
+        let dockerfile = self.context_options().dockerfile;
+        let name = std::path::Path::new(&dockerfile).file_name().map_or_else(
+            || dockerfile.clone(),
+            |name| name.to_string_lossy().into_owned(),
+        );
+        let mut params = self.params.clone();
+        params.insert("dockerfile", vec![name]);
+        params.insert("version", vec!["2".to_owned()]);
+        params.insert("session", vec![session.to_owned()]);
+        params.insert("remote", vec!["client-session".to_owned()]);
+        BuildOptions {
+            params,
+            params_json: self.params_json.clone(),
+            ..Default::default()
         }
+        .serialize()
+        .unwrap_or_default()
     }
 }
 
 #[derive(Default)]
 pub struct BuildOptionsBuilder {
     path: String,
-    params: HashMap<&'static str, String>,
+    compression: ContextCompression,
+    excludes: Vec<String>,
+    deterministic: bool,
+    params: BTreeMap<&'static str, Vec<String>>,
+    params_json: BTreeMap<&'static str, Value>,
 }
 
 impl BuildOptionsBuilder {
@@ -339,6 +567,31 @@ impl BuildOptionsBuilder {
         }
     }
 
+    fn param<V>(
+        &mut self,
+        key: &'static str,
+        value: V,
+    ) -> &mut Self
+    where
+        V: ToString,
+    {
+        self.params.insert(key, vec![value.to_string()]);
+        self
+    }
+
+    /// merges `entries` into the JSON object sent as `key`
+    fn param_object(
+        &mut self,
+        key: &'static str,
+        entries: &HashMap<&str, &str>,
+    ) -> &mut Self {
+        let object = self.params_json.entry(key).or_insert_with(|| json!({}));
+        for (k, v) in entries {
+            object[*k] = json!(v);
+        }
+        self
+    }
+
     /// set the name of the docker file. defaults to "DockerFile"
     pub fn dockerfile<P>(
         &mut self,
@@ -347,11 +600,11 @@ impl BuildOptionsBuilder {
     where
         P: Into<String>,
     {
-        self.params.insert("dockerfile", path.into());
-        self
+        self.param("dockerfile", path.into())
     }
 
-    /// tag this image with a name after building it
+    /// tag this image with a name after building it. may be called more than once to apply
+    /// several tags
     pub fn tag<T>(
         &mut self,
         t: T,
@@ -359,7 +612,7 @@ impl BuildOptionsBuilder {
     where
         T: Into<String>,
     {
-        self.params.insert("t", t.into());
+        self.params.entry("t").or_default().push(t.into());
         self
     }
 
@@ -370,8 +623,7 @@ impl BuildOptionsBuilder {
     where
         R: Into<String>,
     {
-        self.params.insert("remote", r.into());
-        self
+        self.param("remote", r.into())
     }
 
     /// don't use the image cache when building image
@@ -379,24 +631,21 @@ impl BuildOptionsBuilder {
         &mut self,
         nc: bool,
     ) -> &mut Self {
-        self.params.insert("nocache", nc.to_string());
-        self
+        self.param("nocache", nc)
     }
 
     pub fn rm(
         &mut self,
         r: bool,
     ) -> &mut Self {
-        self.params.insert("rm", r.to_string());
-        self
+        self.param("rm", r)
     }
 
     pub fn forcerm(
         &mut self,
         fr: bool,
     ) -> &mut Self {
-        self.params.insert("forcerm", fr.to_string());
-        self
+        self.param("forcerm", fr)
     }
 
     /// `bridge`, `host`, `none`, `container:<name|id>`, or a custom network name.
@@ -407,36 +656,199 @@ impl BuildOptionsBuilder {
     where
         T: Into<String>,
     {
-        self.params.insert("networkmode", t.into());
-        self
+        self.param("networkmode", t.into())
     }
 
     pub fn memory(
         &mut self,
         memory: u64,
     ) -> &mut Self {
-        self.params.insert("memory", memory.to_string());
-        self
+        self.param("memory", memory)
+    }
+
+    /// total memory including swap, or -1 for unlimited swap
+    pub fn memswap(
+        &mut self,
+        memswap: i64,
+    ) -> &mut Self {
+        self.param("memswap", memswap)
     }
 
     pub fn cpu_shares(
         &mut self,
         cpu_shares: u32,
     ) -> &mut Self {
-        self.params.insert("cpushares", cpu_shares.to_string());
+        self.param("cpushares", cpu_shares)
+    }
+
+    /// the CPUs the build may use, like `0-3` or `0,1`
+    pub fn cpu_set_cpus<C>(
+        &mut self,
+        cpus: C,
+    ) -> &mut Self
+    where
+        C: Into<String>,
+    {
+        self.param("cpusetcpus", cpus.into())
+    }
+
+    /// the length of a CPU period in microseconds
+    pub fn cpu_period(
+        &mut self,
+        period: u64,
+    ) -> &mut Self {
+        self.param("cpuperiod", period)
+    }
+
+    /// microseconds of CPU time the build may use per CPU period
+    pub fn cpu_quota(
+        &mut self,
+        quota: u64,
+    ) -> &mut Self {
+        self.param("cpuquota", quota)
+    }
+
+    /// values for the `ARG`s of the Dockerfile. may be called more than once
+    pub fn build_args(
+        &mut self,
+        args: &HashMap<&str, &str>,
+    ) -> &mut Self {
+        self.param_object("buildargs", args)
+    }
+
+    /// labels to set on the image. may be called more than once
+    pub fn labels(
+        &mut self,
+        labels: &HashMap<&str, &str>,
+    ) -> &mut Self {
+        self.param_object("labels", labels)
+    }
+
+    /// the stage of a multi-stage Dockerfile to build
+    pub fn target<T>(
+        &mut self,
+        target: T,
+    ) -> &mut Self
+    where
+        T: Into<String>,
+    {
+        self.param("target", target.into())
+    }
+
+    /// the platform to build for, like `linux/arm64`
+    pub fn platform<P>(
+        &mut self,
+        platform: P,
+    ) -> &mut Self
+    where
+        P: Into<String>,
+    {
+        self.param("platform", platform.into())
+    }
+
+    /// images to use as cache sources
+    pub fn cache_from(
+        &mut self,
+        images: Vec<&str>,
+    ) -> &mut Self {
+        self.params_json.insert("cachefrom", json!(images));
         self
     }
 
-    // todo: memswap
-    // todo: cpusetcpus
-    // todo: cpuperiod
-    // todo: cpuquota
-    // todo: buildargs
+    /// entries for `/etc/hosts` of the build containers, in the form `host:ip`
+    pub fn extra_hosts(
+        &mut self,
+        hosts: Vec<&str>,
+    ) -> &mut Self {
+        self.params.insert(
+            "extrahosts",
+            hosts.into_iter().map(ToOwned::to_owned).collect(),
+        );
+        self
+    }
+
+    /// pull newer versions of the base images even when they are present
+    pub fn pull(
+        &mut self,
+        pull: bool,
+    ) -> &mut Self {
+        self.param("pull", pull)
+    }
+
+    /// squash the new layers into a single layer. requires an experimental daemon
+    pub fn squash(
+        &mut self,
+        squash: bool,
+    ) -> &mut Self {
+        self.param("squash", squash)
+    }
+
+    /// size of `/dev/shm` in bytes
+    pub fn shm_size(
+        &mut self,
+        bytes: u64,
+    ) -> &mut Self {
+        self.param("shmsize", bytes)
+    }
+
+    /// add a BuildKit output, like `local` with a `dest` attribute. may be called more than once
+    pub fn output(
+        &mut self,
+        kind: &str,
+        attrs: &HashMap<&str, &str>,
+    ) -> &mut Self {
+        let outputs = self
+            .params_json
+            .entry("outputs")
+            .or_insert_with(|| json!([]));
+        if let Value::Array(outputs) = outputs {
+            outputs.push(json!({ "Type": kind, "Attrs": attrs }));
+        }
+        self
+    }
+
+    /// leave files matching these `.dockerignore` patterns out of the build context, in addition
+    /// to the patterns in the context's own `.dockerignore`
+    pub fn exclude<I, S>(
+        &mut self,
+        patterns: I,
+    ) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.excludes.extend(patterns.into_iter().map(Into::into));
+        self
+    }
+
+    /// tar up the build context reproducibly, with a fixed timestamp and no ownership in the
+    /// headers and only the executable bit of the permissions, so identical files give a
+    /// byte-identical context
+    pub fn deterministic(
+        &mut self,
+        deterministic: bool,
+    ) -> &mut Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// how the build context is compressed. defaults to gzip level 6
+    pub fn compression(
+        &mut self,
+        compression: ContextCompression,
+    ) -> &mut Self {
+        self.compression = compression;
+        self
+    }
 
     pub fn build(&self) -> BuildOptions {
         BuildOptions {
             path: self.path.clone(),
+            compression: self.compression,
+            excludes: self.excludes.clone(),
+            deterministic: self.deterministic,
             params: self.params.clone(),
+            params_json: self.params_json.clone(),
         }
     }
 }
@@ -1031,6 +1443,10 @@ impl ExecContainerOptions {
 
         serde_json::to_string(&body).map_err(Error::from)
     }
+
+    pub(crate) fn stream_mode(&self) -> StreamMode {
+        StreamMode::from_tty(self.params_bool.get("Tty").copied().unwrap_or(false))
+    }
 }
 
 #[derive(Default)]
@@ -1046,10 +1462,7 @@ impl ExecContainerOptionsBuilder {
         cmds: Vec<&str>,
     ) -> &mut Self {
         for cmd in cmds {
-            self.params
-                .entry("Cmd")
-                .or_insert_with(Vec::new)
-                .push(cmd.to_owned());
+            self.params.entry("Cmd").or_default().push(cmd.to_owned());
         }
         self
     }
@@ -1060,10 +1473,7 @@ impl ExecContainerOptionsBuilder {
         envs: Vec<&str>,
     ) -> &mut Self {
         for env in envs {
-            self.params
-                .entry("Env")
-                .or_insert_with(Vec::new)
-                .push(env.to_owned());
+            self.params.entry("Env").or_default().push(env.to_owned());
         }
         self
     }
@@ -1086,6 +1496,15 @@ impl ExecContainerOptionsBuilder {
         self
     }
 
+    /// Allocate a pseudo-TTY, which merges stdout and stderr into raw output
+    pub fn tty(
+        &mut self,
+        tty: bool,
+    ) -> &mut Self {
+        self.params_bool.insert("Tty", tty);
+        self
+    }
+
     pub fn build(&self) -> ExecContainerOptions {
         ExecContainerOptions {
             params: self.params.clone(),
@@ -1241,6 +1660,7 @@ impl EventsOptionsBuilder {
 #[derive(Default, Debug)]
 pub struct LogsOptions {
     params: HashMap<&'static str, String>,
+    mode: Option<StreamMode>,
 }
 
 impl LogsOptions {
@@ -1261,12 +1681,17 @@ impl LogsOptions {
             )
         }
     }
+
+    pub(crate) fn stream_mode(&self) -> Option<StreamMode> {
+        self.mode
+    }
 }
 
 /// Builder interface for `LogsOptions`
 #[derive(Default)]
 pub struct LogsOptionsBuilder {
     params: HashMap<&'static str, String>,
+    mode: Option<StreamMode>,
 }
 
 impl LogsOptionsBuilder {
@@ -1333,9 +1758,20 @@ impl LogsOptionsBuilder {
         self
     }
 
+    /// How the logs are written, which otherwise is found by inspecting whether the container
+    /// has a TTY
+    pub fn stream_mode(
+        &mut self,
+        mode: StreamMode,
+    ) -> &mut Self {
+        self.mode = Some(mode);
+        self
+    }
+
     pub fn build(&self) -> LogsOptions {
         LogsOptions {
             params: self.params.clone(),
+            mode: self.mode,
         }
     }
 }
@@ -1863,6 +2299,7 @@ impl ServiceListOptionsBuilder {
 #[derive(Default, Debug)]
 pub struct ServiceOptions {
     auth: Option<RegistryAuth>,
+    stored_auth: StoredAuth,
     params: HashMap<&'static str, Value>,
 }
 
@@ -1877,14 +2314,20 @@ impl ServiceOptions {
         serde_json::to_string(&self.params).map_err(Error::from)
     }
 
-    pub(crate) fn auth_header(&self) -> Option<String> {
-        self.auth.clone().map(|a| a.serialize())
+    pub(crate) fn auth_header(&self) -> impl Future<Output = Result<Option<String>>> + 'static {
+        let image = self
+            .params
+            .get("TaskTemplate")
+            .and_then(|template| template["ContainerSpec"]["Image"].as_str())
+            .map(String::from);
+        resolve_auth_header(self.auth.clone(), image, self.stored_auth.clone())
     }
 }
 
 #[derive(Default)]
 pub struct ServiceOptionsBuilder {
     auth: Option<RegistryAuth>,
+    stored_auth: StoredAuth,
     params: HashMap<&'static str, Result<Value>>,
 }
 
@@ -1974,6 +2417,8 @@ impl ServiceOptionsBuilder {
         self
     }
 
+    /// Credentials for the registry of the task template's image, by default the ones
+    /// `docker login` stored for it
     pub fn auth(
         &mut self,
         auth: RegistryAuth,
@@ -1982,6 +2427,25 @@ impl ServiceOptionsBuilder {
         self
     }
 
+    /// Read the credentials `docker login` stored from the `config.json` in `dir` instead of
+    /// `$DOCKER_CONFIG` or `~/.docker`, as `docker --config` does
+    pub fn auth_from_config_dir<P>(
+        &mut self,
+        dir: P,
+    ) -> &mut Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.stored_auth = StoredAuth::Dir(dir.into());
+        self
+    }
+
+    /// Don't fall back on the credentials `docker login` stored for the registry of the task template's image
+    pub fn skip_stored_auth(&mut self) -> &mut Self {
+        self.stored_auth = StoredAuth::Skip;
+        self
+    }
+
     pub fn build(&mut self) -> Result<ServiceOptions> {
         let params = std::mem::take(&mut self.params);
         let mut new_params = HashMap::new();
@@ -1990,11 +2454,272 @@ impl ServiceOptionsBuilder {
         }
         Ok(ServiceOptions {
             auth: self.auth.take(),
+            stored_auth: self.stored_auth.clone(),
             params: new_params,
         })
     }
 }
 
+/// Interface for configuring how a [`Docker`] instance connects to the daemon
+///
+/// # examples
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// let docker = shiplift::Docker::builder()
+///     .host("tcp://10.0.0.2:2376")
+///     .connect_timeout(Duration::from_secs(5))
+///     .timeout(Duration::from_secs(30))
+///     .user_agent("deployer/1.0")
+///     .tls_ca_path("/etc/docker/ca.pem")
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Default)]
+pub struct DockerBuilder {
+    host: Option<String>,
+    api_version: Option<VersionMode>,
+    settings: ClientSettings,
+    timeout: Option<Duration>,
+    user_agent: Option<String>,
+    headers: Vec<(&'static str, String)>,
+    tls: TlsConfig,
+    tls_dir: Option<PathBuf>,
+}
+
+impl DockerBuilder {
+    /// The daemon address in `DOCKER_HOST` form, e.g. `unix:///var/run/docker.sock`,
+    /// `tcp://10.0.0.2:2376` or `ssh://user@host`. Defaults to `DOCKER_HOST` or the default
+    /// socket.
+    pub fn host<S>(
+        &mut self,
+        host: S,
+    ) -> &mut Self
+    where
+        S: Into<String>,
+    {
+        self.host = Some(host.into());
+        self
+    }
+
+    /// Connect to the daemon through a unix socket
+    pub fn unix<P>(
+        &mut self,
+        socket_path: P,
+    ) -> &mut Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.host = Some(format!("unix://{}", socket_path.into().display()));
+        self
+    }
+
+    /// Pin the Engine API version, see [`Docker::with_api_version`]
+    pub fn api_version(
+        &mut self,
+        version: ApiVersion,
+    ) -> &mut Self {
+        self.api_version = Some(VersionMode::Pinned(version));
+        self
+    }
+
+    /// Negotiate the Engine API version with the daemon, see
+    /// [`Docker::with_negotiated_api_version`]
+    pub fn negotiate_api_version(&mut self) -> &mut Self {
+        self.api_version = Some(VersionMode::Negotiated(Arc::default()));
+        self
+    }
+
+    /// Time limit for establishing tcp connections
+    pub fn connect_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> &mut Self {
+        self.settings.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Time limit for requests with a single response. Streaming endpoints such as logs,
+    /// events or attach are not cut off once the daemon starts responding.
+    pub fn timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Maximum number of idle connections kept open for reuse
+    pub fn pool_max_idle_per_host(
+        &mut self,
+        max: usize,
+    ) -> &mut Self {
+        self.settings.pool_max_idle_per_host = Some(max);
+        self
+    }
+
+    /// The `User-Agent` header, `shiplift/<version>` by default
+    pub fn user_agent<S>(
+        &mut self,
+        user_agent: S,
+    ) -> &mut Self
+    where
+        S: Into<String>,
+    {
+        self.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Adds a header sent with every request
+    pub fn header<S>(
+        &mut self,
+        name: &'static str,
+        value: S,
+    ) -> &mut Self
+    where
+        S: Into<String>,
+    {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    /// CA certificates used to verify the daemon, as a PEM file
+    pub fn tls_ca_path<P>(
+        &mut self,
+        path: P,
+    ) -> &mut Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.tls.ca = Some(Pem::File(path.into()));
+        self
+    }
+
+    /// CA certificates used to verify the daemon, PEM encoded
+    pub fn tls_ca_pem<B>(
+        &mut self,
+        pem: B,
+    ) -> &mut Self
+    where
+        B: Into<Vec<u8>>,
+    {
+        self.tls.ca = Some(Pem::Memory(pem.into()));
+        self
+    }
+
+    /// Client certificate presented to the daemon, as a PEM file
+    pub fn tls_cert_path<P>(
+        &mut self,
+        path: P,
+    ) -> &mut Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.tls.cert = Some(Pem::File(path.into()));
+        self
+    }
+
+    /// Client certificate presented to the daemon, PEM encoded
+    pub fn tls_cert_pem<B>(
+        &mut self,
+        pem: B,
+    ) -> &mut Self
+    where
+        B: Into<Vec<u8>>,
+    {
+        self.tls.cert = Some(Pem::Memory(pem.into()));
+        self
+    }
+
+    /// Private key of the client certificate, as a PEM file
+    pub fn tls_key_path<P>(
+        &mut self,
+        path: P,
+    ) -> &mut Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.tls.key = Some(Pem::File(path.into()));
+        self
+    }
+
+    /// Private key of the client certificate, PEM encoded
+    pub fn tls_key_pem<B>(
+        &mut self,
+        pem: B,
+    ) -> &mut Self
+    where
+        B: Into<Vec<u8>>,
+    {
+        self.tls.key = Some(Pem::Memory(pem.into()));
+        self
+    }
+
+    /// Uses whichever of `ca.pem`, `cert.pem` and `key.pem` exist in `dir`, like
+    /// `DOCKER_CERT_PATH`. Certificates set before are replaced, ones set after take precedence
+    /// over the files in `dir`.
+    pub fn tls_cert_dir<P>(
+        &mut self,
+        dir: P,
+    ) -> &mut Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.tls = TlsConfig {
+            skip_verify: self.tls.skip_verify,
+            ..TlsConfig::default()
+        };
+        self.tls_dir = Some(dir.into());
+        self
+    }
+
+    /// Don't verify the daemon's certificate
+    pub fn tls_skip_verify(&mut self) -> &mut Self {
+        self.tls.skip_verify = true;
+        self
+    }
+
+    /// Connects to the daemon
+    ///
+    /// Without any TLS material, `DOCKER_CERT_PATH` and `DOCKER_TLS_VERIFY` decide whether tcp
+    /// connections are encrypted. Fails with [`ConfigError::MissingCertificate`] if the
+    /// directory given to [`tls_cert_dir`](DockerBuilder::tls_cert_dir) holds none of the
+    /// certificates.
+    pub fn build(&self) -> std::result::Result<Docker, ConfigError> {
+        let tls = match &self.tls_dir {
+            Some(dir) => {
+                let from_dir = TlsConfig::from_dir(dir, self.tls.skip_verify)
+                    .ok_or_else(|| ConfigError::MissingCertificate(dir.join("cert.pem")))?;
+                Some(TlsConfig {
+                    ca: self.tls.ca.clone().or(from_dir.ca),
+                    cert: self.tls.cert.clone().or(from_dir.cert),
+                    key: self.tls.key.clone().or(from_dir.key),
+                    skip_verify: self.tls.skip_verify,
+                })
+            }
+            None if self.tls.is_empty() && !self.tls.skip_verify => TlsConfig::from_env(),
+            None => Some(self.tls.clone()),
+        };
+        let host = self
+            .host
+            .clone()
+            .or_else(|| env::var("DOCKER_HOST").ok())
+            .unwrap_or_else(|| "unix:///var/run/docker.sock".to_owned());
+        let mut docker = Docker::from_host_str(&host, tls, &self.settings)?;
+
+        if let Some(version) = &self.api_version {
+            docker.api_version = version.clone();
+        }
+        docker.timeout = self.timeout;
+        if let Some(user_agent) = &self.user_agent {
+            docker.headers = vec![("User-Agent", user_agent.clone())];
+        }
+        docker.headers.extend(self.headers.iter().cloned());
+        Ok(docker)
+    }
+}
+
 fn to_json_value<T>(value: T) -> Result<Value>
 where
     T: Serialize,
@@ -2006,7 +2731,39 @@ where
 
 #[cfg(test)]
 mod tests {
-    use super::{ContainerOptionsBuilder, LogsOptionsBuilder, RegistryAuth};
+    use super::{BuildOptions, ContainerOptionsBuilder, LogsOptionsBuilder, RegistryAuth};
+    use std::collections::HashMap;
+
+    #[test]
+    fn build_options_serialize() {
+        assert_eq!(None, BuildOptions::builder(".").build().serialize());
+
+        let mut args = HashMap::new();
+        args.insert("VERSION", "1.0");
+        let mut dest = HashMap::new();
+        dest.insert("dest", "out");
+        let options = BuildOptions::builder(".")
+            .tag("app:1.0")
+            .tag("app:latest")
+            .build_args(&args)
+            .target("release")
+            .extra_hosts(vec!["db:10.0.0.2", "cache:10.0.0.3"])
+            .cache_from(vec!["app:cache"])
+            .output("local", &dest)
+            .pull(true)
+            .build();
+
+        assert_eq!(
+            concat!(
+                "extrahosts=db%3A10.0.0.2&extrahosts=cache%3A10.0.0.3&pull=true",
+                "&t=app%3A1.0&t=app%3Alatest&target=release",
+                "&buildargs=%7B%22VERSION%22%3A%221.0%22%7D",
+                "&cachefrom=%5B%22app%3Acache%22%5D",
+                "&outputs=%5B%7B%22Attrs%22%3A%7B%22dest%22%3A%22out%22%7D%2C%22Type%22%3A%22local%22%7D%5D",
+            ),
+            options.serialize().unwrap()
+        );
+    }
 
     #[test]
     fn container_options_simple() {
@@ -2203,8 +2960,7 @@ mod tests {
     #[cfg(feature = "chrono")]
     #[test]
     fn logs_options() {
-        let timestamp = chrono::NaiveDateTime::from_timestamp(2_147_483_647, 0);
-        let since = chrono::DateTime::<chrono::Utc>::from_utc(timestamp, chrono::Utc);
+        let since = chrono::DateTime::<chrono::Utc>::from_timestamp(2_147_483_647, 0).unwrap();
 
         let options = LogsOptionsBuilder::default()
             .follow(true)
@@ -0,0 +1,76 @@
+//! Docker CLI configuration, as found in `~/.docker/config.json`
+
+use crate::errors::ConfigError;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Returns the docker CLI configuration directory, `$DOCKER_CONFIG` or `~/.docker`
+pub fn config_dir() -> Option<PathBuf> {
+    match env::var_os("DOCKER_CONFIG") {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => env::var_os("HOME").map(|home| Path::new(&home).join(".docker")),
+    }
+}
+
+/// The contents of the docker CLI's `config.json`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigFile {
+    /// The context selected with `docker context use`
+    pub current_context: Option<String>,
+    /// Credentials saved by `docker login`, keyed by registry address
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub auths: HashMap<String, AuthConfig>,
+    /// The credential helper used for every registry without an entry in `cred_helpers`
+    pub creds_store: Option<String>,
+    /// Credential helpers to use for specific registries
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub cred_helpers: HashMap<String, String>,
+}
+
+/// An entry of the `auths` section of `config.json`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AuthConfig {
+    /// base64 encoded `username:password`
+    pub auth: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub email: Option<String>,
+    pub identitytoken: Option<String>,
+}
+
+impl ConfigFile {
+    /// Loads `config.json` from the [configuration directory](config_dir)
+    ///
+    /// A missing file yields the default, empty configuration.
+    pub fn load() -> Result<ConfigFile, ConfigError> {
+        match config_dir() {
+            Some(dir) => ConfigFile::load_from(dir.join("config.json")),
+            None => Ok(ConfigFile::default()),
+        }
+    }
+
+    /// Loads a configuration file from `path`, yielding the default configuration if it does
+    /// not exist
+    pub fn load_from<P>(path: P) -> Result<ConfigFile, ConfigError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|source| ConfigError::Parse {
+                path: path.to_owned(),
+                source,
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(ConfigFile::default()),
+            Err(source) => Err(ConfigError::Read {
+                path: path.to_owned(),
+                source,
+            }),
+        }
+    }
+}
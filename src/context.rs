@@ -0,0 +1,265 @@
+//! Docker CLI contexts, as managed with `docker context`
+//!
+//! Contexts live in the `contexts` folder of the [configuration directory](crate::config::config_dir).
+//! Each one has a `meta/<id>/meta.json` file describing its endpoints and may have TLS material
+//! in `tls/<id>/docker/`.
+
+use crate::{
+    config::{config_dir, ConfigFile},
+    errors::ConfigError,
+    tls::TlsConfig,
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// The name of the implicit context that uses `DOCKER_HOST` or the default socket
+pub const DEFAULT_CONTEXT: &str = "default";
+
+/// The docker endpoint of a named context
+#[derive(Clone, Debug, PartialEq)]
+pub struct Context {
+    pub name: String,
+    /// The daemon address, e.g. `unix:///var/run/docker.sock` or `tcp://10.0.0.2:2376`
+    pub host: String,
+    pub skip_tls_verify: bool,
+    /// The directory holding the context's `ca.pem`, `cert.pem` and `key.pem`, if any
+    pub tls_path: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Meta {
+    name: String,
+    #[serde(default)]
+    endpoints: HashMap<String, EndpointMeta>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct EndpointMeta {
+    host: Option<String>,
+    #[serde(rename = "SkipTLSVerify", default)]
+    skip_tls_verify: bool,
+}
+
+impl Context {
+    /// Returns the name of the selected context, from `DOCKER_CONTEXT` or the `currentContext`
+    /// of `config.json`
+    pub fn current() -> Result<Option<String>, ConfigError> {
+        if let Some(name) = env::var("DOCKER_CONTEXT").ok().filter(|n| !n.is_empty()) {
+            return Ok(Some(name));
+        }
+        Ok(ConfigFile::load()?
+            .current_context
+            .filter(|n| !n.is_empty()))
+    }
+
+    /// Loads a context from the configuration directory
+    pub fn load(name: &str) -> Result<Context, ConfigError> {
+        let dir = config_dir().ok_or_else(|| ConfigError::ContextNotFound(name.to_owned()))?;
+        Context::load_from(dir, name)
+    }
+
+    /// Loads a context from the given configuration directory
+    pub fn load_from<P>(
+        config_dir: P,
+        name: &str,
+    ) -> Result<Context, ConfigError>
+    where
+        P: AsRef<Path>,
+    {
+        let contexts = config_dir.as_ref().join("contexts");
+        let meta_dir = contexts.join("meta");
+        let read_error = |source| ConfigError::Read {
+            path: meta_dir.clone(),
+            source,
+        };
+
+        let entries = match fs::read_dir(&meta_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(ConfigError::ContextNotFound(name.to_owned()))
+            }
+            Err(e) => return Err(read_error(e)),
+        };
+
+        // context folders are named after a digest of the context name, so rather than
+        // hashing the name we look for the meta.json that carries it. A file that can't be
+        // parsed may belong to any context, so it only fails lookups that find no match
+        let mut parse_error = None;
+        for entry in entries {
+            let entry = entry.map_err(read_error)?;
+            let path = entry.path().join("meta.json");
+            let bytes = match fs::read(&path) {
+                Ok(bytes) => bytes,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(source) => return Err(ConfigError::Read { path, source }),
+            };
+            let meta: Meta = match serde_json::from_slice(&bytes) {
+                Ok(meta) => meta,
+                Err(source) => {
+                    parse_error.get_or_insert(ConfigError::Parse { path, source });
+                    continue;
+                }
+            };
+            if meta.name != name {
+                continue;
+            }
+
+            let endpoint = meta
+                .endpoints
+                .get("docker")
+                .ok_or_else(|| ConfigError::MissingEndpoint(name.to_owned()))?;
+            let host = endpoint
+                .host
+                .clone()
+                .ok_or_else(|| ConfigError::MissingEndpoint(name.to_owned()))?;
+            let tls_path = contexts.join("tls").join(entry.file_name()).join("docker");
+
+            return Ok(Context {
+                name: meta.name,
+                host,
+                skip_tls_verify: endpoint.skip_tls_verify,
+                tls_path: Some(tls_path).filter(|p| p.is_dir()),
+            });
+        }
+
+        Err(parse_error.unwrap_or_else(|| ConfigError::ContextNotFound(name.to_owned())))
+    }
+
+    /// The TLS settings for connecting to the context's daemon, `None` for plain connections
+    ///
+    /// Like the docker CLI, a context that skips verification uses TLS even without any
+    /// certificates of its own.
+    pub(crate) fn tls(&self) -> Option<TlsConfig> {
+        let tls = self
+            .tls_path
+            .as_deref()
+            .and_then(|dir| TlsConfig::from_dir(dir, self.skip_tls_verify));
+        match tls {
+            None if self.skip_tls_verify => Some(TlsConfig {
+                skip_verify: true,
+                ..TlsConfig::default()
+            }),
+            tls => tls,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::Pem;
+
+    fn config_dir(test: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("shiplift-context-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn write_context(
+        dir: &Path,
+        id: &str,
+        meta: &str,
+    ) {
+        let meta_dir = dir.join("contexts/meta").join(id);
+        fs::create_dir_all(&meta_dir).unwrap();
+        fs::write(meta_dir.join("meta.json"), meta).unwrap();
+    }
+
+    #[test]
+    fn load_context() {
+        let dir = config_dir("load");
+        write_context(
+            &dir,
+            "a1",
+            r#"{"Name":"local","Metadata":{},"Endpoints":{"docker":{"Host":"unix:///tmp/docker.sock","SkipTLSVerify":false}}}"#,
+        );
+        write_context(
+            &dir,
+            "b2",
+            r#"{"Name":"remote","Metadata":{},"Endpoints":{"docker":{"Host":"tcp://10.0.0.2:2376","SkipTLSVerify":true}}}"#,
+        );
+        write_context(
+            &dir,
+            "c3",
+            r#"{"Name":"insecure","Metadata":{},"Endpoints":{"docker":{"Host":"tcp://10.0.0.3","SkipTLSVerify":true}}}"#,
+        );
+        let tls = dir.join("contexts/tls/b2/docker");
+        fs::create_dir_all(&tls).unwrap();
+        fs::write(tls.join("ca.pem"), "").unwrap();
+
+        let local = Context::load_from(&dir, "local").unwrap();
+        assert_eq!(local.host, "unix:///tmp/docker.sock");
+        assert_eq!(local.tls_path, None);
+
+        let remote = Context::load_from(&dir, "remote").unwrap();
+        assert_eq!(
+            remote,
+            Context {
+                name: "remote".to_owned(),
+                host: "tcp://10.0.0.2:2376".to_owned(),
+                skip_tls_verify: true,
+                tls_path: Some(tls.clone()),
+            }
+        );
+        let remote_tls = remote.tls().unwrap();
+        assert!(remote_tls.skip_verify);
+        assert!(matches!(remote_tls.ca, Some(Pem::File(ca)) if ca == tls.join("ca.pem")));
+        assert!(local.tls().is_none());
+
+        // skipping verification without certificates still asks for TLS
+        let insecure = Context::load_from(&dir, "insecure").unwrap();
+        assert_eq!(insecure.tls_path, None);
+        let insecure_tls = insecure.tls().unwrap();
+        assert!(insecure_tls.skip_verify && insecure_tls.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skip_corrupt_contexts() {
+        let dir = config_dir("corrupt");
+        write_context(&dir, "d4", "{not json");
+        write_context(
+            &dir,
+            "e5",
+            r#"{"Name":"local","Metadata":{},"Endpoints":{"docker":{"Host":"unix:///tmp/docker.sock"}}}"#,
+        );
+
+        let local = Context::load_from(&dir, "local").unwrap();
+        assert_eq!(local.host, "unix:///tmp/docker.sock");
+        // the corrupt file may be the missing context
+        assert!(matches!(
+            Context::load_from(&dir, "other"),
+            Err(ConfigError::Parse { path, .. }) if path.ends_with("d4/meta.json")
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn load_missing_context() {
+        let dir = config_dir("missing");
+        assert!(matches!(
+            Context::load_from(&dir, "nope"),
+            Err(ConfigError::ContextNotFound(name)) if name == "nope"
+        ));
+
+        write_context(
+            &dir,
+            "c3",
+            r#"{"Name":"k8s","Metadata":{},"Endpoints":{"kubernetes":{}}}"#,
+        );
+        assert!(matches!(
+            Context::load_from(&dir, "k8s"),
+            Err(ConfigError::MissingEndpoint(_))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,366 @@
+//! Registry credentials stored by `docker login`
+//!
+//! Credentials are looked up the same way the docker CLI does: a registry specific
+//! `credHelpers` entry wins over the global `credsStore`, which wins over the plain `auths`
+//! stored in `config.json`. Helpers are external `docker-credential-<name>` programs speaking
+//! the [credential helper protocol](https://github.com/docker/docker-credential-helpers).
+
+use crate::{
+    builder::RegistryAuth,
+    config::{AuthConfig, ConfigFile},
+    errors::ConfigError,
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+/// The address the docker CLI stores Docker Hub credentials under
+pub const DOCKER_HUB: &str = "https://index.docker.io/v1/";
+
+/// Username a credential helper returns when the secret is an identity token
+const TOKEN_USERNAME: &str = "<token>";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperCredentials {
+    username: String,
+    secret: String,
+}
+
+/// Returns the registry an image reference is pulled from, as keyed in `config.json`
+///
+/// References without a registry host, such as `busybox` or `library/busybox:1.33`, resolve to
+/// Docker Hub.
+pub fn registry_for_image(image: &str) -> &str {
+    match image.split_once('/') {
+        Some((host, _)) if host.contains('.') || host.contains(':') || host == "localhost" => {
+            match host {
+                "docker.io" | "index.docker.io" | "registry-1.docker.io" => DOCKER_HUB,
+                _ => host,
+            }
+        }
+        _ => DOCKER_HUB,
+    }
+}
+
+/// Strips the scheme and path off a registry address, e.g. `https://quay.io/v1/` becomes
+/// `quay.io`
+fn hostname(address: &str) -> &str {
+    let address = address
+        .strip_prefix("https://")
+        .or_else(|| address.strip_prefix("http://"))
+        .unwrap_or(address);
+    address.split('/').next().unwrap_or(address)
+}
+
+/// Looks up the entry for `registry`, falling back on the entries for the same host
+///
+/// Of several addresses of the same host, such as `https://index.docker.io/v1/` and
+/// `index.docker.io`, the first in sort order is used so the choice doesn't vary between runs.
+fn by_hostname<'a, V>(
+    entries: &'a HashMap<String, V>,
+    registry: &str,
+) -> Option<(&'a String, &'a V)> {
+    entries.get_key_value(registry).or_else(|| {
+        entries
+            .iter()
+            .filter(|(address, _)| hostname(address) == hostname(registry))
+            .min_by_key(|(address, _)| *address)
+    })
+}
+
+impl ConfigFile {
+    /// Looks up the stored credentials for `registry`, running credential helpers if the
+    /// configuration asks for them
+    ///
+    /// Returns `None` if there are no credentials for the registry.
+    pub fn credentials(
+        &self,
+        registry: &str,
+    ) -> Result<Option<RegistryAuth>, ConfigError> {
+        let helper = by_hostname(&self.cred_helpers, registry)
+            .map(|(_, helper)| helper)
+            .or(self.creds_store.as_ref())
+            .filter(|helper| !helper.is_empty());
+        match helper {
+            Some(helper) => helper_credentials(helper, registry),
+            None => self.stored_credentials(registry),
+        }
+    }
+
+    /// Looks up the credentials for `registry` in `auths`
+    fn stored_credentials(
+        &self,
+        registry: &str,
+    ) -> Result<Option<RegistryAuth>, ConfigError> {
+        match by_hostname(&self.auths, registry) {
+            Some((address, auth)) => auth.to_registry_auth(address).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl AuthConfig {
+    fn to_registry_auth(
+        &self,
+        address: &str,
+    ) -> Result<RegistryAuth, ConfigError> {
+        if let Some(token) = self.identitytoken.as_ref().filter(|t| !t.is_empty()) {
+            return Ok(RegistryAuth::token(token.as_str()));
+        }
+        let (username, password) = match self.auth.as_ref().filter(|a| !a.is_empty()) {
+            Some(auth) => base64::decode(auth)
+                .ok()
+                .and_then(|decoded| String::from_utf8(decoded).ok())
+                .and_then(|decoded| {
+                    decoded
+                        .split_once(':')
+                        .map(|(user, pass)| (user.to_owned(), pass.to_owned()))
+                })
+                .ok_or_else(|| ConfigError::InvalidAuth(address.to_owned()))?,
+            None => (
+                self.username.clone().unwrap_or_default(),
+                self.password.clone().unwrap_or_default(),
+            ),
+        };
+        let mut builder = RegistryAuth::builder();
+        builder
+            .username(username)
+            .password(password)
+            .server_address(address);
+        if let Some(email) = &self.email {
+            builder.email(email.as_str());
+        }
+        Ok(builder.build())
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    /// The `PATH` helpers are looked up in by the tests of this thread
+    static HELPER_PATH: std::cell::RefCell<Option<std::ffi::OsString>> = Default::default();
+}
+
+/// Runs `docker-credential-<helper> get` for `registry`
+fn helper_credentials(
+    helper: &str,
+    registry: &str,
+) -> Result<Option<RegistryAuth>, ConfigError> {
+    let program = format!("docker-credential-{}", helper);
+    let helper_error = |message: String| ConfigError::CredentialHelper {
+        helper: program.clone(),
+        message,
+    };
+
+    let mut command = Command::new(&program);
+    #[cfg(test)]
+    HELPER_PATH.with(|path| {
+        if let Some(path) = &*path.borrow() {
+            command.env("PATH", path);
+        }
+    });
+    let output = command
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .and_then(|mut child| {
+            if let Some(mut stdin) = child.stdin.take() {
+                // helpers that fail early may not read their input at all
+                match stdin.write_all(registry.as_bytes()) {
+                    Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e),
+                    _ => (),
+                }
+            }
+            child.wait_with_output()
+        })
+        .map_err(|e| helper_error(e.to_string()))?;
+
+    if !output.status.success() {
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let message = if stdout.trim().is_empty() {
+            stderr.trim()
+        } else {
+            stdout.trim()
+        };
+        return if message.contains("credentials not found") {
+            Ok(None)
+        } else {
+            Err(helper_error(message.to_owned()))
+        };
+    }
+
+    let credentials: HelperCredentials =
+        serde_json::from_slice(&output.stdout).map_err(|e| helper_error(e.to_string()))?;
+    if credentials.username == TOKEN_USERNAME {
+        Ok(Some(RegistryAuth::token(credentials.secret)))
+    } else {
+        Ok(Some(
+            RegistryAuth::builder()
+                .username(credentials.username)
+                .password(credentials.secret)
+                .server_address(registry)
+                .build(),
+        ))
+    }
+}
+
+/// Looks up the credentials for the registry hosting `image` in the `config.json` of
+/// `config_dir`, or the default configuration, without blocking the async runtime on
+/// credential helpers
+pub(crate) async fn for_image(
+    image: String,
+    config_dir: Option<PathBuf>,
+) -> Result<Option<RegistryAuth>, ConfigError> {
+    tokio::task::spawn_blocking(move || {
+        let config = match config_dir {
+            Some(dir) => ConfigFile::load_from(dir.join("config.json"))?,
+            None => ConfigFile::load()?,
+        };
+        config.credentials(registry_for_image(&image))
+    })
+    .await
+    .map_err(|e| ConfigError::CredentialHelper {
+        helper: "docker-credential-*".to_owned(),
+        message: e.to_string(),
+    })?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "shiplift-credentials-{}-{}",
+            test,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn config(json: &str) -> ConfigFile {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn registry_of_image() {
+        assert_eq!(registry_for_image("busybox"), DOCKER_HUB);
+        assert_eq!(registry_for_image("library/busybox:1.33"), DOCKER_HUB);
+        assert_eq!(registry_for_image("docker.io/library/busybox"), DOCKER_HUB);
+        assert_eq!(registry_for_image("quay.io/coreos/etcd"), "quay.io");
+        assert_eq!(registry_for_image("localhost/app"), "localhost");
+        assert_eq!(
+            registry_for_image("registry.local:5000/app:1"),
+            "registry.local:5000"
+        );
+    }
+
+    #[test]
+    fn stored_credentials() {
+        let config = config(
+            r#"{
+                "auths": {
+                    "https://index.docker.io/v1/": {"auth": "dXNlcjpzM2NyZXQ="},
+                    "https://quay.io/v2/": {"auth": "", "identitytoken": "tok"},
+                    "broken.io": {"auth": "bm9jb2xvbg=="},
+                    "https://mirror.io/v1/": {"identitytoken": "v1"},
+                    "mirror.io/v2/": {"identitytoken": "v2"}
+                }
+            }"#,
+        );
+
+        match config.credentials(DOCKER_HUB).unwrap() {
+            Some(RegistryAuth::Password {
+                username,
+                password,
+                server_address,
+                ..
+            }) => {
+                assert_eq!(username, "user");
+                assert_eq!(password, "s3cret");
+                assert_eq!(server_address.as_deref(), Some(DOCKER_HUB));
+            }
+            other => panic!("unexpected credentials {:?}", other),
+        }
+        assert!(matches!(
+            config.credentials("quay.io").unwrap(),
+            Some(RegistryAuth::Token { identity_token }) if identity_token == "tok"
+        ));
+        assert!(config.credentials("ghcr.io").unwrap().is_none());
+        // of several addresses of a host the same one is picked, whatever the map's order
+        for _ in 0..8 {
+            let config: ConfigFile =
+                serde_json::from_value(serde_json::to_value(&config).unwrap()).unwrap();
+            assert!(matches!(
+                config.credentials("mirror.io").unwrap(),
+                Some(RegistryAuth::Token { identity_token }) if identity_token == "v1"
+            ));
+        }
+        assert!(matches!(
+            config.credentials("broken.io"),
+            Err(ConfigError::InvalidAuth(_))
+        ));
+    }
+
+    #[test]
+    fn helper_credentials() {
+        let dir = temp_dir("helper");
+        let helper = dir.join("docker-credential-shiplift-test");
+        fs::write(
+            &helper,
+            "#!/bin/sh\n\
+             read registry\n\
+             case \"$registry\" in\n\
+             private.io) echo '{\"ServerURL\":\"private.io\",\"Username\":\"bob\",\"Secret\":\"pw\"}' ;;\n\
+             token.io) echo '{\"ServerURL\":\"token.io\",\"Username\":\"<token>\",\"Secret\":\"t0k\"}' ;;\n\
+             *) echo 'credentials not found in native keychain'; exit 1 ;;\n\
+             esac\n",
+        )
+        .unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&helper, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let path = env::var_os("PATH").unwrap_or_default();
+        let mut paths = vec![dir.clone()];
+        paths.extend(env::split_paths(&path));
+        let path = env::join_paths(paths).unwrap();
+        HELPER_PATH.with(|helper_path| *helper_path.borrow_mut() = Some(path));
+
+        let config = config(
+            r#"{
+                "auths": {"private.io": {"auth": "aWdub3JlZDppZ25vcmVk"}},
+                "credsStore": "shiplift-test",
+                "credHelpers": {"broken.io": "shiplift-missing"}
+            }"#,
+        );
+        assert!(matches!(
+            config.credentials("private.io").unwrap(),
+            Some(RegistryAuth::Password { username, password, .. })
+                if username == "bob" && password == "pw"
+        ));
+        assert!(matches!(
+            config.credentials("token.io").unwrap(),
+            Some(RegistryAuth::Token { identity_token }) if identity_token == "t0k"
+        ));
+        assert!(config.credentials("other.io").unwrap().is_none());
+        assert!(matches!(
+            config.credentials("broken.io"),
+            Err(ConfigError::CredentialHelper { helper, .. })
+                if helper == "docker-credential-shiplift-missing"
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,448 @@
+//! Exclusion of build context files with the docker CLI's `.dockerignore` semantics
+
+use std::{
+    fs, io,
+    path::{Component, Path},
+};
+
+/// A piece of a compiled pattern
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Literal(char),
+    /// `?`, any character but a separator
+    One,
+    /// `*`, any run of characters without a separator
+    Star,
+    /// `**/` before more of the pattern, any number of whole directories (including none)
+    Dirs,
+    /// `**` ending the pattern or not followed by a separator, anything at all
+    Any,
+    /// `[...]`, a set of character ranges
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+/// A single line of a `.dockerignore`
+#[derive(Debug, Clone)]
+struct Pattern {
+    tokens: Vec<Token>,
+    /// Whether this is a `!` exception that re-includes what earlier patterns excluded
+    exception: bool,
+}
+
+impl Pattern {
+    fn parse(line: &str) -> Option<Pattern> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (exception, pattern) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest.trim()),
+            None => (false, line),
+        };
+        let pattern = clean(pattern);
+        let pattern = match pattern.strip_prefix('/') {
+            Some(rest) if !rest.is_empty() => rest.to_owned(),
+            _ => pattern,
+        };
+        Some(Pattern {
+            tokens: compile(&pattern),
+            exception,
+        })
+    }
+
+    /// Whether the pattern matches `path` or one of its parent directories
+    fn matches(
+        &self,
+        path: &str,
+    ) -> bool {
+        if match_tokens(&self.tokens, &path.chars().collect::<Vec<_>>()) {
+            return true;
+        }
+        path.match_indices('/')
+            .any(|(i, _)| match_tokens(&self.tokens, &path[..i].chars().collect::<Vec<_>>()))
+    }
+
+    /// The directories leading up to the first wildcard, or the whole path without any
+    #[cfg(feature = "buildkit")]
+    fn literal_prefix(&self) -> String {
+        let literal: String = self
+            .tokens
+            .iter()
+            .map_while(|token| match token {
+                Token::Literal(c) => Some(*c),
+                _ => None,
+            })
+            .collect();
+        if literal.chars().count() == self.tokens.len() {
+            return literal;
+        }
+        match literal.rfind('/') {
+            Some(slash) => literal[..slash].to_owned(),
+            None => String::new(),
+        }
+    }
+}
+
+/// Lexically cleans a slash separated path like go's `filepath.Clean`
+fn clean(path: &str) -> String {
+    let rooted = path.starts_with('/');
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => (),
+            ".." => match parts.last() {
+                Some(&last) if last != ".." => {
+                    parts.pop();
+                }
+                _ if rooted => (),
+                _ => parts.push(".."),
+            },
+            part => parts.push(part),
+        }
+    }
+    let joined = parts.join("/");
+    match (rooted, joined.is_empty()) {
+        (true, _) => format!("/{}", joined),
+        (false, true) => ".".to_owned(),
+        (false, false) => joined,
+    }
+}
+
+fn compile(pattern: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // `**/` is treated like `**` at the end of the pattern
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    if chars.peek().is_some() {
+                        Token::Dirs
+                    } else {
+                        Token::Any
+                    }
+                } else {
+                    Token::Any
+                }
+            }
+            '*' => Token::Star,
+            '?' => Token::One,
+            '\\' => Token::Literal(chars.next().unwrap_or('\\')),
+            '[' => {
+                let mut negated = false;
+                if chars.peek() == Some(&'^') {
+                    chars.next();
+                    negated = true;
+                }
+                let mut ranges = Vec::new();
+                let mut closed = false;
+                while let Some(c) = chars.next() {
+                    let start = match c {
+                        ']' => {
+                            closed = true;
+                            break;
+                        }
+                        '\\' => chars.next().unwrap_or('\\'),
+                        c => c,
+                    };
+                    let end = if chars.peek() == Some(&'-') {
+                        chars.next();
+                        match chars.next() {
+                            Some('\\') => chars.next().unwrap_or('\\'),
+                            Some(']') | None => {
+                                // a trailing `-` is literal
+                                ranges.push((start, start));
+                                ranges.push(('-', '-'));
+                                closed = true;
+                                break;
+                            }
+                            Some(end) => end,
+                        }
+                    } else {
+                        start
+                    };
+                    ranges.push((start, end));
+                }
+                if !closed {
+                    // the docker CLI rejects these, match nothing rather than everything
+                    return vec![Token::Class {
+                        negated: false,
+                        ranges: Vec::new(),
+                    }];
+                }
+                Token::Class { negated, ranges }
+            }
+            c => Token::Literal(c),
+        };
+        tokens.push(token);
+    }
+    tokens
+}
+
+fn match_tokens(
+    tokens: &[Token],
+    path: &[char],
+) -> bool {
+    let (token, rest) = match tokens.split_first() {
+        Some(split) => split,
+        None => return path.is_empty(),
+    };
+    match token {
+        Token::Literal(c) => path.first() == Some(c) && match_tokens(rest, &path[1..]),
+        Token::One => {
+            matches!(path.first(), Some(&c) if c != '/') && match_tokens(rest, &path[1..])
+        }
+        Token::Class { negated, ranges } => match path.first() {
+            Some(&c) => {
+                let found = ranges.iter().any(|&(start, end)| start <= c && c <= end);
+                found != *negated && match_tokens(rest, &path[1..])
+            }
+            None => false,
+        },
+        Token::Star => {
+            let run = path.iter().take_while(|&&c| c != '/').count();
+            (0..=run).any(|n| match_tokens(rest, &path[n..]))
+        }
+        Token::Dirs => {
+            match_tokens(rest, path)
+                || path
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &c)| c == '/')
+                    .any(|(i, _)| match_tokens(rest, &path[i + 1..]))
+        }
+        Token::Any => (0..=path.len()).any(|n| match_tokens(rest, &path[n..])),
+    }
+}
+
+/// Decides which files of a build context are left out, like the docker CLI does
+#[derive(Debug, Clone, Default)]
+pub struct Excludes {
+    patterns: Vec<Pattern>,
+    /// Files that are sent regardless of the patterns
+    keep: Vec<String>,
+}
+
+impl Excludes {
+    /// Loads the `.dockerignore` of the context at `root`, preferring a `<dockerfile>.dockerignore`
+    /// next to the Dockerfile, followed by `extra` patterns
+    ///
+    /// The Dockerfile and `.dockerignore` themselves are always kept.
+    pub fn load<S>(
+        root: &Path,
+        dockerfile: &str,
+        extra: &[S],
+    ) -> io::Result<Excludes>
+    where
+        S: AsRef<str>,
+    {
+        let dockerfile = clean(dockerfile);
+        let mut lines = String::new();
+        for ignore in &[
+            format!("{}.dockerignore", dockerfile),
+            ".dockerignore".to_owned(),
+        ] {
+            match fs::read_to_string(root.join(ignore)) {
+                Ok(contents) => {
+                    lines = contents;
+                    break;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(Excludes {
+            patterns: lines
+                .lines()
+                .chain(extra.iter().map(AsRef::as_ref))
+                .filter_map(Pattern::parse)
+                .collect(),
+            keep: vec![dockerfile, ".dockerignore".to_owned()],
+        })
+    }
+
+    /// Compiles `patterns` without keeping any files regardless of them, like the patterns a
+    /// BuildKit session is asked to filter with
+    #[cfg(feature = "buildkit")]
+    pub fn from_patterns<S>(patterns: &[S]) -> Excludes
+    where
+        S: AsRef<str>,
+    {
+        Excludes {
+            patterns: patterns
+                .iter()
+                .map(AsRef::as_ref)
+                .filter_map(Pattern::parse)
+                .collect(),
+            keep: Vec::new(),
+        }
+    }
+
+    /// Whether the file or directory at `path`, relative to the context root, is left out
+    pub fn excludes(
+        &self,
+        path: &Path,
+    ) -> bool {
+        let path = slash_path(path);
+        !self.keep.contains(&path) && self.matches(&path)
+    }
+
+    /// Whether the last pattern matching the slash separated `path` isn't an exception
+    pub(crate) fn matches(
+        &self,
+        path: &str,
+    ) -> bool {
+        self.patterns.iter().fold(false, |excluded, pattern| {
+            if excluded != pattern.exception {
+                excluded
+            } else if pattern.matches(path) {
+                !pattern.exception
+            } else {
+                excluded
+            }
+        })
+    }
+
+    /// Whether any pattern may match the directory at the slash separated `dir` or anything
+    /// inside of it
+    #[cfg(feature = "buildkit")]
+    pub(crate) fn may_match_within(
+        &self,
+        dir: &str,
+    ) -> bool {
+        let within = |path: &str, parent: &str| {
+            path == parent
+                || path
+                    .strip_prefix(parent)
+                    .is_some_and(|rest| rest.starts_with('/'))
+        };
+        self.patterns.iter().any(|pattern| {
+            let prefix = pattern.literal_prefix();
+            prefix.is_empty() || within(&prefix, dir) || within(dir, &prefix)
+        })
+    }
+
+    /// Whether files inside the excluded directory at `path` may still be kept
+    pub fn may_keep_within(
+        &self,
+        path: &Path,
+    ) -> bool {
+        let prefix = format!("{}/", slash_path(path));
+        self.patterns.iter().any(|pattern| pattern.exception)
+            || self.keep.iter().any(|keep| keep.starts_with(&prefix))
+    }
+}
+
+/// Joins the components of a relative path with `/`
+pub(crate) fn slash_path(path: &Path) -> String {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn excludes(patterns: &[&str]) -> Excludes {
+        Excludes {
+            patterns: patterns
+                .iter()
+                .copied()
+                .filter_map(Pattern::parse)
+                .collect(),
+            keep: vec!["Dockerfile".to_owned(), ".dockerignore".to_owned()],
+        }
+    }
+
+    fn excluded(
+        patterns: &[&str],
+        path: &str,
+    ) -> bool {
+        excludes(patterns).excludes(Path::new(path))
+    }
+
+    #[test]
+    fn match_like_the_cli() {
+        assert!(excluded(&["target"], "target"));
+        assert!(excluded(&["target"], "target/debug/shiplift"));
+        assert!(!excluded(&["target"], "src/target"));
+        assert!(excluded(&["/target/"], "target/debug"));
+        assert!(excluded(&["./target"], "target"));
+        assert!(excluded(&["*.md"], "README.md"));
+        assert!(!excluded(&["*.md"], "docs/README.md"));
+        assert!(excluded(&["*/*.md"], "docs/README.md"));
+        assert!(excluded(&["**/*.md"], "README.md"));
+        assert!(excluded(&["**/*.md"], "docs/api/README.md"));
+        assert!(excluded(&["docs/**"], "docs/api/README.md"));
+        assert!(excluded(&["**.md"], "README.md"));
+        assert!(excluded(&["**.md"], "docs/README.md"));
+        assert!(!excluded(&["**.md"], "docs/README.txt"));
+        assert!(excluded(&["docs/**.md"], "docs/api/README.md"));
+        assert!(excluded(
+            &["**/node_modules"],
+            "web/app/node_modules/left-pad/index.js"
+        ));
+        assert!(excluded(&["src/**/test"], "src/test"));
+        assert!(excluded(&["src/**/test"], "src/a/b/test"));
+        assert!(!excluded(&["src/**/test"], "src/a/btest"));
+        assert!(excluded(&["file?.txt"], "file1.txt"));
+        assert!(!excluded(&["file?.txt"], "file10.txt"));
+        assert!(excluded(&["file[0-9].txt"], "file7.txt"));
+        assert!(!excluded(&["file[^0-9].txt"], "file7.txt"));
+        assert!(excluded(&["file[^0-9].txt"], "fileA.txt"));
+        assert!(excluded(&["\\*literal"], "*literal"));
+        assert!(!excluded(&["\\*literal"], "xliteral"));
+        assert!(!excluded(&["# comment", "", "   "], "comment"));
+        assert!(!excluded(&["bad[range"], "bad[range"));
+        assert!(excluded(&["a/../b"], "b"));
+    }
+
+    #[test]
+    fn later_patterns_win() {
+        let patterns = ["*.md", "!README.md", "README*"];
+        assert!(excluded(&patterns, "CHANGELOG.md"));
+        assert!(excluded(&patterns, "README.md"));
+
+        let patterns = ["docs", "!docs/keep"];
+        assert!(excluded(&patterns, "docs/drop"));
+        assert!(!excluded(&patterns, "docs/keep"));
+        assert!(!excluded(&patterns, "docs/keep/nested"));
+        assert!(excludes(&patterns).may_keep_within(Path::new("docs")));
+        assert!(!excludes(&["docs"]).may_keep_within(Path::new("docs")));
+    }
+
+    #[test]
+    fn keep_build_files() {
+        assert!(!excluded(&["*"], "Dockerfile"));
+        assert!(!excluded(&["*"], ".dockerignore"));
+        assert!(excluded(&["*"], "Cargo.toml"));
+
+        let dir =
+            std::env::temp_dir().join(format!("shiplift-dockerignore-{}", std::process::id()));
+        fs::create_dir_all(dir.join("build")).unwrap();
+        fs::write(dir.join(".dockerignore"), "*\n").unwrap();
+        fs::write(dir.join("build/app.Dockerfile.dockerignore"), "*.log\n").unwrap();
+
+        let default = Excludes::load(&dir, "Dockerfile", &["!src"]).unwrap();
+        let specific = Excludes::load(&dir, "./build/app.Dockerfile", &[] as &[&str]).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(default.excludes(Path::new("Cargo.toml")));
+        assert!(!default.excludes(Path::new("src/lib.rs")));
+        assert!(!specific.excludes(Path::new("Cargo.toml")));
+        assert!(specific.excludes(Path::new("build.log")));
+        assert!(!specific.excludes(Path::new("build/app.Dockerfile")));
+        assert!(specific.may_keep_within(Path::new("build")));
+    }
+}
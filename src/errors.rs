@@ -1,8 +1,9 @@
 //! Representations of various client errors
 
-use hyper::{self, http, StatusCode};
+use crate::version::ApiVersion;
+use hyper::{self, http, Method, StatusCode};
 use serde_json::Error as SerdeError;
-use std::{error::Error as StdError, fmt, string::FromUtf8Error};
+use std::{error::Error as StdError, fmt, io, path::PathBuf, string::FromUtf8Error};
 
 use futures_util::io::Error as IoError;
 
@@ -14,8 +15,47 @@ pub enum Error {
     IO(IoError),
     Encoding(FromUtf8Error),
     InvalidResponse(String),
-    Fault { code: StatusCode, message: String },
+    /// The daemon answered a request with an error status
+    Fault {
+        code: StatusCode,
+        message: String,
+        method: Method,
+        /// The path and query of the request
+        endpoint: String,
+    },
+    /// A request could not be sent or its response could not be received, e.g. because the
+    /// daemon isn't running
+    Request {
+        method: Method,
+        /// The path and query of the request
+        endpoint: String,
+        source: hyper::Error,
+    },
     ConnectionNotUpgraded,
+    /// A failure the daemon reported in the middle of a streamed response, such as a pull or
+    /// build that broke off halfway
+    Stream {
+        message: String,
+        /// The code of the `errorDetail`, e.g. the exit code of a failed `RUN` instruction
+        code: Option<i64>,
+    },
+    /// A build failed, see [`BuildResult::collect`](crate::rep::BuildResult::collect)
+    Build {
+        /// The step that failed, e.g. `Step 2/3 : RUN make`, or `[2/3] RUN make` with BuildKit
+        step: Option<String>,
+        message: String,
+        /// The code of the `errorDetail`, e.g. the exit code of a failed `RUN` instruction
+        code: Option<i64>,
+        /// Everything the build printed before it failed
+        log: String,
+    },
+    UnsupportedApiVersion {
+        endpoint: String,
+        required: ApiVersion,
+        current: ApiVersion,
+    },
+    Config(ConfigError),
+    Tty(TtyError),
 }
 
 impl From<SerdeError> for Error {
@@ -49,6 +89,18 @@ impl From<IoError> for Error {
     }
 }
 
+impl From<ConfigError> for Error {
+    fn from(error: ConfigError) -> Error {
+        Error::Config(error)
+    }
+}
+
+impl From<TtyError> for Error {
+    fn from(error: TtyError) -> Error {
+        Error::Tty(error)
+    }
+}
+
 impl From<FromUtf8Error> for Error {
     fn from(error: FromUtf8Error) -> Error {
         Error::Encoding(error)
@@ -70,11 +122,43 @@ impl fmt::Display for Error {
             Error::InvalidResponse(ref cause) => {
                 write!(f, "Response doesn't have the expected format: {}", cause)
             }
-            Error::Fault { code, .. } => write!(f, "{}", code),
+            Error::Fault {
+                code,
+                message,
+                method,
+                endpoint,
+            } => write!(
+                f,
+                "{} {} failed with {}: {}",
+                method, endpoint, code, message
+            ),
+            Error::Request {
+                method,
+                endpoint,
+                source,
+            } => write!(f, "{} {} failed: {}", method, endpoint, source),
             Error::ConnectionNotUpgraded => write!(
                 f,
                 "expected the docker host to upgrade the HTTP connection but it did not"
             ),
+            Error::Stream { message, .. } => write!(f, "{}", message),
+            Error::Build {
+                step: Some(step),
+                message,
+                ..
+            } => write!(f, "{} failed: {}", step, message),
+            Error::Build { message, .. } => write!(f, "{}", message),
+            Error::UnsupportedApiVersion {
+                endpoint,
+                required,
+                current,
+            } => write!(
+                f,
+                "{} requires API version {} but the client is using {}",
+                endpoint, required, current
+            ),
+            Error::Config(ref err) => err.fmt(f),
+            Error::Tty(ref err) => err.fmt(f),
         }
     }
 }
@@ -84,9 +168,192 @@ impl StdError for Error {
         match self {
             Error::SerdeJsonError(ref err) => Some(err),
             Error::Http(ref err) => Some(err),
+            Error::Hyper(ref err) => Some(err),
+            Error::Request { source, .. } => Some(source),
             Error::IO(ref err) => Some(err),
             Error::Encoding(e) => Some(e),
+            Error::Config(e) => Some(e),
+            Error::Tty(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Broad categories of errors, for handling the common failures without matching status codes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The object of the request does not exist, a `404`
+    NotFound,
+    /// The request conflicts with the state of the object, e.g. a name in use, a `409`
+    Conflict,
+    /// The request had no effect, e.g. starting a running container, a `304`
+    NotModified,
+    /// The daemon or a registry behind it refused the credentials, a `401`
+    Unauthorized,
+    /// The daemon could not be reached
+    DaemonUnavailable,
+    Other,
+}
+
+impl Error {
+    /// Returns the category of this error
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Fault { code, .. } => match *code {
+                StatusCode::NOT_FOUND => ErrorKind::NotFound,
+                StatusCode::CONFLICT => ErrorKind::Conflict,
+                StatusCode::NOT_MODIFIED => ErrorKind::NotModified,
+                StatusCode::UNAUTHORIZED => ErrorKind::Unauthorized,
+                _ => ErrorKind::Other,
+            },
+            Error::Request { source, .. } | Error::Hyper(source) if source.is_connect() => {
+                ErrorKind::DaemonUnavailable
+            }
+            _ => ErrorKind::Other,
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        self.kind() == ErrorKind::NotFound
+    }
+
+    pub fn is_conflict(&self) -> bool {
+        self.kind() == ErrorKind::Conflict
+    }
+
+    pub fn is_not_modified(&self) -> bool {
+        self.kind() == ErrorKind::NotModified
+    }
+
+    pub fn is_unauthorized(&self) -> bool {
+        self.kind() == ErrorKind::Unauthorized
+    }
+
+    pub fn is_daemon_unavailable(&self) -> bool {
+        self.kind() == ErrorKind::DaemonUnavailable
+    }
+
+    /// Returns the method and endpoint of the request that failed, if the error came from one
+    pub fn request(&self) -> Option<(&Method, &str)> {
+        match self {
+            Error::Fault {
+                method, endpoint, ..
+            }
+            | Error::Request {
+                method, endpoint, ..
+            } => Some((method, endpoint)),
+            _ => None,
+        }
+    }
+}
+
+/// Errors raised while working out how to connect to a docker daemon
+#[derive(Debug)]
+pub enum ConfigError {
+    /// A configuration file could not be read
+    Read { path: PathBuf, source: io::Error },
+    /// A configuration file is not in the expected format
+    Parse { path: PathBuf, source: SerdeError },
+    /// No context with the given name exists
+    ContextNotFound(String),
+    /// The context does not define a docker endpoint
+    MissingEndpoint(String),
+    /// The daemon address is not a valid url
+    InvalidUrl(String),
+    /// The daemon address uses a scheme shiplift can't connect with
+    UnsupportedScheme(String),
+    /// The stored credentials for a registry are not valid base64 `username:password`
+    InvalidAuth(String),
+    /// A credential helper could not be run or failed
+    CredentialHelper { helper: String, message: String },
+    /// A certificate or key file does not exist
+    MissingCertificate(PathBuf),
+    /// A certificate or key could not be loaded
+    InvalidCertificate { path: PathBuf, message: String },
+    /// The TLS connector could not be set up
+    Tls(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, source } => {
+                write!(f, "failed to read {}: {}", path.display(), source)
+            }
+            ConfigError::Parse { path, source } => {
+                write!(f, "failed to parse {}: {}", path.display(), source)
+            }
+            ConfigError::ContextNotFound(name) => write!(f, "context {:?} does not exist", name),
+            ConfigError::MissingEndpoint(name) => {
+                write!(f, "context {:?} has no docker endpoint", name)
+            }
+            ConfigError::InvalidUrl(url) => write!(f, "invalid docker host url {:?}", url),
+            ConfigError::UnsupportedScheme(scheme) => {
+                write!(f, "unsupported docker host scheme {:?}", scheme)
+            }
+            ConfigError::InvalidAuth(registry) => {
+                write!(f, "invalid credentials stored for registry {:?}", registry)
+            }
+            ConfigError::CredentialHelper { helper, message } => {
+                write!(f, "credential helper {} failed: {}", helper, message)
+            }
+            ConfigError::MissingCertificate(path) => {
+                write!(f, "certificate file {} does not exist", path.display())
+            }
+            ConfigError::InvalidCertificate { path, message } => {
+                write!(
+                    f,
+                    "invalid certificate file {}: {}",
+                    path.display(),
+                    message
+                )
+            }
+            ConfigError::Tls(message) => write!(f, "failed to set up TLS: {}", message),
+        }
+    }
+}
+
+impl StdError for ConfigError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            ConfigError::Read { source, .. } => Some(source),
+            ConfigError::Parse { source, .. } => Some(source),
             _ => None,
         }
     }
 }
+
+/// Errors decoding the multiplexed output of a container
+#[derive(Debug)]
+pub enum TtyError {
+    /// A frame header names a stream other than stdin, stdout, stderr or systemerr, so the
+    /// framing can't be trusted anymore
+    UnknownStream(u8),
+    /// The output ended in the middle of a frame
+    Truncated { expected: usize, received: usize },
+    /// The daemon reported an error on the systemerr stream, e.g. because an exec failed
+    Daemon(String),
+}
+
+impl fmt::Display for TtyError {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        match self {
+            TtyError::UnknownStream(n) => write!(f, "invalid stream number {} in output", n),
+            TtyError::Truncated { expected, received } => write!(
+                f,
+                "output ended after {} of {} bytes of a frame",
+                received, expected
+            ),
+            TtyError::Daemon(message) => write!(f, "error from daemon in stream: {}", message),
+        }
+    }
+}
+
+impl StdError for TtyError {}
@@ -0,0 +1,240 @@
+//! Decoding of the JSON progress streams returned by pull, push, build and import
+
+use crate::{Error, Result};
+use bytes::{Buf, BytesMut};
+use futures_util::stream::{self, Stream, StreamExt};
+use hyper::body::Bytes;
+use serde_json::Value;
+
+/// How far the value at the front of a buffer has been scanned, so a value split over many
+/// chunks is parsed once it may be complete rather than again for every chunk
+#[derive(Debug, Default)]
+struct Scan {
+    offset: usize,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl Scan {
+    /// Scans the bytes buffered since the last call, returning whether the value at the front
+    /// of `buf` may be complete: its object or array was closed or a line ended. Other values
+    /// are left to the parser
+    fn ready(
+        &mut self,
+        buf: &[u8],
+    ) -> bool {
+        if !matches!(buf.first(), Some(b'{') | Some(b'[')) {
+            return true;
+        }
+        let mut ready = false;
+        for &b in &buf[self.offset..] {
+            self.offset += 1;
+            if b == b'\n' {
+                ready = true;
+            } else if self.in_string {
+                match b {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => self.in_string = false,
+                    _ => (),
+                }
+            } else {
+                match b {
+                    b'"' => self.in_string = true,
+                    b'{' | b'[' => self.depth += 1,
+                    b'}' | b']' => {
+                        self.depth = self.depth.saturating_sub(1);
+                        if self.depth == 0 {
+                            return true;
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+        ready
+    }
+}
+
+/// Takes the next complete value off the front of `buf`, returning `Ok(None)` until enough
+/// bytes have been buffered
+///
+/// Values may be newline delimited or simply concatenated. After a syntax error the rest of the
+/// offending line is dropped, so a single bad line doesn't poison the values after it.
+fn next_value(
+    buf: &mut BytesMut,
+    scan: &mut Scan,
+) -> serde_json::Result<Option<Value>> {
+    match buf.iter().position(|b| !b.is_ascii_whitespace()) {
+        Some(start) => {
+            buf.advance(start);
+            scan.offset = scan.offset.saturating_sub(start);
+        }
+        None => {
+            buf.clear();
+            *scan = Scan::default();
+            return Ok(None);
+        }
+    }
+    if !scan.ready(buf) {
+        return Ok(None);
+    }
+
+    let mut values = serde_json::Deserializer::from_slice(buf).into_iter::<Value>();
+    let next = values.next();
+    let offset = values.byte_offset();
+
+    match next {
+        Some(Ok(value)) => {
+            buf.advance(offset);
+            *scan = Scan::default();
+            Ok(Some(value))
+        }
+        // keep scanning where the last chunk ended
+        Some(Err(e)) if e.is_eof() => Ok(None),
+        Some(Err(e)) => {
+            match buf.iter().position(|&b| b == b'\n') {
+                Some(newline) => buf.advance(newline + 1),
+                None => buf.clear(),
+            }
+            *scan = Scan::default();
+            Err(e)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Decodes a stream of body chunks into the JSON values they carry, regardless of how the values
+/// are split across chunks
+pub(crate) fn decode<S>(chunks: S) -> impl Stream<Item = Result<Value>>
+where
+    S: Stream<Item = Result<Bytes>> + Unpin,
+{
+    let state = (chunks, BytesMut::new(), Scan::default());
+    stream::unfold(Some(state), |state| async move {
+        let (mut chunks, mut buf, mut scan) = state?;
+        loop {
+            match next_value(&mut buf, &mut scan) {
+                Ok(Some(value)) => return Some((Ok(value), Some((chunks, buf, scan)))),
+                Err(e) => return Some((Err(Error::from(e)), Some((chunks, buf, scan)))),
+                Ok(None) => (),
+            }
+            match chunks.next().await {
+                Some(Ok(chunk)) => buf.extend_from_slice(&chunk),
+                Some(Err(e)) => return Some((Err(e), Some((chunks, buf, scan)))),
+                None if buf.is_empty() => return None,
+                // the body ended in the middle of a value
+                None => {
+                    let e = serde_json::from_slice::<Value>(&buf)
+                        .expect_err("incomplete values fail to parse");
+                    return Some((Err(Error::from(e)), None));
+                }
+            }
+        }
+    })
+}
+
+/// Turns the `{"errorDetail": {...}, "error": "..."}` values the daemon reports failures with
+/// into [`Error::Stream`]
+pub(crate) fn check_error(value: Value) -> Result<Value> {
+    let detail = &value["errorDetail"];
+    match value["error"]
+        .as_str()
+        .or_else(|| detail["message"].as_str())
+    {
+        Some(message) => Err(Error::Stream {
+            message: message.to_owned(),
+            code: detail["code"].as_i64(),
+        }),
+        None => Ok(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::TryStreamExt;
+    use serde_json::json;
+
+    async fn decode_chunks(chunks: Vec<&[u8]>) -> Vec<Result<Value>> {
+        let chunks = chunks
+            .into_iter()
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect::<Vec<_>>();
+        decode(stream::iter(chunks)).collect().await
+    }
+
+    #[tokio::test]
+    async fn decode_values_split_anywhere() {
+        let body = br#"{"status":"Pulling fs layer","id":"a"}
+{"aux":{"ID":"sha256:0123"}}{"stream":"Step 1/2 : FROM scratch\n"}  [1,2]
+{"stream":"} \"{[\\"}7
+"#;
+        let expected = vec![
+            json!({"status": "Pulling fs layer", "id": "a"}),
+            json!({"aux": {"ID": "sha256:0123"}}),
+            json!({"stream": "Step 1/2 : FROM scratch\n"}),
+            json!([1, 2]),
+            json!({"stream": "} \"{[\\"}),
+            json!(7),
+        ];
+
+        for split in 0..=body.len() {
+            let (head, tail) = body.split_at(split);
+            let values = decode(stream::iter(vec![
+                Ok(Bytes::copy_from_slice(head)),
+                Ok(Bytes::new()),
+                Ok(Bytes::copy_from_slice(tail)),
+            ]))
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+            assert_eq!(values, expected, "split at {}", split);
+        }
+
+        let bytes = body.iter().map(std::slice::from_ref).collect();
+        let values = decode_chunks(bytes).await;
+        assert_eq!(
+            values.into_iter().collect::<Result<Vec<_>>>().unwrap(),
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn recover_from_bad_lines() {
+        let values = decode_chunks(vec![b"{\"id\":1}\n{oops}\n{\"id\"", b":2}\n"]).await;
+        assert!(matches!(
+            &values[..],
+            [Ok(_), Err(Error::SerdeJsonError(_)), Ok(_)]
+        ));
+        assert_eq!(values[2].as_ref().unwrap(), &json!({"id": 2}));
+    }
+
+    #[test]
+    fn error_values() {
+        let error = json!({
+            "errorDetail": {"code": 1, "message": "The command '/bin/sh -c exit 1' returned a non-zero code: 1"},
+            "error": "The command '/bin/sh -c exit 1' returned a non-zero code: 1"
+        });
+        assert!(matches!(
+            check_error(error),
+            Err(Error::Stream { message, code: Some(1) }) if message.starts_with("The command")
+        ));
+        assert!(matches!(
+            check_error(json!({"errorDetail": {"message": "denied"}})),
+            Err(Error::Stream { message, code: None }) if message == "denied"
+        ));
+
+        let progress = json!({"status": "Downloading", "id": "a", "progressDetail": {}});
+        assert_eq!(check_error(progress.clone()).unwrap(), progress);
+    }
+
+    #[tokio::test]
+    async fn report_truncated_values() {
+        let values = decode_chunks(vec![b"{\"id\":1}\n{\"error\":\"boo"]).await;
+        assert!(matches!(&values[..], [Ok(_), Err(Error::SerdeJsonError(e))] if e.is_eof()));
+
+        assert!(decode_chunks(vec![b" \r\n", b""]).await.is_empty());
+    }
+}
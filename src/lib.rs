@@ -18,60 +18,126 @@
 //! ```
 
 pub mod builder;
+pub mod config;
+pub mod context;
+pub mod credentials;
 pub mod errors;
+#[cfg(feature = "mock")]
+pub mod mock;
 pub mod rep;
+#[cfg(feature = "buildkit")]
+pub mod session;
+#[cfg(feature = "ssh")]
+pub mod ssh;
 pub mod transport;
 pub mod tty;
+pub mod version;
 
+mod dockerignore;
+mod json;
+mod protobuf;
 mod tarball;
+mod tls;
 
+#[cfg(feature = "buildkit")]
+pub use crate::session::SessionOptions;
+#[cfg(feature = "ssh")]
+use crate::ssh::SshConnector;
 pub use crate::{
     builder::{
         BuildOptions, ContainerConnectionOptions, ContainerFilter, ContainerListOptions,
-        ContainerOptions, EventsOptions, ExecContainerOptions, ExecResizeOptions, ImageFilter,
-        ImageListOptions, LogsOptions, NetworkCreateOptions, NetworkListOptions, PullOptions,
-        RegistryAuth, RmContainerOptions, ServiceFilter, ServiceListOptions, ServiceOptions,
-        TagOptions, VolumeCreateOptions,
+        ContainerOptions, ContextCompression, DockerBuilder, EventsOptions, ExecContainerOptions,
+        ExecResizeOptions, ImageFilter, ImageListOptions, LogsOptions, NetworkCreateOptions,
+        NetworkListOptions, PullOptions, PushOptions, RegistryAuth, RmContainerOptions,
+        ServiceFilter, ServiceListOptions, ServiceOptions, TagOptions, VolumeCreateOptions,
     },
-    errors::Error,
+    errors::{ConfigError, Error, ErrorKind},
+    tarball::BuildContext,
+    version::ApiVersion,
 };
 use crate::{
+    context::{Context, DEFAULT_CONTEXT},
     rep::{
-        Change, Container as ContainerRep, ContainerCreateInfo, ContainerDetails, Event,
-        ExecDetails, Exit, History, Image as ImageRep, ImageDetails, Info, NetworkCreateInfo,
-        NetworkDetails as NetworkInfo, SearchResult, ServiceCreateInfo, ServiceDetails,
-        Services as ServicesRep, Stats, Status, Top, Version, Volume as VolumeRep,
-        VolumeCreateInfo, Volumes as VolumesRep,
+        BuildEvent, BuildResult, Change, Container as ContainerRep, ContainerCreateInfo,
+        ContainerDetails, Event, ExecDetails, Exit, History, Image as ImageRep, ImageDetails, Info,
+        NetworkCreateInfo, NetworkDetails as NetworkInfo, PingInfo, PullEvent, SearchResult,
+        ServiceCreateInfo, ServiceDetails, Services as ServicesRep, Stats, Status, Top, Version,
+        Volume as VolumeRep, VolumeCreateInfo, Volumes as VolumesRep,
     },
-    transport::{tar, Headers, Payload, Transport},
+    tls::TlsConfig,
+    transport::{tar, BoxedConnector, Headers, Payload, Transport},
     tty::Multiplexer as TtyMultiPlexer,
 };
 use futures_util::{
+    future,
     io::{AsyncRead, AsyncWrite},
     stream::Stream,
     TryFutureExt, TryStreamExt,
 };
 // use futures::{future::Either, Future, IntoFuture, Stream};
+pub use bytes::Bytes;
 pub use hyper::Uri;
 use hyper::{client::HttpConnector, Body, Client, Method};
-#[cfg(feature = "tls")]
-use hyper_openssl::HttpsConnector;
 #[cfg(feature = "unix-socket")]
 use hyperlocal::UnixConnector;
 use mime::Mime;
-#[cfg(feature = "tls")]
-use openssl::ssl::{SslConnector, SslFiletype, SslMethod};
-use serde_json::Value;
-use std::{env, io, io::Read, iter, path::Path, time::Duration};
+use serde_json::{json, Value};
+use std::{env, future::Future, io, io::Read, iter, path::Path, sync::Arc, time::Duration};
+use tokio::sync::OnceCell;
 use url::form_urlencoded;
 
 /// Represents the result of all docker operations
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// The `User-Agent` sent unless configured otherwise with [`DockerBuilder::user_agent`]
+const DEFAULT_USER_AGENT: &str = concat!("shiplift/", env!("CARGO_PKG_VERSION"));
+
 /// Entrypoint interface for communicating with docker daemon
 #[derive(Clone)]
 pub struct Docker {
     transport: Transport,
+    api_version: VersionMode,
+    /// Headers sent with every request
+    headers: Vec<(&'static str, String)>,
+    /// Limit on the time taken by requests with a single response
+    timeout: Option<Duration>,
+}
+
+/// Connection settings shared by the constructors and [`DockerBuilder`]
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ClientSettings {
+    pub connect_timeout: Option<Duration>,
+    pub pool_max_idle_per_host: Option<usize>,
+}
+
+impl ClientSettings {
+    fn client(&self) -> hyper::client::Builder {
+        let mut builder = Client::builder();
+        if let Some(max) = self.pool_max_idle_per_host {
+            builder.pool_max_idle_per_host(max);
+        }
+        builder
+    }
+
+    fn http_connector(&self) -> HttpConnector {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        http.set_connect_timeout(self.connect_timeout);
+
+        http
+    }
+}
+
+/// How a `Docker` instance picks the API version prefixed to requests
+#[derive(Clone, Debug)]
+enum VersionMode {
+    /// Requests are sent without a version prefix and get the daemon's default behavior
+    Unversioned,
+    /// Requests are always sent with the given version
+    Pinned(ApiVersion),
+    /// The version is negotiated with the daemon on first use. Once set, holds the agreed version,
+    /// if the daemon advertised one. Concurrent first requests share a single ping.
+    Negotiated(Arc<OnceCell<Option<ApiVersion>>>),
 }
 
 /// Interface for accessing and manipulating a named docker image
@@ -117,12 +183,8 @@ impl<'docker> Image<'docker> {
     }
 
     /// Export this image to a tarball
-    pub fn export(&self) -> impl Stream<Item = Result<Vec<u8>>> + Unpin + 'docker {
-        Box::pin(
-            self.docker
-                .stream_get(format!("/images/{}/get", self.name))
-                .map_ok(|c| c.to_vec()),
-        )
+    pub fn export(&self) -> impl Stream<Item = Result<Bytes>> + Unpin + 'docker {
+        Box::pin(self.docker.stream_get(format!("/images/{}/get", self.name)))
     }
 
     /// Adds a tag to an image
@@ -137,6 +199,34 @@ impl<'docker> Image<'docker> {
         let _ = self.docker.post(&path.join("?"), None).await?;
         Ok(())
     }
+
+    /// Pushes the image to its registry, streaming the progress reported by the daemon
+    pub fn push(
+        &self,
+        opts: &PushOptions,
+    ) -> impl Stream<Item = Result<PullEvent>> + Unpin + 'docker {
+        let mut path = vec![format!("/images/{}/push", self.name)];
+        if let Some(query) = opts.serialize() {
+            path.push(query);
+        }
+        let auth = opts.auth_header(&self.name);
+        let docker = self.docker;
+
+        Box::pin(
+            async move {
+                // the daemon refuses pushes without the header, even to registries that
+                // don't need credentials
+                let auth = auth
+                    .await?
+                    .unwrap_or_else(|| base64::encode_config("{}", base64::URL_SAFE));
+                let headers = Some(iter::once(("X-Registry-Auth", auth)));
+                Ok(docker
+                    .stream_post_into_values(path.join("?"), None, headers)
+                    .map_ok(PullEvent::from))
+            }
+            .try_flatten_stream(),
+        )
+    }
 }
 
 /// Interface for docker images
@@ -154,33 +244,96 @@ impl<'docker> Images<'docker> {
     pub fn build(
         &self,
         opts: &BuildOptions,
-    ) -> impl Stream<Item = Result<Value>> + Unpin + 'docker {
+    ) -> impl Stream<Item = Result<BuildEvent>> + Unpin + 'docker {
+        self.build_from_context(BuildContext::new().path("", &opts.path), opts)
+    }
+
+    /// Builds a new image like [`build`](Images::build), resolving to the result once the build
+    /// finishes
+    pub async fn build_and_wait(
+        &self,
+        opts: &BuildOptions,
+    ) -> Result<BuildResult> {
+        BuildResult::collect(self.build(opts)).await
+    }
+
+    /// Builds a new image with BuildKit, which pulls the build context in `opts.path`, secrets
+    /// and SSH agents from a session kept open for the length of the build
+    ///
+    /// Enables `RUN --mount` and the other Dockerfile features the legacy builder lacks. The
+    /// daemon reports progress as [`BuildEvent::Trace`](crate::rep::BuildEvent::Trace) events
+    /// and reads the `.dockerignore` of the context itself.
+    #[cfg(feature = "buildkit")]
+    pub fn build_with_buildkit(
+        &self,
+        opts: &BuildOptions,
+        session_opts: &SessionOptions,
+    ) -> impl Stream<Item = Result<BuildEvent>> + Unpin + 'docker {
+        let id = session::new_id();
+        let endpoint = format!("/build?{}", opts.serialize_buildkit(&id));
+        let context = std::path::PathBuf::from(&opts.path);
+        let context_options = opts.context_options();
+        let session_opts = session_opts.clone();
+
+        let docker = self.docker;
+        Box::pin(
+            async move {
+                let session = session::open(
+                    docker,
+                    id,
+                    context,
+                    &context_options.dockerfile,
+                    context_options.excludes,
+                    session_opts,
+                )
+                .await?;
+
+                let value_stream =
+                    docker.stream_post_into_values(endpoint, None, None::<iter::Empty<_>>);
+
+                // The session is closed once the build stream is dropped
+                Ok(value_stream.map_ok(move |value| {
+                    let _ = &session;
+                    BuildEvent::from(value)
+                }))
+            }
+            .try_flatten_stream(),
+        )
+    }
+
+    /// Builds a new image from a build context put together in memory, ignoring the `path` of
+    /// `opts`
+    pub fn build_from_context(
+        &self,
+        context: BuildContext,
+        opts: &BuildOptions,
+    ) -> impl Stream<Item = Result<BuildEvent>> + Unpin + 'docker {
         let mut endpoint = vec!["/build".to_owned()];
         if let Some(query) = opts.serialize() {
             endpoint.push(query)
         }
 
-        // To not tie the lifetime of `opts` to the 'stream, we do the tarring work outside of the
-        // stream. But for backwards compatability, we have to return the error inside of the
-        // stream.
-        let mut bytes = Vec::default();
-        let tar_result = tarball::dir(&mut bytes, opts.path.as_str());
+        // To not tie the lifetime of `opts` to the 'stream, we take owned copies of what the
+        // tarring needs.
+        let context_options = opts.context_options();
 
         // We must take ownership of the Docker reference. If we don't then the lifetime of 'stream
         // is incorrectly tied to `self`.
         let docker = self.docker;
         Box::pin(
             async move {
-                // Bubble up error inside the stream for backwards compatability
-                tar_result?;
+                // Fail before sending anything when a path in the context is missing. Errors found
+                // while tarring end the request body instead.
+                context.check()?;
 
+                // The context is tarred up on a blocking task while the daemon reads it
                 let value_stream = docker.stream_post_into_values(
                     endpoint.join("?"),
-                    Some((Body::from(bytes), tar())),
+                    Some((tarball::stream(context, context_options), tar())),
                     None::<iter::Empty<_>>,
                 );
 
-                Ok(value_stream)
+                Ok(value_stream.map_ok(BuildEvent::from))
             }
             .try_flatten_stream(),
         )
@@ -226,18 +379,22 @@ impl<'docker> Images<'docker> {
     pub fn pull(
         &self,
         opts: &PullOptions,
-    ) -> impl Stream<Item = Result<Value>> + Unpin + 'docker {
+    ) -> impl Stream<Item = Result<PullEvent>> + Unpin + 'docker {
         let mut path = vec!["/images/create".to_owned()];
         if let Some(query) = opts.serialize() {
             path.push(query);
         }
-        let headers = opts
-            .auth_header()
-            .map(|a| iter::once(("X-Registry-Auth", a)));
+        let auth = opts.auth_header();
+        let docker = self.docker;
 
         Box::pin(
-            self.docker
-                .stream_post_into_values(path.join("?"), None, headers),
+            async move {
+                let headers = auth.await?.map(|a| iter::once(("X-Registry-Auth", a)));
+                Ok(docker
+                    .stream_post_into_values(path.join("?"), None, headers)
+                    .map_ok(PullEvent::from))
+            }
+            .try_flatten_stream(),
         )
     }
 
@@ -246,14 +403,12 @@ impl<'docker> Images<'docker> {
     pub fn export(
         &self,
         names: Vec<&str>,
-    ) -> impl Stream<Item = Result<Vec<u8>>> + 'docker {
+    ) -> impl Stream<Item = Result<Bytes>> + 'docker {
         let params = names.iter().map(|n| ("names", *n));
         let query = form_urlencoded::Serializer::new(String::new())
             .extend_pairs(params)
             .finish();
-        self.docker
-            .stream_get(format!("/images/get?{}", query))
-            .map_ok(|c| c.to_vec())
+        self.docker.stream_get(format!("/images/get?{}", query))
     }
 
     /// imports an image or set of images from a given tarball source
@@ -261,7 +416,7 @@ impl<'docker> Images<'docker> {
     pub fn import<R>(
         self,
         mut tarball: R,
-    ) -> impl Stream<Item = Result<Value>> + Unpin + 'docker
+    ) -> impl Stream<Item = Result<BuildEvent>> + Unpin + 'docker
     where
         R: Read + Send + 'docker,
     {
@@ -276,7 +431,7 @@ impl<'docker> Images<'docker> {
                     Some((Body::from(bytes), tar())),
                     None::<iter::Empty<_>>,
                 );
-                Ok(value_stream)
+                Ok(value_stream.map_ok(BuildEvent::from))
             }
             .try_flatten_stream(),
         )
@@ -322,7 +477,7 @@ impl<'docker> Container<'docker> {
         psargs: Option<&str>,
     ) -> Result<Top> {
         let mut path = vec![format!("/containers/{}/top", self.id)];
-        if let Some(ref args) = psargs {
+        if let Some(args) = psargs {
             let encoded = form_urlencoded::Serializer::new(String::new())
                 .append_pair("ps_args", args)
                 .finish();
@@ -332,6 +487,9 @@ impl<'docker> Container<'docker> {
     }
 
     /// Returns a stream of logs emitted but the container instance
+    ///
+    /// Unless the [`StreamMode`](tty::StreamMode) is set in `opts`, the container is inspected
+    /// first to find out whether it has a TTY, in which case the raw output is yielded as stdout.
     pub fn logs(
         &self,
         opts: &LogsOptions,
@@ -341,9 +499,27 @@ impl<'docker> Container<'docker> {
             path.push(query)
         }
 
-        let stream = Box::pin(self.docker.stream_get(path.join("?")));
+        let docker = self.docker;
+        let id = self.id.clone();
+        let mode = opts.stream_mode();
+        Box::pin(
+            async move {
+                let mode = match mode {
+                    Some(mode) => mode,
+                    None => Container::new(docker, id).stream_mode().await?,
+                };
+
+                let stream = Box::pin(docker.stream_get(path.join("?")));
+
+                Ok(tty::decode_as(mode, stream))
+            }
+            .try_flatten_stream(),
+        )
+    }
 
-        Box::pin(tty::decode(stream))
+    /// Inspects the container to find out how it writes its output
+    async fn stream_mode(&self) -> Result<tty::StreamMode> {
+        Ok(tty::StreamMode::from_tty(self.inspect().await?.config.tty))
     }
 
     /// Attaches a multiplexed TCP stream to the container that can be used to read Stdout, Stderr and write Stdin.
@@ -364,10 +540,23 @@ impl<'docker> Container<'docker> {
     /// The `[TtyMultiplexer]` implements Stream for returning Stdout and Stderr chunks. It also implements `[AsyncWrite]` for writing to Stdin.
     ///
     /// The multiplexer can be split into its read and write halves with the `[split](TtyMultiplexer::split)` method
+    ///
+    /// The container is inspected first to find out whether it has a TTY, in which case its raw
+    /// output is yielded as stdout. Use `[attach_with_mode](Container::attach_with_mode)` to skip
+    /// that.
     pub async fn attach(&self) -> Result<TtyMultiPlexer<'docker>> {
+        let mode = self.stream_mode().await?;
+        self.attach_with_mode(mode).await
+    }
+
+    /// Attaches a `[TtyMultiplexer]` to a container that writes its output in `mode`
+    pub async fn attach_with_mode(
+        &self,
+        mode: tty::StreamMode,
+    ) -> Result<TtyMultiPlexer<'docker>> {
         let tcp_stream = self.attach_raw().await?;
 
-        Ok(TtyMultiPlexer::new(tcp_stream))
+        Ok(TtyMultiPlexer::new(tcp_stream, mode))
     }
 
     /// Returns a set of changes made to the container instance
@@ -378,10 +567,9 @@ impl<'docker> Container<'docker> {
     }
 
     /// Exports the current docker container into a tarball
-    pub fn export(&self) -> impl Stream<Item = Result<Vec<u8>>> + 'docker {
+    pub fn export(&self) -> impl Stream<Item = Result<Bytes>> + 'docker {
         self.docker
             .stream_get(format!("/containers/{}/export", self.id))
-            .map_ok(|c| c.to_vec())
     }
 
     /// Returns a stream of stats specific to this container instance
@@ -391,7 +579,7 @@ impl<'docker> Container<'docker> {
         let reader = Box::pin(
             self.docker
                 .stream_get(format!("/containers/{}/stats", self.id))
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+                .map_err(io::Error::other),
         )
         .into_async_read();
 
@@ -453,7 +641,7 @@ impl<'docker> Container<'docker> {
         let mut path = vec![format!("/containers/{}/kill", self.id)];
         if let Some(sig) = signal {
             let encoded = form_urlencoded::Serializer::new(String::new())
-                .append_pair("signal", &sig.to_owned())
+                .append_pair("signal", sig)
                 .finish();
             path.push(encoded)
         }
@@ -546,13 +734,13 @@ impl<'docker> Container<'docker> {
     pub fn copy_from(
         &self,
         path: &Path,
-    ) -> impl Stream<Item = Result<Vec<u8>>> + 'docker {
+    ) -> impl Stream<Item = Result<Bytes>> + 'docker {
         let path_arg = form_urlencoded::Serializer::new(String::new())
             .append_pair("path", &path.to_string_lossy())
             .finish();
 
         let endpoint = format!("/containers/{}/archive?{}", self.id, path_arg);
-        self.docker.stream_get(endpoint).map_ok(|c| c.to_vec())
+        self.docker.stream_get(endpoint)
     }
 
     /// Copy a byte slice as file into (see `bytes`) the container.
@@ -696,6 +884,17 @@ pub struct Exec<'docker> {
     id: String,
 }
 
+/// The body of `POST /exec/{id}/start`, whose `Tty` field rather than the exec's config decides
+/// whether the daemon frames the output
+fn exec_start_body(mode: tty::StreamMode) -> Body {
+    json!({
+        "Detach": false,
+        "Tty": mode == tty::StreamMode::Raw,
+    })
+    .to_string()
+    .into()
+}
+
 impl<'docker> Exec<'docker> {
     fn new<S>(
         docker: &'docker Docker,
@@ -761,6 +960,7 @@ impl<'docker> Exec<'docker> {
         // the stream. But for backwards compatability, we have to return the error inside of the
         // stream.
         let body_result = opts.serialize();
+        let mode = opts.stream_mode();
 
         // To not tie the lifetime of `container_id` to the stream, we convert it to an (owned)
         // endpoint outside of the stream.
@@ -778,11 +978,11 @@ impl<'docker> Exec<'docker> {
 
                 let stream = Box::pin(docker.stream_post(
                     format!("/exec/{}/start", exec_id),
-                    Some(("{}".into(), mime::APPLICATION_JSON)),
+                    Some((exec_start_body(mode), mime::APPLICATION_JSON)),
                     None::<iter::Empty<_>>,
                 ));
 
-                Ok(tty::decode(stream))
+                Ok(tty::decode_as(mode, stream))
             }
             .try_flatten_stream(),
         )
@@ -804,22 +1004,49 @@ impl<'docker> Exec<'docker> {
     }
 
     /// Starts this exec instance returning a multiplexed tty stream
+    ///
+    /// The exec instance is inspected first to find out whether it has a TTY, in which case its
+    /// raw output is yielded as stdout.
     pub fn start(&self) -> impl Stream<Item = Result<tty::TtyChunk>> + 'docker {
+        self.start_as(None)
+    }
+
+    /// Starts this exec instance, which writes its output in `mode`
+    pub fn start_with_mode(
+        &self,
+        mode: tty::StreamMode,
+    ) -> impl Stream<Item = Result<tty::TtyChunk>> + 'docker {
+        self.start_as(Some(mode))
+    }
+
+    fn start_as(
+        &self,
+        mode: Option<tty::StreamMode>,
+    ) -> impl Stream<Item = Result<tty::TtyChunk>> + 'docker {
         // We must take ownership of the docker reference to not needlessly tie the stream to the
         // lifetime of `self`.
         let docker = self.docker;
+        let id = self.id.clone();
         // We convert `self.id` into the (owned) endpoint outside of the stream to not needlessly
         // tie the stream to the lifetime of `self`.
         let endpoint = format!("/exec/{}/start", &self.id);
         Box::pin(
             async move {
+                let mode = match mode {
+                    Some(mode) => mode,
+                    None => {
+                        let details = Exec::new(docker, id).inspect().await?;
+                        tty::StreamMode::from_tty(details.process_config.tty)
+                    }
+                };
+
                 let stream = Box::pin(docker.stream_post(
                     endpoint,
-                    Some(("{}".into(), mime::APPLICATION_JSON)),
+                    Some((exec_start_body(mode), mime::APPLICATION_JSON)),
                     None::<iter::Empty<_>>,
                 ));
 
-                Ok(tty::decode(stream))
+                Ok(tty::decode_as(mode, stream))
             }
             .try_flatten_stream(),
         )
@@ -887,7 +1114,7 @@ impl<'docker> Networks<'docker> {
         opts: &NetworkCreateOptions,
     ) -> Result<NetworkCreateInfo> {
         let body: Body = opts.serialize()?.into();
-        let path = vec!["/networks/create".to_owned()];
+        let path = ["/networks/create".to_owned()];
 
         self.docker
             .post_json(&path.join("?"), Some((body, mime::APPLICATION_JSON)))
@@ -985,7 +1212,7 @@ impl<'docker> Volumes<'docker> {
         opts: &VolumeCreateOptions,
     ) -> Result<VolumeCreateInfo> {
         let body: Body = opts.serialize()?.into();
-        let path = vec!["/volumes/create".to_owned()];
+        let path = ["/volumes/create".to_owned()];
 
         self.docker
             .post_json(&path.join("?"), Some((body, mime::APPLICATION_JSON)))
@@ -994,13 +1221,8 @@ impl<'docker> Volumes<'docker> {
 
     /// Lists the docker volumes on the current docker host
     pub async fn list(&self) -> Result<Vec<VolumeRep>> {
-        let path = vec!["/volumes".to_owned()];
-
-        let volumes_rep = self.docker.get_json::<VolumesRep>(&path.join("?")).await?;
-        Ok(match volumes_rep.volumes {
-            Some(volumes) => volumes,
-            None => vec![],
-        })
+        let volumes_rep = self.docker.get_json::<VolumesRep>("/volumes").await?;
+        Ok(volumes_rep.volumes.unwrap_or_default())
     }
 
     /// Returns a reference to a set of operations available for a named volume
@@ -1102,10 +1324,11 @@ impl<'docker> Service<'docker> {
         opts: &ServiceOptions,
     ) -> Result<ServiceCreateInfo> {
         let body: Body = opts.serialize()?.into();
-        let path = vec!["/service/create".to_owned()];
+        let path = ["/service/create".to_owned()];
 
         let headers = opts
             .auth_header()
+            .await?
             .map(|a| iter::once(("X-Registry-Auth", a)));
 
         self.docker
@@ -1132,6 +1355,9 @@ impl<'docker> Service<'docker> {
     }
 
     /// Returns a stream of logs from a service
+    ///
+    /// The logs are decoded as multiplexed output unless another
+    /// [`StreamMode`](tty::StreamMode) is set in `opts`.
     pub fn logs(
         &self,
         opts: &LogsOptions,
@@ -1142,39 +1368,20 @@ impl<'docker> Service<'docker> {
         }
 
         let stream = Box::pin(self.docker.stream_get(path.join("?")));
+        let mode = opts.stream_mode().unwrap_or(tty::StreamMode::Multiplexed);
 
-        Box::pin(tty::decode(stream))
+        Box::pin(tty::decode_as(mode, stream))
     }
 }
 
-fn get_http_connector() -> HttpConnector {
-    let mut http = HttpConnector::new();
-    http.enforce_http(false);
-
-    http
-}
-
-#[cfg(feature = "tls")]
-fn get_docker_for_tcp(tcp_host_str: String) -> Docker {
-    let http = get_http_connector();
-    if let Ok(ref certs) = env::var("DOCKER_CERT_PATH") {
-        // fixme: don't unwrap before you know what's in the box
-        // https://github.com/hyperium/hyper/blob/master/src/net.rs#L427-L428
-        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
-        connector.set_cipher_list("DEFAULT").unwrap();
-        let cert = &format!("{}/cert.pem", certs);
-        let key = &format!("{}/key.pem", certs);
-        connector
-            .set_certificate_file(&Path::new(cert), SslFiletype::PEM)
-            .unwrap();
-        connector
-            .set_private_key_file(&Path::new(key), SslFiletype::PEM)
-            .unwrap();
-        if env::var("DOCKER_TLS_VERIFY").is_ok() {
-            let ca = &format!("{}/ca.pem", certs);
-            connector.set_ca_file(&Path::new(ca)).unwrap();
-        }
-
+#[cfg(any(feature = "tls", feature = "rustls-tls"))]
+fn get_docker_for_tcp(
+    tcp_host_str: String,
+    tls: Option<TlsConfig>,
+    settings: &ClientSettings,
+) -> std::result::Result<Docker, ConfigError> {
+    let http = settings.http_connector();
+    if let Some(tls) = tls {
         // If we are attempting to connec to the docker daemon via tcp
         // we need to convert the scheme to `https` to let hyper connect.
         // Otherwise, hyper will reject the connection since it does not
@@ -1185,52 +1392,136 @@ fn get_docker_for_tcp(tcp_host_str: String) -> Docker {
             tcp_host_str
         };
 
-        Docker {
-            transport: Transport::EncryptedTcp {
-                client: Client::builder()
-                    .build(HttpsConnector::with_connector(http, connector).unwrap()),
-                host: tcp_host_str,
-            },
-        }
+        Ok(Docker::from_transport(Transport::EncryptedTcp {
+            client: settings.client().build(tls.connector(http)?),
+            host: tcp_host_str,
+        }))
     } else {
-        Docker {
-            transport: Transport::Tcp {
-                client: Client::builder().build(http),
-                host: tcp_host_str,
-            },
-        }
+        Ok(Docker::from_transport(Transport::Tcp {
+            client: settings.client().build(http),
+            host: tcp_host_str.replace("tcp://", "http://"),
+        }))
     }
 }
 
-#[cfg(not(feature = "tls"))]
-fn get_docker_for_tcp(tcp_host_str: String) -> Docker {
-    let http = get_http_connector();
-    Docker {
-        transport: Transport::Tcp {
-            client: Client::builder().build(http),
-            host: tcp_host_str,
-        },
-    }
+#[cfg(not(any(feature = "tls", feature = "rustls-tls")))]
+fn get_docker_for_tcp(
+    tcp_host_str: String,
+    tls: Option<TlsConfig>,
+    settings: &ClientSettings,
+) -> std::result::Result<Docker, ConfigError> {
+    if tls.is_some() || tcp_host_str.starts_with("https://") {
+        return Err(ConfigError::UnsupportedScheme("https".to_owned()));
+    }
+    let http = settings.http_connector();
+    Ok(Docker::from_transport(Transport::Tcp {
+        client: settings.client().build(http),
+        host: tcp_host_str.replace("tcp://", "http://"),
+    }))
 }
 
 // https://docs.docker.com/reference/api/docker_remote_api_v1.17/
 impl Docker {
     /// constructs a new Docker instance for a docker host listening at a url specified by an env var `DOCKER_HOST`,
     /// falling back on unix:///var/run/docker.sock
+    ///
+    /// # Panics
+    ///
+    /// Panics if `DOCKER_HOST` or the TLS settings can't be used, see [`Docker::try_new`].
     pub fn new() -> Docker {
-        match env::var("DOCKER_HOST").ok() {
-            Some(host) => {
-                #[cfg(feature = "unix-socket")]
-                if let Some(path) = host.strip_prefix("unix://") {
-                    return Docker::unix(path);
-                }
-                let host = host.parse().expect("invalid url");
-                Docker::host(host)
+        Docker::try_new().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// constructs a new Docker instance like [`Docker::new`], returning an error if
+    /// `DOCKER_HOST` or the certificates in `DOCKER_CERT_PATH` can't be used
+    pub fn try_new() -> std::result::Result<Docker, ConfigError> {
+        Docker::from_docker_host()
+    }
+
+    /// constructs a new Docker instance the way the docker CLI picks a daemon
+    ///
+    /// `DOCKER_HOST` takes precedence, followed by the context named by `DOCKER_CONTEXT` and
+    /// then the `currentContext` of `config.json`. Without any of them the default socket is
+    /// used.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the selected context or host can't be used, see [`Docker::try_from_env`].
+    pub fn from_env() -> Docker {
+        Docker::try_from_env().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// constructs a new Docker instance like [`Docker::from_env`], returning an error if the
+    /// selected context or host can't be used
+    pub fn try_from_env() -> std::result::Result<Docker, ConfigError> {
+        if env::var_os("DOCKER_HOST").is_some() {
+            return Docker::from_docker_host();
+        }
+        match Context::current()? {
+            Some(name) => Docker::from_context(&name),
+            None => Docker::from_docker_host(),
+        }
+    }
+
+    /// constructs a new Docker instance for the named `docker context`
+    ///
+    /// The context is looked up in the `contexts` folder of `$DOCKER_CONFIG` or `~/.docker`,
+    /// along with its TLS material. The `default` context uses `DOCKER_HOST` or the default
+    /// socket.
+    pub fn from_context(name: &str) -> std::result::Result<Docker, ConfigError> {
+        if name == DEFAULT_CONTEXT {
+            return Docker::from_docker_host();
+        }
+        let context = Context::load(name)?;
+        Docker::from_host_str(&context.host, context.tls(), &ClientSettings::default())
+    }
+
+    /// Connects to `DOCKER_HOST`, or the default socket if it is unset
+    fn from_docker_host() -> std::result::Result<Docker, ConfigError> {
+        match env::var("DOCKER_HOST") {
+            Ok(host) => {
+                Docker::from_host_str(&host, TlsConfig::from_env(), &ClientSettings::default())
             }
             #[cfg(feature = "unix-socket")]
-            None => Docker::unix("/var/run/docker.sock"),
+            Err(_) => Ok(Docker::unix("/var/run/docker.sock")),
             #[cfg(not(feature = "unix-socket"))]
-            None => panic!("Unix socket support is disabled"),
+            Err(_) => Err(ConfigError::UnsupportedScheme("unix".to_owned())),
+        }
+    }
+
+    /// Connects to a daemon address in `DOCKER_HOST` form
+    fn from_host_str(
+        host: &str,
+        tls: Option<TlsConfig>,
+        settings: &ClientSettings,
+    ) -> std::result::Result<Docker, ConfigError> {
+        let invalid = || ConfigError::InvalidUrl(host.to_owned());
+        let scheme = &host[..host.find("://").ok_or_else(invalid)?];
+
+        match scheme {
+            #[cfg(feature = "unix-socket")]
+            "unix" => Ok(Docker::unix_with(&host[scheme.len() + 3..], settings)),
+            #[cfg(not(feature = "unix-socket"))]
+            "unix" => Err(ConfigError::UnsupportedScheme(scheme.to_owned())),
+            #[cfg(feature = "ssh")]
+            "ssh" => {
+                let uri: Uri = host.parse().map_err(|_| invalid())?;
+                Ok(Docker::ssh_with(SshConnector::new(&uri)?, settings))
+            }
+            "tcp" | "http" | "https" => {
+                let uri: Uri = host.parse().map_err(|_| invalid())?;
+                let authority = uri.authority().ok_or_else(invalid)?;
+                let tcp_host_str = match (scheme, uri.port_u16()) {
+                    // the docker CLI defaults to the registered docker ports
+                    ("tcp", None) if tls.is_some() => format!("https://{}:2376", authority),
+                    ("tcp", None) => format!("http://{}:2375", authority),
+                    ("tcp", Some(_)) if tls.is_some() => format!("https://{}", authority),
+                    ("tcp", Some(_)) => format!("http://{}", authority),
+                    _ => format!("{}://{}", scheme, authority),
+                };
+                get_docker_for_tcp(tcp_host_str, tls, settings)
+            }
+            _ => Err(ConfigError::UnsupportedScheme(scheme.to_owned())),
         }
     }
 
@@ -1241,39 +1532,139 @@ impl Docker {
     where
         S: Into<String>,
     {
+        Docker::unix_with(socket_path, &ClientSettings::default())
+    }
+
+    #[cfg(feature = "unix-socket")]
+    fn unix_with<S>(
+        socket_path: S,
+        settings: &ClientSettings,
+    ) -> Docker
+    where
+        S: Into<String>,
+    {
+        // idle connections are kept alive for reuse. Upgraded (attach) connections and
+        // streams dropped before their end are never handed back to the pool
+        Docker::from_transport(Transport::Unix {
+            client: settings.client().build(UnixConnector),
+            path: socket_path.into(),
+        })
+    }
+
+    /// Creates a new docker instance for a docker host reached over ssh
+    ///
+    /// Every connection runs the command of `connector`, `ssh ... docker system dial-stdio` for
+    /// connectors made with [`SshConnector::new`].
+    #[cfg(feature = "ssh")]
+    pub fn ssh(connector: SshConnector) -> Docker {
+        Docker::ssh_with(connector, &ClientSettings::default())
+    }
+
+    #[cfg(feature = "ssh")]
+    fn ssh_with(
+        connector: SshConnector,
+        settings: &ClientSettings,
+    ) -> Docker {
+        Docker::from_transport(Transport::Ssh {
+            command: connector.to_string(),
+            client: settings.client().build(connector),
+        })
+    }
+
+    /// Wraps a transport with the default settings
+    fn from_transport(transport: Transport) -> Docker {
         Docker {
-            transport: Transport::Unix {
-                client: Client::builder()
-                    .pool_max_idle_per_host(0)
-                    .build(UnixConnector),
-                path: socket_path.into(),
-            },
+            transport,
+            api_version: VersionMode::Unversioned,
+            headers: vec![("User-Agent", DEFAULT_USER_AGENT.to_owned())],
+            timeout: None,
         }
     }
 
+    /// Returns a builder for configuring the connection to the daemon
+    pub fn builder() -> DockerBuilder {
+        DockerBuilder::default()
+    }
+
     /// constructs a new Docker instance for docker host listening at the given host url
+    ///
+    /// # Panics
+    ///
+    /// Panics if the url or the TLS settings can't be used, see [`Docker::try_host`].
     pub fn host(host: Uri) -> Docker {
-        let tcp_host_str = format!(
-            "{}://{}:{}",
-            host.scheme_str().unwrap(),
-            host.host().unwrap().to_owned(),
-            host.port_u16().unwrap_or(80)
-        );
-
-        match host.scheme_str() {
-            #[cfg(feature = "unix-socket")]
-            Some("unix") => Docker {
-                transport: Transport::Unix {
-                    client: Client::builder().build(UnixConnector),
-                    path: host.path().to_owned(),
-                },
-            },
+        Docker::try_host(host).unwrap_or_else(|e| panic!("{}", e))
+    }
 
-            #[cfg(not(feature = "unix-socket"))]
-            Some("unix") => panic!("Unix socket support is disabled"),
+    /// constructs a new Docker instance like [`Docker::host`], returning an error for urls
+    /// without a scheme or host, unsupported schemes, and unusable certificates in
+    /// `DOCKER_CERT_PATH`
+    pub fn try_host(host: Uri) -> std::result::Result<Docker, ConfigError> {
+        // unix urls keep the socket in their path, which `Uri` needs an authority in front of
+        let host_str = match host.scheme_str() {
+            Some("unix") => format!("unix://{}", host.path()),
+            _ => host.to_string(),
+        };
+        Docker::from_host_str(&host_str, TlsConfig::from_env(), &ClientSettings::default())
+    }
 
-            _ => get_docker_for_tcp(tcp_host_str),
-        }
+    /// constructs a new Docker instance that reaches the docker daemon through a custom connector
+    ///
+    /// `connector` can be any hyper connector, or more generally any `Service<Uri>` yielding a
+    /// tokio IO stream that implements hyper's `Connection`. `base_uri` provides the scheme and authority used for requests, e.g.
+    /// `http://docker`.
+    pub fn with_connector<C>(
+        connector: C,
+        base_uri: Uri,
+    ) -> Docker
+    where
+        C: hyper::service::Service<Uri> + Clone + Send + Sync + 'static,
+        C::Response: tokio::io::AsyncRead
+            + tokio::io::AsyncWrite
+            + hyper::client::connect::Connection
+            + Unpin
+            + Send
+            + 'static,
+        C::Future: Send + 'static,
+        C::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        Docker::from_transport(Transport::Custom {
+            client: Client::builder().build(BoxedConnector::new(connector)),
+            host: base_uri.to_string().trim_end_matches('/').to_owned(),
+        })
+    }
+
+    /// Pins the Engine API version used for all requests, e.g. `/v1.41/containers/json`
+    pub fn with_api_version(
+        mut self,
+        version: ApiVersion,
+    ) -> Docker {
+        self.api_version = VersionMode::Pinned(version);
+        self
+    }
+
+    /// Negotiates the Engine API version with the daemon before the first request
+    ///
+    /// The `Api-Version` header of `/_ping` is compared with [`ApiVersion::LATEST`] and the lower
+    /// of the two is used for all subsequent requests. Daemons too old to advertise a version
+    /// are spoken to without a version prefix.
+    pub fn with_negotiated_api_version(mut self) -> Docker {
+        self.api_version = VersionMode::Negotiated(Arc::default());
+        self
+    }
+
+    /// Returns the Engine API version requests are sent with, negotiating it first if needed
+    ///
+    /// `None` means requests are unversioned.
+    pub async fn api_version(&self) -> Result<Option<ApiVersion>> {
+        let negotiated = match &self.api_version {
+            VersionMode::Unversioned => return Ok(None),
+            VersionMode::Pinned(version) => return Ok(Some(*version)),
+            VersionMode::Negotiated(negotiated) => negotiated,
+        };
+        negotiated
+            .get_or_try_init(|| async { Ok(negotiate(&self.ping().await?)) })
+            .await
+            .copied()
     }
 
     /// Exports an interface for interacting with docker images
@@ -1309,9 +1700,48 @@ impl Docker {
         self.get_json("/info").await
     }
 
-    /// Returns a simple ping response indicating the docker daemon is accessible
-    pub async fn ping(&self) -> Result<String> {
-        self.get("/_ping").await
+    /// Pings the docker daemon, returning the details it advertises in the response headers
+    pub async fn ping(&self) -> Result<PingInfo> {
+        self.ping_with(Method::GET).await
+    }
+
+    /// Pings the docker daemon with a `HEAD` request, which skips the response body
+    ///
+    /// Requires API version 1.40 or later on the daemon.
+    pub async fn ping_head(&self) -> Result<PingInfo> {
+        self.ping_with(Method::HEAD).await
+    }
+
+    async fn ping_with(
+        &self,
+        method: Method,
+    ) -> Result<PingInfo> {
+        // `/_ping` is served unversioned, so it can be used before negotiating a version
+        let headers = self
+            .timed(
+                self.transport
+                    .request_headers(method, "/_ping", self.headers(Headers::None)),
+            )
+            .await?;
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned)
+        };
+        let ping = PingInfo {
+            api_version: header("Api-Version").and_then(|v| v.parse().ok()),
+            os_type: header("OSType"),
+            experimental: header("Docker-Experimental").as_deref() == Some("true"),
+            builder_version: header("Builder-Version"),
+            swarm: header("Swarm"),
+        };
+
+        if let VersionMode::Negotiated(negotiated) = &self.api_version {
+            // fails if already negotiated, or while the negotiating ping is in flight
+            let _ = negotiated.set(negotiate(&ping));
+        }
+        Ok(ping)
     }
 
     /// Returns a stream of docker events
@@ -1323,11 +1753,8 @@ impl Docker {
         if let Some(query) = opts.serialize() {
             path.push(query);
         }
-        let reader = Box::pin(
-            self.stream_get(path.join("?"))
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
-        )
-        .into_async_read();
+        let reader =
+            Box::pin(self.stream_get(path.join("?")).map_err(io::Error::other)).into_async_read();
 
         let codec = futures_codec::LinesCodec {};
 
@@ -1344,22 +1771,40 @@ impl Docker {
     // Utility functions to make requests
     //
 
-    async fn get(
+    /// Prefixes `endpoint` with the API version in use, making sure the endpoint exists in that
+    /// version
+    async fn versioned(
         &self,
         endpoint: &str,
     ) -> Result<String> {
-        self.transport
-            .request(Method::GET, endpoint, Payload::None, Headers::None)
-            .await
+        let version = match self.api_version().await? {
+            Some(version) => version,
+            None => return Ok(endpoint.to_owned()),
+        };
+        if let Some(required) = ApiVersion::required_for(endpoint) {
+            if version < required {
+                return Err(Error::UnsupportedApiVersion {
+                    endpoint: endpoint.split('?').next().unwrap_or_default().to_owned(),
+                    required,
+                    current: version,
+                });
+            }
+        }
+        Ok(format!("/v{}{}", version, endpoint))
     }
 
     async fn get_json<T: serde::de::DeserializeOwned>(
         &self,
         endpoint: &str,
     ) -> Result<T> {
+        let endpoint = self.versioned(endpoint).await?;
         let raw_string = self
-            .transport
-            .request(Method::GET, endpoint, Payload::None, Headers::None)
+            .timed(self.transport.request(
+                Method::GET,
+                endpoint,
+                Payload::None,
+                self.headers(Headers::None),
+            ))
             .await?;
 
         Ok(serde_json::from_str::<T>(&raw_string)?)
@@ -1370,9 +1815,14 @@ impl Docker {
         endpoint: &str,
         body: Option<(Body, Mime)>,
     ) -> Result<String> {
-        self.transport
-            .request(Method::POST, endpoint, body, Headers::None)
-            .await
+        let endpoint = self.versioned(endpoint).await?;
+        self.timed(self.transport.request(
+            Method::POST,
+            endpoint,
+            body,
+            self.headers(Headers::None),
+        ))
+        .await
     }
 
     async fn put(
@@ -1380,9 +1830,12 @@ impl Docker {
         endpoint: &str,
         body: Option<(Body, Mime)>,
     ) -> Result<String> {
-        self.transport
-            .request(Method::PUT, endpoint, body, Headers::None)
-            .await
+        let endpoint = self.versioned(endpoint).await?;
+        self.timed(
+            self.transport
+                .request(Method::PUT, endpoint, body, self.headers(Headers::None)),
+        )
+        .await
     }
 
     async fn post_json<T, B>(
@@ -1394,9 +1847,14 @@ impl Docker {
         T: serde::de::DeserializeOwned,
         B: Into<Body>,
     {
+        let endpoint = self.versioned(endpoint.as_ref()).await?;
         let string = self
-            .transport
-            .request(Method::POST, endpoint, body, Headers::None)
+            .timed(self.transport.request(
+                Method::POST,
+                endpoint,
+                body,
+                self.headers(Headers::None),
+            ))
             .await?;
 
         Ok(serde_json::from_str::<T>(&string)?)
@@ -1413,9 +1871,12 @@ impl Docker {
         B: Into<Body>,
         H: IntoIterator<Item = (&'static str, String)> + 'a,
     {
+        let endpoint = self.versioned(endpoint.as_ref()).await?;
         let string = self
-            .transport
-            .request(Method::POST, endpoint, body, headers)
+            .timed(
+                self.transport
+                    .request(Method::POST, endpoint, body, self.headers(headers)),
+            )
             .await?;
 
         Ok(serde_json::from_str::<T>(&string)?)
@@ -1425,18 +1886,28 @@ impl Docker {
         &self,
         endpoint: &str,
     ) -> Result<String> {
-        self.transport
-            .request(Method::DELETE, endpoint, Payload::None, Headers::None)
-            .await
+        let endpoint = self.versioned(endpoint).await?;
+        self.timed(self.transport.request(
+            Method::DELETE,
+            endpoint,
+            Payload::None,
+            self.headers(Headers::None),
+        ))
+        .await
     }
 
     async fn delete_json<T: serde::de::DeserializeOwned>(
         &self,
         endpoint: &str,
     ) -> Result<T> {
+        let endpoint = self.versioned(endpoint).await?;
         let string = self
-            .transport
-            .request(Method::DELETE, endpoint, Payload::None, Headers::None)
+            .timed(self.transport.request(
+                Method::DELETE,
+                endpoint,
+                Payload::None,
+                self.headers(Headers::None),
+            ))
             .await?;
 
         Ok(serde_json::from_str::<T>(&string)?)
@@ -1450,17 +1921,23 @@ impl Docker {
         endpoint: impl AsRef<str> + 'a,
         body: Option<(Body, Mime)>,
         headers: Option<H>,
-    ) -> impl Stream<Item = Result<hyper::body::Bytes>> + 'a
+    ) -> impl Stream<Item = Result<Bytes>> + 'a
     where
         H: IntoIterator<Item = (&'static str, String)> + 'a,
     {
-        self.transport
-            .stream_chunks(Method::POST, endpoint, body, headers)
+        async move {
+            let endpoint = self.versioned(endpoint.as_ref()).await?;
+            Ok(self
+                .transport
+                .stream_chunks(Method::POST, endpoint, body, self.headers(headers)))
+        }
+        .try_flatten_stream()
     }
 
     /// Send a streaming post request that returns a stream of JSON values
     ///
-    /// Assumes that each received chunk contains one or more JSON values
+    /// Values may be split across or share chunks of the response body. Errors the daemon
+    /// reports along the way are turned into [`Error::Stream`] items.
     fn stream_post_into_values<'a, H>(
         &'a self,
         endpoint: impl AsRef<str> + 'a,
@@ -1470,27 +1947,24 @@ impl Docker {
     where
         H: IntoIterator<Item = (&'static str, String)> + 'a,
     {
-        self.stream_post(endpoint, body, headers)
-            .and_then(|chunk| async move {
-                let stream = futures_util::stream::iter(
-                    serde_json::Deserializer::from_slice(&chunk)
-                        .into_iter()
-                        .collect::<Vec<_>>(),
-                )
-                .map_err(Error::from);
-
-                Ok(stream)
-            })
-            .try_flatten()
+        json::decode(Box::pin(self.stream_post(endpoint, body, headers)))
+            .and_then(|value| future::ready(json::check_error(value)))
     }
 
     fn stream_get<'a>(
         &'a self,
         endpoint: impl AsRef<str> + Unpin + 'a,
-    ) -> impl Stream<Item = Result<hyper::body::Bytes>> + 'a {
-        let headers = Some(Vec::default());
-        self.transport
-            .stream_chunks(Method::GET, endpoint, Option::<(Body, Mime)>::None, headers)
+    ) -> impl Stream<Item = Result<Bytes>> + 'a {
+        async move {
+            let endpoint = self.versioned(endpoint.as_ref()).await?;
+            Ok(self.transport.stream_chunks(
+                Method::GET,
+                endpoint,
+                Option::<(Body, Mime)>::None,
+                self.headers(Headers::None),
+            ))
+        }
+        .try_flatten_stream()
     }
 
     async fn stream_post_upgrade<'a>(
@@ -1498,10 +1972,78 @@ impl Docker {
         endpoint: impl AsRef<str> + 'a,
         body: Option<(Body, Mime)>,
     ) -> Result<impl futures_util::io::AsyncRead + futures_util::io::AsyncWrite + 'a> {
-        self.transport
-            .stream_upgrade(Method::POST, endpoint, body)
-            .await
+        let endpoint = self.versioned(endpoint.as_ref()).await?;
+        self.timed(self.transport.stream_upgrade(
+            Method::POST,
+            endpoint,
+            body,
+            self.headers(Headers::None),
+        ))
+        .await
+    }
+
+    /// Hijacks the connection of a POST to `endpoint` for a protocol named by the `Upgrade`
+    /// header
+    #[cfg(feature = "buildkit")]
+    async fn upgrade<H>(
+        &self,
+        endpoint: &str,
+        headers: H,
+    ) -> Result<hyper::upgrade::Upgraded>
+    where
+        H: IntoIterator<Item = (&'static str, String)>,
+    {
+        let endpoint = self.versioned(endpoint).await?;
+        self.timed(self.transport.stream_upgrade_tokio(
+            Method::POST,
+            endpoint,
+            Option::<(Body, Mime)>::None,
+            self.headers(Some(headers)),
+        ))
+        .await
+    }
+
+    /// Adds the headers sent with every request to the headers of a request
+    fn headers<H>(
+        &self,
+        headers: Option<H>,
+    ) -> Headers
+    where
+        H: IntoIterator<Item = (&'static str, String)>,
+    {
+        Some(
+            self.headers
+                .iter()
+                .cloned()
+                .chain(headers.into_iter().flatten())
+                .collect(),
+        )
     }
+
+    /// Fails `request` with a `TimedOut` IO error if it takes longer than the configured
+    /// request timeout
+    async fn timed<T>(
+        &self,
+        request: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, request)
+                .await
+                .unwrap_or_else(|_| {
+                    Err(Error::IO(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "request timed out",
+                    )))
+                }),
+            None => request.await,
+        }
+    }
+}
+
+/// Picks the API version to use with a daemon, the lower of the daemon's and this crate's
+fn negotiate(ping: &PingInfo) -> Option<ApiVersion> {
+    ping.api_version
+        .map(|daemon| daemon.min(ApiVersion::LATEST))
 }
 
 impl Default for Docker {
@@ -1538,4 +2080,97 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn try_host_errors() {
+        use super::Docker;
+        use crate::errors::ConfigError;
+
+        assert!(matches!(
+            Docker::try_host("localhost:2375".parse().unwrap()),
+            Err(ConfigError::InvalidUrl(_))
+        ));
+        assert!(matches!(
+            Docker::try_host("fd://localhost".parse().unwrap()),
+            Err(ConfigError::UnsupportedScheme(scheme)) if scheme == "fd"
+        ));
+        match Docker::try_host("tcp://localhost:2375".parse().unwrap())
+            .unwrap()
+            .transport
+        {
+            crate::transport::Transport::Tcp { host, .. } => {
+                assert_eq!(host, "http://localhost:2375")
+            }
+            _ => panic!("Expected transport to be http."),
+        }
+        // like DOCKER_HOST, tcp urls without a port use the registered docker port
+        match Docker::try_host("tcp://localhost".parse().unwrap())
+            .unwrap()
+            .transport
+        {
+            crate::transport::Transport::Tcp { host, .. } => {
+                assert_eq!(host, "http://localhost:2375")
+            }
+            _ => panic!("Expected transport to be http."),
+        }
+    }
+
+    #[test]
+    fn builder_requires_certificates_in_tls_dir() {
+        use super::Docker;
+        use crate::errors::ConfigError;
+
+        let dir = std::env::temp_dir().join("shiplift-no-such-cert-dir");
+        let result = Docker::builder()
+            .host("tcp://127.0.0.1:2376")
+            .tls_cert_dir(&dir)
+            .build();
+        assert!(matches!(
+            result,
+            Err(ConfigError::MissingCertificate(path)) if path == dir.join("cert.pem")
+        ));
+
+        // skipping verification still asks for an encrypted connection
+        let result = Docker::builder()
+            .host("tcp://127.0.0.1")
+            .tls_skip_verify()
+            .tls_cert_dir(&dir)
+            .build();
+        assert!(matches!(result, Err(ConfigError::MissingCertificate(_))));
+    }
+
+    #[test]
+    fn tcp_host_string() {
+        use super::Docker;
+        use crate::transport::Transport;
+
+        let d = Docker::from_host_str("tcp://127.0.0.1", None, &Default::default()).unwrap();
+        match d.transport {
+            Transport::Tcp { host, .. } => assert_eq!(host, "http://127.0.0.1:2375"),
+            _ => panic!("Expected transport to be http."),
+        }
+
+        let d = Docker::from_host_str("http://localhost:8000", None, &Default::default()).unwrap();
+        match d.transport {
+            Transport::Tcp { host, .. } => assert_eq!(host, "http://localhost:8000"),
+            _ => panic!("Expected transport to be http."),
+        }
+
+        #[cfg(feature = "ssh")]
+        match Docker::from_host_str("ssh://deploy@build-01", None, &Default::default())
+            .unwrap()
+            .transport
+        {
+            Transport::Ssh { command, .. } => assert_eq!(
+                command,
+                "ssh -l deploy -- build-01 docker system dial-stdio"
+            ),
+            _ => panic!("Expected transport to be ssh."),
+        }
+
+        assert!(matches!(
+            Docker::from_host_str("npipe:////./pipe/docker_engine", None, &Default::default()),
+            Err(crate::errors::ConfigError::UnsupportedScheme(scheme)) if scheme == "npipe"
+        ));
+    }
 }
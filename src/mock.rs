@@ -0,0 +1,3457 @@
+//! An in-process fake docker daemon for testing code built on shiplift
+//!
+//! [`MockDaemon`] serves the parts of the Engine API that shiplift calls from an in-memory model
+//! of containers, images, networks and volumes. It listens on a temporary unix socket or a
+//! loopback port, so a regular [`Docker`] client can be pointed at it in CI where no real
+//! daemon is available.
+//!
+//! The model is intentionally small:
+//!
+//! * pulling any image succeeds and streams JSON progress for two fake layers, and pushing
+//!   any local image succeeds. [`MockDaemon::require_auth`] makes both insist on credentials
+//! * a started container behaves like `cat`: bytes written to it through `attach` are echoed
+//!   back on stdout, and [`MockDaemon::write_log`] can be used to emit arbitrary output
+//! * exec instances print their command line on stdout and exit with status 0
+//! * builds interpret the Dockerfile just enough to emit realistic progress. `RUN echo ...`
+//!   prints its arguments and `RUN exit <n>` fails the build
+//! * BuildKit builds pull the Dockerfile and context through the client's session. `COPY`
+//!   prints what it copied, `RUN cat /run/secrets/<id>` prints a secret mounted with
+//!   `--mount=type=secret` and `RUN --mount=type=ssh ssh-add -l` asks the forwarded agent for
+//!   its identities
+//!
+//! # examples
+//!
+//! ```no_run
+//! # async {
+//! use shiplift::{mock::MockDaemon, ContainerOptions};
+//!
+//! let daemon = MockDaemon::tcp().await.unwrap();
+//! daemon.add_image("busybox:latest");
+//!
+//! let docker = daemon.docker();
+//! let info = docker
+//!     .containers()
+//!     .create(&ContainerOptions::builder("busybox").build())
+//!     .await
+//!     .unwrap();
+//! docker.containers().get(&info.id).start().await.unwrap();
+//! # };
+//! ```
+
+#[cfg(feature = "buildkit")]
+use crate::session;
+use crate::{tty::TtyChunk, version::ApiVersion, Docker};
+use flate2::read::GzDecoder;
+use futures_util::stream::{self, Stream, StreamExt};
+use hyper::{
+    body::Bytes, header, server::conn::Http, service::service_fn, Body, Method, Request, Response,
+    StatusCode,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    convert::Infallible,
+    io::{self, Read},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    sync::broadcast,
+    task::JoinHandle,
+};
+use url::form_urlencoded;
+
+/// Oldest API version the mock daemon accepts
+const MIN_API_VERSION: ApiVersion = ApiVersion::new(1, 12);
+
+/// A fake docker daemon serving the Engine API from memory
+///
+/// The daemon runs on the current tokio runtime and shuts down when dropped.
+pub struct MockDaemon {
+    state: Shared,
+    address: Address,
+    server: JoinHandle<()>,
+}
+
+enum Address {
+    Tcp(SocketAddr),
+    #[cfg(feature = "unix-socket")]
+    Unix(PathBuf),
+}
+
+impl MockDaemon {
+    /// Starts a daemon listening on a random loopback port
+    pub async fn tcp() -> io::Result<MockDaemon> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let address = Address::Tcp(listener.local_addr()?);
+        let state = Shared::default();
+
+        let server = tokio::spawn({
+            let state = state.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    serve(stream, state.clone());
+                }
+            }
+        });
+
+        Ok(MockDaemon {
+            state,
+            address,
+            server,
+        })
+    }
+
+    /// Starts a daemon listening on a unix socket in the temporary directory
+    #[cfg(feature = "unix-socket")]
+    pub async fn unix() -> io::Result<MockDaemon> {
+        static SOCKETS: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "shiplift-mock-{}-{}.sock",
+            std::process::id(),
+            SOCKETS.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path)?;
+        let state = Shared::default();
+
+        let server = tokio::spawn({
+            let state = state.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    serve(stream, state.clone());
+                }
+            }
+        });
+
+        Ok(MockDaemon {
+            state,
+            address: Address::Unix(path),
+            server,
+        })
+    }
+
+    /// The address of this daemon in `DOCKER_HOST` form, e.g. `unix:///tmp/shiplift-mock.sock`
+    /// or `http://127.0.0.1:2375`
+    pub fn host(&self) -> String {
+        match &self.address {
+            Address::Tcp(addr) => format!("http://{}", addr),
+            #[cfg(feature = "unix-socket")]
+            Address::Unix(path) => format!("unix://{}", path.display()),
+        }
+    }
+
+    /// Returns a client connected to this daemon
+    pub fn docker(&self) -> Docker {
+        match &self.address {
+            Address::Tcp(_) => Docker::host(self.host().parse().unwrap()),
+            #[cfg(feature = "unix-socket")]
+            Address::Unix(path) => Docker::unix(path.to_string_lossy()),
+        }
+    }
+
+    /// Adds an image with the given reference to the daemon and returns its id
+    pub fn add_image<S>(
+        &self,
+        reference: S,
+    ) -> String
+    where
+        S: AsRef<str>,
+    {
+        let mut state = self.state.lock();
+        let id = state.new_id();
+        let tag = normalize_reference(reference.as_ref());
+        state.untag(&tag);
+        state.images.insert(
+            id.clone(),
+            MockImage::new(id.clone(), vec![tag], vec!["sh".to_owned()]),
+        );
+        format!("sha256:{}", id)
+    }
+
+    /// Emits a chunk of output from a container
+    ///
+    /// The chunk is added to the container's logs and forwarded to attached clients.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no container with the given id or name exists.
+    pub fn write_log<S>(
+        &self,
+        container: S,
+        chunk: TtyChunk,
+    ) where
+        S: AsRef<str>,
+    {
+        let (stream, bytes) = match chunk {
+            TtyChunk::StdIn(bytes) => (0, bytes),
+            TtyChunk::StdOut(bytes) => (1, bytes),
+            TtyChunk::StdErr(bytes) => (2, bytes),
+        };
+        let mut state = self.state.lock();
+        let id = state
+            .container_id(container.as_ref())
+            .unwrap_or_else(|_| panic!("no such container: {}", container.as_ref()));
+        state.output(&id, stream, bytes);
+    }
+
+    /// Sets the API version the daemon advertises and accepts, [`ApiVersion::LATEST`] by default
+    pub fn set_api_version(
+        &self,
+        version: ApiVersion,
+    ) {
+        self.state.lock().api_version = version;
+    }
+
+    /// Makes pulls and pushes fail unless the client sends these registry credentials
+    pub fn require_auth<U, P>(
+        &self,
+        username: U,
+        password: P,
+    ) where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        self.state.lock().registry_auth = Some((username.into(), password.into()));
+    }
+
+    /// Returns the requests served so far as `METHOD /path?query` lines
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().requests.clone()
+    }
+
+    /// Returns the number of client connections accepted so far
+    pub fn connections(&self) -> usize {
+        self.state.lock().connections
+    }
+
+    /// Returns the headers of the requests served so far, in the order of [`requests`]
+    ///
+    /// [`requests`]: MockDaemon::requests
+    pub fn request_headers(&self) -> Vec<header::HeaderMap> {
+        self.state.lock().request_headers.clone()
+    }
+}
+
+impl Drop for MockDaemon {
+    fn drop(&mut self) {
+        self.server.abort();
+        #[cfg(feature = "unix-socket")]
+        if let Address::Unix(path) = &self.address {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn serve<I>(
+    io: I,
+    state: Shared,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    state.lock().connections += 1;
+    let service = service_fn(move |req| handle(state.clone(), req));
+    tokio::spawn(async move {
+        let _ = Http::new()
+            .http1_only(true)
+            .serve_connection(io, service)
+            .with_upgrades()
+            .await;
+    });
+}
+
+//################################################################################
+// State
+//################################################################################
+
+#[derive(Clone, Default)]
+struct Shared(Arc<Mutex<State>>);
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+struct State {
+    containers: BTreeMap<String, MockContainer>,
+    images: BTreeMap<String, MockImage>,
+    networks: BTreeMap<String, MockNetwork>,
+    volumes: BTreeMap<String, MockVolume>,
+    execs: HashMap<String, MockExec>,
+    events: Vec<Value>,
+    event_tx: broadcast::Sender<Value>,
+    requests: Vec<String>,
+    request_headers: Vec<header::HeaderMap>,
+    connections: usize,
+    api_version: ApiVersion,
+    registry_auth: Option<(String, String)>,
+    counter: u64,
+    #[cfg(feature = "buildkit")]
+    sessions: HashMap<String, MockSession>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        let (event_tx, _) = broadcast::channel(256);
+        let mut state = State {
+            containers: BTreeMap::new(),
+            images: BTreeMap::new(),
+            networks: BTreeMap::new(),
+            volumes: BTreeMap::new(),
+            execs: HashMap::new(),
+            events: Vec::new(),
+            event_tx,
+            requests: Vec::new(),
+            request_headers: Vec::new(),
+            connections: 0,
+            api_version: ApiVersion::LATEST,
+            registry_auth: None,
+            counter: 0,
+            #[cfg(feature = "buildkit")]
+            sessions: HashMap::new(),
+        };
+        for (name, driver) in &[("bridge", "bridge"), ("host", "host"), ("none", "null")] {
+            let id = state.new_id();
+            state.networks.insert(
+                id.clone(),
+                MockNetwork {
+                    id,
+                    name: (*name).to_owned(),
+                    driver: (*driver).to_owned(),
+                    labels: Value::Null,
+                    internal: false,
+                    attachable: false,
+                    predefined: true,
+                    containers: BTreeSet::new(),
+                },
+            );
+        }
+        state
+    }
+}
+
+impl State {
+    /// Generates a unique 64 character hex id
+    fn new_id(&mut self) -> String {
+        self.counter += 1;
+        let mut x = self.counter;
+        (0..4)
+            .map(|_| {
+                // splitmix64
+                x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+                let mut z = x;
+                z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                format!("{:016x}", z ^ (z >> 31))
+            })
+            .collect()
+    }
+
+    fn container_id(
+        &self,
+        reference: &str,
+    ) -> Result<String, Fault> {
+        let reference = reference.trim_start_matches('/');
+        self.containers
+            .values()
+            .find(|c| c.id == reference || c.name == reference)
+            .or_else(|| {
+                let mut matches = self
+                    .containers
+                    .values()
+                    .filter(|c| !reference.is_empty() && c.id.starts_with(reference));
+                match (matches.next(), matches.next()) {
+                    (Some(c), None) => Some(c),
+                    _ => None,
+                }
+            })
+            .map(|c| c.id.clone())
+            .ok_or_else(|| {
+                Fault::new(
+                    StatusCode::NOT_FOUND,
+                    format!("No such container: {}", reference),
+                )
+            })
+    }
+
+    fn container(
+        &mut self,
+        reference: &str,
+    ) -> Result<&mut MockContainer, Fault> {
+        let id = self.container_id(reference)?;
+        Ok(self.containers.get_mut(&id).unwrap())
+    }
+
+    fn image_id(
+        &self,
+        reference: &str,
+    ) -> Option<String> {
+        let tag = normalize_reference(reference);
+        let bare = reference.trim_start_matches("sha256:");
+        self.images
+            .values()
+            .find(|i| i.tags.contains(&tag) || i.id == bare)
+            .or_else(|| {
+                self.images
+                    .values()
+                    .find(|i| bare.len() >= 12 && i.id.starts_with(bare))
+            })
+            .map(|i| i.id.clone())
+    }
+
+    fn image(
+        &self,
+        reference: &str,
+    ) -> Result<&MockImage, Fault> {
+        self.image_id(reference)
+            .and_then(|id| self.images.get(&id))
+            .ok_or_else(|| {
+                Fault::new(
+                    StatusCode::NOT_FOUND,
+                    format!("No such image: {}", reference),
+                )
+            })
+    }
+
+    /// Removes `tag` from whichever image currently carries it
+    fn untag(
+        &mut self,
+        tag: &str,
+    ) {
+        for image in self.images.values_mut() {
+            image.tags.retain(|t| t != tag);
+        }
+    }
+
+    fn network_id(
+        &self,
+        reference: &str,
+    ) -> Result<String, Fault> {
+        self.networks
+            .values()
+            .find(|n| n.id == reference || n.name == reference)
+            .or_else(|| {
+                self.networks
+                    .values()
+                    .find(|n| !reference.is_empty() && n.id.starts_with(reference))
+            })
+            .map(|n| n.id.clone())
+            .ok_or_else(|| {
+                Fault::new(
+                    StatusCode::NOT_FOUND,
+                    format!("network {} not found", reference),
+                )
+            })
+    }
+
+    fn output(
+        &mut self,
+        id: &str,
+        stream: u8,
+        bytes: Bytes,
+    ) {
+        if let Some(container) = self.containers.get_mut(id) {
+            container.logs.push((stream, bytes.clone()));
+            let _ = container.output.send(Output::Chunk(stream, bytes));
+        }
+    }
+
+    fn event(
+        &mut self,
+        typ: &str,
+        action: &str,
+        id: &str,
+        attributes: Value,
+    ) {
+        let (secs, nanos) = now();
+        let from = attributes.get("image").cloned().unwrap_or(Value::Null);
+        let event = json!({
+            "Type": typ,
+            "Action": action,
+            "Actor": { "ID": id, "Attributes": attributes },
+            "status": action,
+            "id": id,
+            "from": from,
+            "scope": "local",
+            "time": secs,
+            "timeNano": nanos,
+        });
+        self.events.push(event.clone());
+        let _ = self.event_tx.send(event);
+    }
+
+    fn container_event(
+        &mut self,
+        action: &str,
+        id: &str,
+    ) {
+        if let Some(c) = self.containers.get(id) {
+            let attributes = json!({ "image": c.image, "name": c.name });
+            self.event("container", action, id, attributes);
+        }
+    }
+
+    fn start(
+        &mut self,
+        id: &str,
+    ) {
+        let pid = 1000 + self.counter;
+        self.counter += 1;
+        if let Some(c) = self.containers.get_mut(id) {
+            c.status = Status::Running;
+            c.pid = pid;
+            c.started_at = rfc3339(now().0);
+        }
+        self.container_event("start", id);
+    }
+
+    fn stop(
+        &mut self,
+        id: &str,
+        exit_code: i64,
+    ) {
+        if let Some(c) = self.containers.get_mut(id) {
+            c.status = Status::Exited;
+            c.exit_code = exit_code;
+            c.pid = 0;
+            c.finished_at = rfc3339(now().0);
+            let _ = c.output.send(Output::Exited(exit_code));
+        }
+        self.container_event("die", id);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Status {
+    Created,
+    Running,
+    Paused,
+    Exited,
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Status::Created => "created",
+            Status::Running => "running",
+            Status::Paused => "paused",
+            Status::Exited => "exited",
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Output {
+    Chunk(u8, Bytes),
+    Exited(i64),
+}
+
+struct MockContainer {
+    id: String,
+    name: String,
+    image: String,
+    image_id: String,
+    config: Value,
+    created: u64,
+    status: Status,
+    exit_code: i64,
+    pid: u64,
+    started_at: String,
+    finished_at: String,
+    logs: Vec<(u8, Bytes)>,
+    files: BTreeMap<String, Vec<u8>>,
+    output: broadcast::Sender<Output>,
+}
+
+impl MockContainer {
+    fn tty(&self) -> bool {
+        self.config["Tty"].as_bool().unwrap_or(false)
+    }
+
+    fn running(&self) -> bool {
+        self.status == Status::Running || self.status == Status::Paused
+    }
+
+    fn cmd(&self) -> Vec<String> {
+        string_list(&self.config["Entrypoint"])
+            .into_iter()
+            .chain(string_list(&self.config["Cmd"]))
+            .collect()
+    }
+
+    fn status_text(&self) -> String {
+        match self.status {
+            Status::Created => "Created".to_owned(),
+            Status::Running => "Up Less than a second".to_owned(),
+            Status::Paused => "Up Less than a second (Paused)".to_owned(),
+            Status::Exited => format!("Exited ({}) Less than a second ago", self.exit_code),
+        }
+    }
+
+    fn summary(
+        &self,
+        state: &State,
+    ) -> Value {
+        json!({
+            "Id": self.id,
+            "Names": [format!("/{}", self.name)],
+            "Image": self.image,
+            "ImageID": format!("sha256:{}", self.image_id),
+            "Command": self.cmd().join(" "),
+            "Created": self.created,
+            "Ports": [],
+            "Labels": labels(&self.config["Labels"]),
+            "State": self.status.as_str(),
+            "Status": self.status_text(),
+            "HostConfig": { "NetworkMode": self.network_mode() },
+            "NetworkSettings": { "Networks": self.networks(state) },
+            "Mounts": [],
+        })
+    }
+
+    fn network_mode(&self) -> String {
+        self.config["HostConfig"]["NetworkMode"]
+            .as_str()
+            .unwrap_or("default")
+            .to_owned()
+    }
+
+    fn ip_address(&self) -> String {
+        if self.running() {
+            format!("172.17.0.{}", 2 + self.pid % 250)
+        } else {
+            String::new()
+        }
+    }
+
+    fn networks(
+        &self,
+        state: &State,
+    ) -> Value {
+        let mut networks = serde_json::Map::new();
+        for network in state.networks.values() {
+            let attached = network.containers.contains(&self.id)
+                || (network.name == "bridge"
+                    && ["default", "bridge"].contains(&self.network_mode().as_str()));
+            if attached {
+                networks.insert(
+                    network.name.clone(),
+                    json!({
+                        "NetworkID": network.id,
+                        "EndpointID": if self.running() { &self.id } else { "" },
+                        "Gateway": if self.running() { "172.17.0.1" } else { "" },
+                        "IPAddress": self.ip_address(),
+                        "IPPrefixLen": if self.running() { 16 } else { 0 },
+                        "IPv6Gateway": "",
+                        "GlobalIPv6Address": "",
+                        "GlobalIPv6PrefixLen": 0,
+                        "MacAddress": "",
+                    }),
+                );
+            }
+        }
+        Value::Object(networks)
+    }
+
+    fn details(
+        &self,
+        state: &State,
+    ) -> Value {
+        let cmd = self.cmd();
+        let config = &self.config;
+        let host_config = &config["HostConfig"];
+        json!({
+            "Id": self.id,
+            "Created": rfc3339(self.created),
+            "Path": cmd.first().cloned().unwrap_or_default(),
+            "Args": cmd.iter().skip(1).collect::<Vec<_>>(),
+            "State": {
+                "Status": self.status.as_str(),
+                "Running": self.running(),
+                "Paused": self.status == Status::Paused,
+                "Restarting": false,
+                "OOMKilled": false,
+                "Dead": false,
+                "Pid": self.pid,
+                "ExitCode": self.exit_code,
+                "Error": "",
+                "StartedAt": self.started_at,
+                "FinishedAt": self.finished_at,
+            },
+            "Image": format!("sha256:{}", self.image_id),
+            "ResolvConfPath": format!("/var/lib/docker/containers/{}/resolv.conf", self.id),
+            "HostnamePath": format!("/var/lib/docker/containers/{}/hostname", self.id),
+            "HostsPath": format!("/var/lib/docker/containers/{}/hosts", self.id),
+            "LogPath": format!("/var/lib/docker/containers/{0}/{0}-json.log", self.id),
+            "Name": format!("/{}", self.name),
+            "RestartCount": 0,
+            "Driver": "overlay2",
+            "Platform": "linux",
+            "MountLabel": "",
+            "ProcessLabel": "",
+            "AppArmorProfile": "",
+            "HostConfig": {
+                "ContainerIDFile": "",
+                "NetworkMode": self.network_mode(),
+                "Privileged": host_config["Privileged"].as_bool().unwrap_or(false),
+                "PublishAllPorts": host_config["PublishAllPorts"].as_bool().unwrap_or(false),
+                "AutoRemove": host_config["AutoRemove"].as_bool().unwrap_or(false),
+                "Binds": host_config["Binds"],
+                "CpuShares": host_config["CpuShares"].as_u64().unwrap_or(0),
+                "Memory": host_config["Memory"].as_u64().unwrap_or(0),
+                "MemorySwap": host_config["MemorySwap"].as_i64().unwrap_or(0),
+                "PortBindings": host_config["PortBindings"],
+            },
+            "Mounts": [],
+            "Config": container_config(&self.id[..12], config),
+            "NetworkSettings": {
+                "Bridge": "",
+                "Gateway": if self.running() { "172.17.0.1" } else { "" },
+                "IPAddress": self.ip_address(),
+                "IPPrefixLen": if self.running() { 16 } else { 0 },
+                "MacAddress": "",
+                "Ports": {},
+                "Networks": self.networks(state),
+            },
+        })
+    }
+}
+
+struct MockImage {
+    id: String,
+    tags: Vec<String>,
+    parent: String,
+    created: u64,
+    size: u64,
+    cmd: Vec<String>,
+    labels: Value,
+    history: Vec<String>,
+}
+
+impl MockImage {
+    fn new(
+        id: String,
+        tags: Vec<String>,
+        cmd: Vec<String>,
+    ) -> Self {
+        MockImage {
+            id,
+            tags,
+            parent: String::new(),
+            created: now().0,
+            size: 1_234_567,
+            cmd,
+            labels: Value::Null,
+            history: vec!["/bin/sh -c #(nop) ADD file:mock in / ".to_owned()],
+        }
+    }
+
+    fn summary(&self) -> Value {
+        json!({
+            "Id": format!("sha256:{}", self.id),
+            "ParentId": self.parent,
+            "RepoTags": self.tags,
+            "RepoDigests": [],
+            "Created": self.created,
+            "Size": self.size,
+            "SharedSize": -1,
+            "VirtualSize": self.size,
+            "Labels": self.labels,
+            "Containers": -1,
+        })
+    }
+
+    fn details(&self) -> Value {
+        let config = container_config("", &json!({ "Cmd": self.cmd, "Labels": self.labels }));
+        json!({
+            "Id": format!("sha256:{}", self.id),
+            "RepoTags": self.tags,
+            "RepoDigests": [],
+            "Parent": self.parent,
+            "Comment": "",
+            "Created": rfc3339(self.created),
+            "Container": "",
+            "ContainerConfig": config,
+            "DockerVersion": "20.10.5",
+            "Author": "",
+            "Config": config,
+            "Architecture": "amd64",
+            "Os": "linux",
+            "Size": self.size,
+            "VirtualSize": self.size,
+            "GraphDriver": { "Name": "overlay2", "Data": {} },
+            "RootFS": { "Type": "layers", "Layers": [format!("sha256:{}", self.id)] },
+            "Metadata": { "LastTagTime": rfc3339(self.created) },
+        })
+    }
+}
+
+struct MockNetwork {
+    id: String,
+    name: String,
+    driver: String,
+    labels: Value,
+    internal: bool,
+    attachable: bool,
+    predefined: bool,
+    containers: BTreeSet<String>,
+}
+
+impl MockNetwork {
+    fn details(&self) -> Value {
+        let containers: serde_json::Map<String, Value> = self
+            .containers
+            .iter()
+            .map(|id| {
+                (
+                    id.clone(),
+                    json!({
+                        "EndpointID": id,
+                        "MacAddress": "",
+                        "IPv4Address": "",
+                        "IPv6Address": "",
+                    }),
+                )
+            })
+            .collect();
+        json!({
+            "Name": self.name,
+            "Id": self.id,
+            "Created": rfc3339(now().0),
+            "Scope": "local",
+            "Driver": self.driver,
+            "EnableIPv6": false,
+            "IPAM": { "Driver": "default", "Options": {}, "Config": [] },
+            "Internal": self.internal,
+            "Attachable": self.attachable,
+            "Containers": containers,
+            "Options": {},
+            "Labels": self.labels,
+        })
+    }
+}
+
+struct MockVolume {
+    name: String,
+    driver: String,
+    labels: Value,
+    created: u64,
+}
+
+impl MockVolume {
+    fn details(&self) -> Value {
+        json!({
+            "Name": self.name,
+            "Driver": self.driver,
+            "Mountpoint": format!("/var/lib/docker/volumes/{}/_data", self.name),
+            "CreatedAt": rfc3339(self.created),
+            "Labels": self.labels,
+            "Scope": "local",
+            "Options": {},
+        })
+    }
+}
+
+struct MockExec {
+    id: String,
+    container: String,
+    cmd: Vec<String>,
+    tty: bool,
+    attach_stdout: bool,
+    attach_stderr: bool,
+    exit_code: Option<i64>,
+}
+
+//################################################################################
+// Routing
+//################################################################################
+
+/// An error response in the daemon's `{"message": ...}` format
+struct Fault {
+    status: StatusCode,
+    message: String,
+}
+
+impl Fault {
+    fn new<S>(
+        status: StatusCode,
+        message: S,
+    ) -> Self
+    where
+        S: Into<String>,
+    {
+        Fault {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn bad_request<S>(message: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Fault::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    fn conflict<S>(message: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Fault::new(StatusCode::CONFLICT, message)
+    }
+
+    fn into_response(self) -> Response<Body> {
+        json_response(self.status, &json!({ "message": self.message }))
+    }
+}
+
+type Reply = Result<Response<Body>, Fault>;
+
+struct Query(Vec<(String, String)>);
+
+impl Query {
+    fn parse(query: Option<&str>) -> Self {
+        Query(
+            form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+                .into_owned()
+                .collect(),
+        )
+    }
+
+    fn get(
+        &self,
+        key: &str,
+    ) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn all(
+        &self,
+        key: &str,
+    ) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    fn flag(
+        &self,
+        key: &str,
+    ) -> bool {
+        matches!(self.get(key), Some("1") | Some("true") | Some("True"))
+    }
+
+    /// Parses the JSON `filters` parameter into a map of filter name to accepted values
+    fn filters(&self) -> HashMap<String, Vec<String>> {
+        let value = self
+            .get("filters")
+            .and_then(|f| serde_json::from_str::<Value>(f).ok())
+            .unwrap_or(Value::Null);
+        let mut filters = HashMap::new();
+        if let Value::Object(map) = value {
+            for (key, values) in map {
+                let values = match values {
+                    Value::Array(list) => list
+                        .iter()
+                        .filter_map(|v| v.as_str().map(str::to_owned))
+                        .collect(),
+                    // {"label": {"a=b": true}}
+                    Value::Object(set) => set.keys().cloned().collect(),
+                    _ => Vec::new(),
+                };
+                filters.insert(key, values);
+            }
+        }
+        filters
+    }
+}
+
+async fn handle(
+    state: Shared,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    {
+        let mut state = state.lock();
+        state.requests.push(format!(
+            "{} {}",
+            req.method(),
+            req.uri()
+                .path_and_query()
+                .map(|p| p.as_str())
+                .unwrap_or_else(|| req.uri().path())
+        ));
+        state.request_headers.push(req.headers().clone());
+    }
+
+    let path = req.uri().path().to_owned();
+    let mut segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    // strip and validate the optional `/v1.xx` prefix
+    let requested = segments
+        .first()
+        .filter(|s| s.starts_with('v'))
+        .and_then(|s| s.parse::<ApiVersion>().ok());
+    if let Some(requested) = requested {
+        segments.remove(0);
+        let supported = state.lock().api_version;
+        if requested > supported {
+            return Ok(Fault::bad_request(format!(
+                "client version {} is too new. Maximum supported API version is {}",
+                requested, supported
+            ))
+            .into_response());
+        }
+        if requested < MIN_API_VERSION {
+            return Ok(Fault::bad_request(format!(
+                "client version {} is too old. Minimum supported API version is {}, please upgrade your client to a newer version",
+                requested, MIN_API_VERSION
+            ))
+            .into_response());
+        }
+    }
+
+    let reply = match segments.first() {
+        Some(&"_ping") => ping(&state, &req),
+        Some(&"version") => version(&state),
+        Some(&"info") => Ok(info(&state)),
+        Some(&"events") => events(&state, &req),
+        Some(&"containers") => containers(&state, &segments[1..], req).await,
+        Some(&"exec") => exec(&state, &segments[1..], req).await,
+        Some(&"images") => images(&state, &segments[1..], req).await,
+        Some(&"build") => build(&state, req).await,
+        #[cfg(feature = "buildkit")]
+        Some(&"session") => session(&state, req),
+        Some(&"networks") => networks(&state, &segments[1..], req).await,
+        Some(&"volumes") => volumes(&state, &segments[1..], req).await,
+        Some(&"services") | Some(&"service") | Some(&"swarm") | Some(&"nodes")
+        | Some(&"tasks") => Err(Fault::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "This node is not a swarm manager. Use \"docker swarm init\" or \"docker swarm join\" to connect this node to swarm and try again.",
+        )),
+        _ => Err(not_found(&path)),
+    };
+
+    Ok(reply.unwrap_or_else(Fault::into_response))
+}
+
+fn not_found(path: &str) -> Fault {
+    Fault::new(StatusCode::NOT_FOUND, format!("page not found: {}", path))
+}
+
+fn method_not_allowed() -> Fault {
+    Fault::new(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+}
+
+fn ping(
+    state: &Shared,
+    req: &Request<Body>,
+) -> Reply {
+    let body = if req.method() == Method::HEAD {
+        Body::empty()
+    } else {
+        Body::from("OK")
+    };
+    Ok(Response::builder()
+        .header("Api-Version", state.lock().api_version.to_string())
+        .header("OSType", "linux")
+        .header("Docker-Experimental", "false")
+        .header("Builder-Version", "1")
+        .header("Swarm", "inactive")
+        .header(header::CACHE_CONTROL, "no-cache, no-store, must-revalidate")
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(body)
+        .unwrap())
+}
+
+fn version(state: &Shared) -> Reply {
+    let api_version = state.lock().api_version;
+    Ok(json_response(
+        StatusCode::OK,
+        &json!({
+            "Version": "20.10.5",
+            "ApiVersion": api_version.to_string(),
+            "MinAPIVersion": MIN_API_VERSION.to_string(),
+            "GitCommit": "mock",
+            "GoVersion": "go1.13.15",
+            "Os": "linux",
+            "Arch": "amd64",
+            "KernelVersion": "5.10.0-mock",
+            "BuildTime": rfc3339(0),
+        }),
+    ))
+}
+
+fn info(state: &Shared) -> Response<Body> {
+    let state = state.lock();
+    let running = state.containers.values().filter(|c| c.running()).count();
+    json_response(
+        StatusCode::OK,
+        &json!({
+            "ID": "MOCK:DAEMON",
+            "Containers": state.containers.len(),
+            "ContainersRunning": running,
+            "ContainersPaused": state.containers.values().filter(|c| c.status == Status::Paused).count(),
+            "ContainersStopped": state.containers.len() - running,
+            "Images": state.images.len(),
+            "Driver": "overlay2",
+            "DriverStatus": [["Backing Filesystem", "extfs"]],
+            "DockerRootDir": "/var/lib/docker",
+            "KernelVersion": "5.10.0-mock",
+            "MemTotal": 8_589_934_592u64,
+            "MemoryLimit": true,
+            "SwapLimit": true,
+            "NCPU": 4,
+            "NEventsListener": 0,
+            "NGoroutines": 32,
+            "Name": "mock",
+            "OperatingSystem": "shiplift mock daemon",
+            "OSType": "linux",
+            "Architecture": "x86_64",
+            "ServerVersion": "20.10.5",
+            "SystemTime": rfc3339(now().0),
+        }),
+    )
+}
+
+fn events(
+    state: &Shared,
+    req: &Request<Body>,
+) -> Reply {
+    let query = Query::parse(req.uri().query());
+    let filters = query.filters();
+    let since = query.get("since").and_then(|s| s.parse::<u64>().ok());
+    let until = query.get("until").and_then(|s| s.parse::<u64>().ok());
+
+    let matches = move |event: &Value| {
+        let time = event["time"].as_u64().unwrap_or(0);
+        let accepts = |key: &str, value: &str| {
+            filters
+                .get(key)
+                .map(|accepted| accepted.iter().any(|a| a == value))
+                .unwrap_or(true)
+        };
+        since.map(|s| time >= s).unwrap_or(true)
+            && until.map(|u| time <= u).unwrap_or(true)
+            && accepts("type", event["Type"].as_str().unwrap_or_default())
+            && accepts("event", event["Action"].as_str().unwrap_or_default())
+            && (accepts("container", event["id"].as_str().unwrap_or_default())
+                || accepts(
+                    "container",
+                    event["Actor"]["Attributes"]["name"]
+                        .as_str()
+                        .unwrap_or_default(),
+                ))
+    };
+
+    let (past, rx) = {
+        let state = state.lock();
+        let past: Vec<Value> = state
+            .events
+            .iter()
+            .filter(|e| matches(e))
+            .cloned()
+            .collect();
+        // without an upper bound the stream stays open for new events, just like dockerd
+        let rx = until.is_none().then(|| state.event_tx.subscribe());
+        (past, rx)
+    };
+
+    let line = |event: &Value| Bytes::from(format!("{}\n", event));
+    let past = stream::iter(past.iter().map(line).collect::<Vec<_>>());
+    let live = stream::unfold(rx, move |rx| {
+        let matches = matches.clone();
+        async move {
+            let mut rx = rx?;
+            loop {
+                match rx.recv().await {
+                    Ok(event) if matches(&event) => return Some((line(&event), Some(rx))),
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+
+    Ok(stream_response("application/json", past.chain(live)))
+}
+
+//################################################################################
+// Containers
+//################################################################################
+
+async fn containers(
+    state: &Shared,
+    segments: &[&str],
+    mut req: Request<Body>,
+) -> Reply {
+    let query = Query::parse(req.uri().query());
+    let method = req.method().clone();
+
+    match (&method, segments) {
+        (&Method::GET, ["json"]) => {
+            let state = state.lock();
+            let filters = query.filters();
+            let list: Vec<Value> = state
+                .containers
+                .values()
+                .filter(|c| query.flag("all") || c.running())
+                .filter(|c| {
+                    filters
+                        .get("status")
+                        .map(|s| s.iter().any(|s| s == c.status.as_str()))
+                        .unwrap_or(true)
+                })
+                .filter(|c| {
+                    filters
+                        .get("label")
+                        .map(|wanted| wanted.iter().all(|l| has_label(&c.config["Labels"], l)))
+                        .unwrap_or(true)
+                })
+                .filter(|c| {
+                    filters
+                        .get("exit")
+                        .map(|codes| {
+                            c.status == Status::Exited
+                                && codes.iter().any(|code| *code == c.exit_code.to_string())
+                        })
+                        .unwrap_or(true)
+                })
+                .map(|c| c.summary(&state))
+                .collect();
+            Ok(json_response(StatusCode::OK, &Value::Array(list)))
+        }
+        (&Method::POST, ["create"]) => {
+            let config = read_json(req.into_body()).await?;
+            let mut state = state.lock();
+            let image_ref = config["Image"]
+                .as_str()
+                .ok_or_else(|| {
+                    Fault::bad_request("Config cannot be empty in order to create a container")
+                })?
+                .to_owned();
+            let image = state.image(&image_ref)?;
+            let image_id = image.id.clone();
+            let default_cmd = image.cmd.clone();
+
+            let name = match query.get("name") {
+                Some(name) => {
+                    let name = name.trim_start_matches('/').to_owned();
+                    if let Some(other) = state.containers.values().find(|c| c.name == name) {
+                        return Err(Fault::conflict(format!(
+                            "Conflict. The container name \"/{}\" is already in use by container \"{}\". You have to remove (or rename) that container to be able to reuse that name.",
+                            name, other.id
+                        )));
+                    }
+                    name
+                }
+                None => format!("mock_container_{}", state.containers.len() + 1),
+            };
+
+            let id = state.new_id();
+            let mut config = config;
+            if config["Cmd"].is_null() && config["Entrypoint"].is_null() {
+                config["Cmd"] = json!(default_cmd);
+            }
+            let (output, _) = broadcast::channel(256);
+            state.containers.insert(
+                id.clone(),
+                MockContainer {
+                    id: id.clone(),
+                    name,
+                    image: image_ref,
+                    image_id,
+                    config,
+                    created: now().0,
+                    status: Status::Created,
+                    exit_code: 0,
+                    pid: 0,
+                    started_at: "0001-01-01T00:00:00Z".to_owned(),
+                    finished_at: "0001-01-01T00:00:00Z".to_owned(),
+                    logs: Vec::new(),
+                    files: BTreeMap::new(),
+                    output,
+                },
+            );
+            state.container_event("create", &id);
+            Ok(json_response(
+                StatusCode::CREATED,
+                &json!({ "Id": id, "Warnings": [] }),
+            ))
+        }
+        (&Method::GET, [id, "json"]) => {
+            let state = state.lock();
+            let id = state.container_id(id)?;
+            Ok(json_response(
+                StatusCode::OK,
+                &state.containers[&id].details(&state),
+            ))
+        }
+        (&Method::GET, [id, "top"]) => {
+            let mut state = state.lock();
+            let c = state.container(id)?;
+            if !c.running() {
+                return Err(Fault::conflict(format!(
+                    "Container {} is not running",
+                    c.id
+                )));
+            }
+            Ok(json_response(
+                StatusCode::OK,
+                &json!({
+                    "Titles": ["UID", "PID", "PPID", "C", "STIME", "TTY", "TIME", "CMD"],
+                    "Processes": [[
+                        "root", c.pid.to_string(), "1", "0", "00:00", "?", "00:00:00", c.cmd().join(" ")
+                    ]],
+                }),
+            ))
+        }
+        (&Method::GET, [id, "logs"]) => {
+            let stdout = query.flag("stdout");
+            let stderr = query.flag("stderr");
+            if !stdout && !stderr {
+                return Err(Fault::bad_request(
+                    "Bad parameters: you must choose at least one stream",
+                ));
+            }
+            let wanted = move |stream: u8| (stream == 1 && stdout) || (stream == 2 && stderr);
+
+            let mut state = state.lock();
+            let c = state.container(id)?;
+            let tty = c.tty();
+            let mut backlog: Vec<Bytes> = c
+                .logs
+                .iter()
+                .filter(|(s, _)| wanted(*s))
+                .map(|(s, b)| frame(tty, *s, b))
+                .collect();
+            if let Some(tail) = query.get("tail").and_then(|t| t.parse::<usize>().ok()) {
+                backlog = backlog.split_off(backlog.len().saturating_sub(tail));
+            }
+            let rx = (query.flag("follow") && c.running()).then(|| c.output.subscribe());
+
+            let live = stream::unfold(rx, move |rx| async move {
+                let mut rx = rx?;
+                loop {
+                    match rx.recv().await {
+                        Ok(Output::Chunk(s, bytes)) if wanted(s) => {
+                            return Some((frame(tty, s, &bytes), Some(rx)))
+                        }
+                        Ok(Output::Chunk(..)) | Err(broadcast::error::RecvError::Lagged(_)) => {
+                            continue
+                        }
+                        Ok(Output::Exited(_)) | Err(broadcast::error::RecvError::Closed) => {
+                            return None
+                        }
+                    }
+                }
+            });
+            Ok(stream_response(
+                raw_stream_type(tty),
+                stream::iter(backlog).chain(live),
+            ))
+        }
+        (&Method::GET, [id, "changes"]) => {
+            let mut state = state.lock();
+            let c = state.container(id)?;
+            let changes: Vec<Value> = c
+                .files
+                .keys()
+                .map(|path| json!({ "Path": path, "Kind": 1 }))
+                .collect();
+            Ok(json_response(StatusCode::OK, &Value::Array(changes)))
+        }
+        (&Method::GET, [id, "export"]) => {
+            let mut state = state.lock();
+            let c = state.container(id)?;
+            let mut files = c.files.clone();
+            files.insert(
+                "etc/hostname".to_owned(),
+                format!("{}\n", &c.id[..12]).into(),
+            );
+            Ok(tar_response(tarball(
+                files
+                    .iter()
+                    .map(|(p, d)| (p.trim_start_matches('/'), &d[..])),
+            )))
+        }
+        (&Method::GET, [id, "stats"]) => {
+            let mut state = state.lock();
+            let c = state.container(id)?;
+            if !c.running() {
+                return Err(Fault::conflict(format!(
+                    "Container {} is not running",
+                    c.id
+                )));
+            }
+            Ok(json_response(StatusCode::OK, &stats()))
+        }
+        (&Method::POST, [id, "start"]) => {
+            let mut state = state.lock();
+            let c = state.container(id)?;
+            if c.running() {
+                return Ok(empty_response(StatusCode::NOT_MODIFIED));
+            }
+            let id = c.id.clone();
+            state.start(&id);
+            Ok(empty_response(StatusCode::NO_CONTENT))
+        }
+        (&Method::POST, [id, "stop"]) => {
+            let mut state = state.lock();
+            let c = state.container(id)?;
+            if !c.running() {
+                return Ok(empty_response(StatusCode::NOT_MODIFIED));
+            }
+            let id = c.id.clone();
+            state.stop(&id, 0);
+            state.container_event("stop", &id);
+            Ok(empty_response(StatusCode::NO_CONTENT))
+        }
+        (&Method::POST, [id, "restart"]) => {
+            let mut state = state.lock();
+            let id = state.container_id(id)?;
+            if state.containers[&id].running() {
+                state.stop(&id, 0);
+            }
+            state.start(&id);
+            state.container_event("restart", &id);
+            Ok(empty_response(StatusCode::NO_CONTENT))
+        }
+        (&Method::POST, [id, "kill"]) => {
+            let mut state = state.lock();
+            let c = state.container(id)?;
+            if !c.running() {
+                return Err(Fault::conflict(format!(
+                    "Cannot kill container: {}: Container {} is not running",
+                    id, c.id
+                )));
+            }
+            let id = c.id.clone();
+            state.container_event("kill", &id);
+            state.stop(&id, 137);
+            Ok(empty_response(StatusCode::NO_CONTENT))
+        }
+        (&Method::POST, [id, action @ "pause"]) | (&Method::POST, [id, action @ "unpause"]) => {
+            let mut state = state.lock();
+            let c = state.container(id)?;
+            if !c.running() {
+                return Err(Fault::conflict(format!(
+                    "Container {} is not running",
+                    c.id
+                )));
+            }
+            c.status = if *action == "pause" {
+                Status::Paused
+            } else {
+                Status::Running
+            };
+            let id = c.id.clone();
+            state.container_event(action, &id);
+            Ok(empty_response(StatusCode::NO_CONTENT))
+        }
+        (&Method::POST, [id, "rename"]) => {
+            let name = query
+                .get("name")
+                .ok_or_else(|| Fault::bad_request("name is required"))?
+                .to_owned();
+            let mut state = state.lock();
+            let id = state.container_id(id)?;
+            if state.containers.values().any(|c| c.name == name) {
+                return Err(Fault::conflict(format!(
+                    "Error when allocating new name: Conflict. The container name \"/{}\" is already in use",
+                    name
+                )));
+            }
+            state.containers.get_mut(&id).unwrap().name = name;
+            state.container_event("rename", &id);
+            Ok(empty_response(StatusCode::NO_CONTENT))
+        }
+        (&Method::POST, [id, "wait"]) => {
+            let rx = {
+                let mut state = state.lock();
+                let c = state.container(id)?;
+                if c.running() {
+                    Some(c.output.subscribe())
+                } else {
+                    return Ok(json_response(
+                        StatusCode::OK,
+                        &json!({ "StatusCode": c.exit_code, "Error": null }),
+                    ));
+                }
+            };
+            let mut exit_code = 0;
+            if let Some(mut rx) = rx {
+                loop {
+                    match rx.recv().await {
+                        Ok(Output::Exited(code)) => {
+                            exit_code = code;
+                            break;
+                        }
+                        Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    }
+                }
+            }
+            Ok(json_response(
+                StatusCode::OK,
+                &json!({ "StatusCode": exit_code, "Error": null }),
+            ))
+        }
+        (&Method::POST, [id, "attach"]) => {
+            let (container_id, tty, rx) = {
+                let mut state = state.lock();
+                let c = state.container(id)?;
+                (c.id.clone(), c.tty(), c.output.subscribe())
+            };
+            let on_upgrade = hyper::upgrade::on(&mut req);
+            let state = state.clone();
+            tokio::spawn(async move {
+                if let Ok(upgraded) = on_upgrade.await {
+                    attach(state, container_id, tty, rx, upgraded).await;
+                }
+            });
+            Ok(Response::builder()
+                .status(StatusCode::SWITCHING_PROTOCOLS)
+                .header(header::CONTENT_TYPE, raw_stream_type(tty))
+                .header(header::CONNECTION, "Upgrade")
+                .header(header::UPGRADE, "tcp")
+                .body(Body::empty())
+                .unwrap())
+        }
+        (&Method::POST, [id, "exec"]) => {
+            let config = read_json(req.into_body()).await?;
+            let mut state = state.lock();
+            let c = state.container(id)?;
+            if !c.running() {
+                return Err(Fault::conflict(format!(
+                    "Container {} is not running",
+                    c.id
+                )));
+            }
+            let container = c.id.clone();
+            let exec_id = state.new_id();
+            state.execs.insert(
+                exec_id.clone(),
+                MockExec {
+                    id: exec_id.clone(),
+                    container,
+                    cmd: string_list(&config["Cmd"]),
+                    tty: config["Tty"].as_bool().unwrap_or(false),
+                    attach_stdout: config["AttachStdout"].as_bool().unwrap_or(false),
+                    attach_stderr: config["AttachStderr"].as_bool().unwrap_or(false),
+                    exit_code: None,
+                },
+            );
+            Ok(json_response(
+                StatusCode::CREATED,
+                &json!({ "Id": exec_id }),
+            ))
+        }
+        (&Method::GET, [id, "archive"]) => {
+            let path = query
+                .get("path")
+                .ok_or_else(|| Fault::bad_request("path is required"))?;
+            let mut state = state.lock();
+            let c = state.container(id)?;
+            let wanted = path.trim_end_matches("/.").trim_end_matches('/');
+            let base = wanted.rsplit('/').next().unwrap_or_default();
+            let entries: Vec<(String, &[u8])> = c
+                .files
+                .iter()
+                .filter_map(|(file, data)| {
+                    if file == wanted {
+                        Some((base.to_owned(), &data[..]))
+                    } else {
+                        file.strip_prefix(&format!("{}/", wanted))
+                            .map(|rest| (format!("{}/{}", base, rest), &data[..]))
+                    }
+                })
+                .collect();
+            if entries.is_empty() {
+                return Err(Fault::new(
+                    StatusCode::NOT_FOUND,
+                    format!("Could not find the file {} in container {}", path, c.id),
+                ));
+            }
+            Ok(tar_response(tarball(
+                entries.iter().map(|(p, d)| (p.trim_start_matches('/'), *d)),
+            )))
+        }
+        (&Method::PUT, [id, "archive"]) => {
+            let path = query
+                .get("path")
+                .ok_or_else(|| Fault::bad_request("path is required"))?
+                .trim_end_matches('/')
+                .to_owned();
+            let body = read_bytes(req.into_body()).await?;
+            let files = untar(&body).map_err(|e| Fault::bad_request(e.to_string()))?;
+            let mut state = state.lock();
+            let c = state.container(id)?;
+            for (name, data) in files {
+                c.files.insert(format!("{}/{}", path, name), data);
+            }
+            Ok(empty_response(StatusCode::OK))
+        }
+        (&Method::DELETE, [id]) => {
+            let mut state = state.lock();
+            let id = state.container_id(id)?;
+            if state.containers[&id].running() {
+                if !query.flag("force") {
+                    return Err(Fault::conflict(format!(
+                        "You cannot remove a running container {}. Stop the container before attempting removal or force remove",
+                        id
+                    )));
+                }
+                state.stop(&id, 137);
+            }
+            state.container_event("destroy", &id);
+            state.containers.remove(&id);
+            for network in state.networks.values_mut() {
+                network.containers.remove(&id);
+            }
+            Ok(empty_response(StatusCode::NO_CONTENT))
+        }
+        (_, [_, "json"]) | (_, ["create"]) | (_, [_, "start"]) | (_, [_, "stop"]) => {
+            Err(method_not_allowed())
+        }
+        _ => Err(not_found(req.uri().path())),
+    }
+}
+
+/// Serves an upgraded attach connection until the container stops or the client hangs up
+async fn attach(
+    state: Shared,
+    id: String,
+    tty: bool,
+    mut rx: broadcast::Receiver<Output>,
+    upgraded: hyper::upgrade::Upgraded,
+) {
+    let (mut reader, mut writer) = tokio::io::split(upgraded);
+
+    let stdin = tokio::spawn({
+        let id = id.clone();
+        async move {
+            let mut buf = vec![0u8; 4096];
+            while let Ok(n) = reader.read(&mut buf).await {
+                if n == 0 {
+                    break;
+                }
+                let mut state = state.lock();
+                if state.containers.get(&id).map(|c| c.running()) == Some(true) {
+                    state.output(&id, 1, Bytes::copy_from_slice(&buf[..n]));
+                }
+            }
+        }
+    });
+
+    loop {
+        match rx.recv().await {
+            Ok(Output::Chunk(stream, bytes)) => {
+                if writer.write_all(&frame(tty, stream, &bytes)).await.is_err() {
+                    break;
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Ok(Output::Exited(_)) | Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+
+    stdin.abort();
+    let _ = writer.shutdown().await;
+}
+
+//################################################################################
+// Exec
+//################################################################################
+
+async fn exec(
+    state: &Shared,
+    segments: &[&str],
+    req: Request<Body>,
+) -> Reply {
+    let method = req.method().clone();
+    match (&method, segments) {
+        (&Method::POST, [id, "start"]) => {
+            // like dockerd, the start body rather than the exec's config decides whether the
+            // output is framed
+            let start = read_json(req.into_body()).await?;
+            let mut state = state.lock();
+            let exec = state.execs.get_mut(*id).ok_or_else(|| {
+                Fault::new(
+                    StatusCode::NOT_FOUND,
+                    format!("No such exec instance: {}", id),
+                )
+            })?;
+            if exec.exit_code.is_some() {
+                return Err(Fault::conflict(format!("Exec {} has already run", exec.id)));
+            }
+            exec.exit_code = Some(0);
+            let tty = start["Tty"].as_bool().unwrap_or_default();
+            let mut chunks = Vec::new();
+            if exec.attach_stdout {
+                chunks.push(frame(
+                    tty,
+                    1,
+                    format!("{}\n", exec.cmd.join(" ")).as_bytes(),
+                ));
+            }
+            let _ = exec.attach_stderr;
+            Ok(stream_response(raw_stream_type(tty), stream::iter(chunks)))
+        }
+        (&Method::POST, [id, "resize"]) => {
+            let state = state.lock();
+            if !state.execs.contains_key(*id) {
+                return Err(Fault::new(
+                    StatusCode::NOT_FOUND,
+                    format!("No such exec instance: {}", id),
+                ));
+            }
+            Ok(empty_response(StatusCode::OK))
+        }
+        (&Method::GET, [id, "json"]) => {
+            let state = state.lock();
+            let exec = state.execs.get(*id).ok_or_else(|| {
+                Fault::new(
+                    StatusCode::NOT_FOUND,
+                    format!("No such exec instance: {}", id),
+                )
+            })?;
+            let pid = state
+                .containers
+                .get(&exec.container)
+                .map(|c| c.pid)
+                .unwrap_or_default();
+            Ok(json_response(
+                StatusCode::OK,
+                &json!({
+                    "ID": exec.id,
+                    "ContainerID": exec.container,
+                    "Running": false,
+                    "ExitCode": exec.exit_code,
+                    "CanRemove": false,
+                    "DetachKeys": "",
+                    "OpenStdin": false,
+                    "OpenStdout": exec.attach_stdout,
+                    "OpenStderr": exec.attach_stderr,
+                    "Pid": pid,
+                    "ProcessConfig": {
+                        "arguments": exec.cmd.iter().skip(1).collect::<Vec<_>>(),
+                        "entrypoint": exec.cmd.first().cloned().unwrap_or_default(),
+                        "privileged": false,
+                        "tty": exec.tty,
+                        "user": "",
+                    },
+                }),
+            ))
+        }
+        _ => Err(not_found(req.uri().path())),
+    }
+}
+
+//################################################################################
+// Images
+//################################################################################
+
+async fn images(
+    state: &Shared,
+    segments: &[&str],
+    req: Request<Body>,
+) -> Reply {
+    let query = Query::parse(req.uri().query());
+    let method = req.method().clone();
+
+    match (&method, segments) {
+        (&Method::GET, ["json"]) => {
+            let state = state.lock();
+            let filters = query.filters();
+            let list: Vec<Value> = state
+                .images
+                .values()
+                .filter(|i| query.flag("all") || !i.tags.is_empty())
+                .filter(|i| {
+                    filters
+                        .get("dangling")
+                        .map(|d| d.iter().any(|d| (d == "true") == i.tags.is_empty()))
+                        .unwrap_or(true)
+                })
+                .filter(|i| {
+                    filters
+                        .get("label")
+                        .map(|wanted| wanted.iter().all(|l| has_label(&i.labels, l)))
+                        .unwrap_or(true)
+                })
+                .map(MockImage::summary)
+                .collect();
+            Ok(json_response(StatusCode::OK, &Value::Array(list)))
+        }
+        (&Method::GET, ["search"]) => {
+            let term = query.get("term").unwrap_or_default();
+            let state = state.lock();
+            let results: BTreeSet<String> = state
+                .images
+                .values()
+                .flat_map(|i| i.tags.iter())
+                .map(|t| repository(t).to_owned())
+                .filter(|r| r.contains(term))
+                .collect();
+            let results: Vec<Value> = results
+                .into_iter()
+                .map(|name| {
+                    json!({
+                        "name": name,
+                        "description": "",
+                        "is_official": false,
+                        "is_automated": false,
+                        "star_count": 0,
+                    })
+                })
+                .collect();
+            Ok(json_response(StatusCode::OK, &Value::Array(results)))
+        }
+        (&Method::POST, ["create"]) => {
+            let image = query
+                .get("fromImage")
+                .ok_or_else(|| Fault::bad_request("fromImage or fromSrc is required"))?;
+            if !authorized(state, &req) {
+                return Err(Fault::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!(
+                        "Head \"https://registry/v2/{}/manifests/latest\": unauthorized: incorrect username or password",
+                        repository(image)
+                    ),
+                ));
+            }
+            let reference = match query.get("tag").filter(|t| !t.is_empty()) {
+                Some(tag) if tag.starts_with("sha256:") => format!("{}@{}", image, tag),
+                Some(tag) => format!("{}:{}", image, tag),
+                None => normalize_reference(image),
+            };
+            Ok(stream_response(
+                "application/json",
+                stream::iter(pull(state, &reference)),
+            ))
+        }
+        (&Method::POST, ["load"]) => {
+            let body = read_bytes(req.into_body()).await?;
+            let files = untar(&body).map_err(|e| Fault::bad_request(e.to_string()))?;
+            let tags: Vec<String> = files
+                .iter()
+                .find(|(name, _)| name == "manifest.json")
+                .and_then(|(_, data)| serde_json::from_slice::<Value>(data).ok())
+                .map(|manifest| {
+                    manifest
+                        .as_array()
+                        .map(|entries| {
+                            entries
+                                .iter()
+                                .flat_map(|e| string_list(&e["RepoTags"]))
+                                .collect()
+                        })
+                        .unwrap_or_default()
+                })
+                .unwrap_or_default();
+
+            let mut state = state.lock();
+            let id = state.new_id();
+            for tag in &tags {
+                state.untag(tag);
+            }
+            state.images.insert(
+                id.clone(),
+                MockImage::new(id.clone(), tags.clone(), vec!["sh".to_owned()]),
+            );
+            let messages: Vec<Bytes> = if tags.is_empty() {
+                vec![json_line(
+                    &json!({ "stream": format!("Loaded image ID: sha256:{}\n", id) }),
+                )]
+            } else {
+                tags.iter()
+                    .map(|t| json_line(&json!({ "stream": format!("Loaded image: {}\n", t) })))
+                    .collect()
+            };
+            Ok(stream_response("application/json", stream::iter(messages)))
+        }
+        (&Method::GET, ["get"]) => {
+            let state = state.lock();
+            let images = query
+                .all("names")
+                .into_iter()
+                .map(|name| state.image(name))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(tar_response(save(&images)))
+        }
+        (&Method::GET, [name @ .., "json"]) if !name.is_empty() => {
+            let state = state.lock();
+            Ok(json_response(
+                StatusCode::OK,
+                &state.image(&name.join("/"))?.details(),
+            ))
+        }
+        (&Method::GET, [name @ .., "history"]) if !name.is_empty() => {
+            let state = state.lock();
+            let image = state.image(&name.join("/"))?;
+            let history: Vec<Value> = image
+                .history
+                .iter()
+                .rev()
+                .enumerate()
+                .map(|(i, created_by)| {
+                    json!({
+                        "Id": if i == 0 { format!("sha256:{}", image.id) } else { "<missing>".to_owned() },
+                        "Created": image.created,
+                        "CreatedBy": created_by,
+                        "Tags": if i == 0 { json!(image.tags) } else { Value::Null },
+                        "Size": if i == 0 { image.size } else { 0 },
+                        "Comment": "",
+                    })
+                })
+                .collect();
+            Ok(json_response(StatusCode::OK, &Value::Array(history)))
+        }
+        (&Method::GET, [name @ .., "get"]) if !name.is_empty() => {
+            let state = state.lock();
+            let image = state.image(&name.join("/"))?;
+            Ok(tar_response(save(&[image])))
+        }
+        (&Method::POST, [name @ .., "push"]) if !name.is_empty() => {
+            if !req.headers().contains_key("X-Registry-Auth") {
+                return Err(Fault::bad_request("missing X-Registry-Auth header"));
+            }
+            let name = name.join("/");
+            let reference = match query.get("tag").filter(|t| !t.is_empty()) {
+                Some(tag) => format!("{}:{}", name, tag),
+                None => normalize_reference(&name),
+            };
+            let messages = if authorized(state, &req) {
+                push(state, &reference)?
+            } else {
+                vec![error_line("unauthorized: authentication required", None)]
+            };
+            Ok(stream_response("application/json", stream::iter(messages)))
+        }
+        (&Method::POST, [name @ .., "tag"]) if !name.is_empty() => {
+            let repo = query
+                .get("repo")
+                .ok_or_else(|| Fault::bad_request("repo is required"))?;
+            let tag = format!("{}:{}", repo, query.get("tag").unwrap_or("latest"));
+            let mut state = state.lock();
+            let id = state.image(&name.join("/"))?.id.clone();
+            state.untag(&tag);
+            state.images.get_mut(&id).unwrap().tags.push(tag);
+            state.event("image", "tag", &format!("sha256:{}", id), json!({}));
+            Ok(empty_response(StatusCode::CREATED))
+        }
+        (&Method::DELETE, name) if !name.is_empty() => {
+            let reference = name.join("/");
+            let mut state = state.lock();
+            let id = state.image(&reference)?.id.clone();
+            if let Some(c) = state.containers.values().find(|c| c.image_id == id) {
+                if !query.flag("force") {
+                    return Err(Fault::conflict(format!(
+                        "conflict: unable to remove repository reference \"{}\" (must force) - container {} is using its referenced image {}",
+                        reference,
+                        &c.id[..12],
+                        &id[..12]
+                    )));
+                }
+            }
+            let tag = normalize_reference(&reference);
+            let image = state.images.get_mut(&id).unwrap();
+            let mut statuses = Vec::new();
+            if image.tags.contains(&tag) && (image.tags.len() > 1 || query.flag("noprune")) {
+                image.tags.retain(|t| *t != tag);
+                statuses.push(json!({ "Untagged": tag }));
+            } else {
+                for tag in image.tags.drain(..) {
+                    statuses.push(json!({ "Untagged": tag }));
+                }
+                statuses.push(json!({ "Deleted": format!("sha256:{}", id) }));
+                state.images.remove(&id);
+                state.event("image", "delete", &format!("sha256:{}", id), json!({}));
+            }
+            Ok(json_response(StatusCode::OK, &Value::Array(statuses)))
+        }
+        _ => Err(not_found(req.uri().path())),
+    }
+}
+
+/// Pulls `reference` into the daemon, returning the progress messages to stream back
+fn pull(
+    state: &Shared,
+    reference: &str,
+) -> Vec<Bytes> {
+    let mut state = state.lock();
+    let (repo, tag) = split_reference(reference);
+    let mut messages = vec![json!({ "status": format!("Pulling from {}", repo), "id": tag })];
+
+    if state.image_id(reference).is_some() {
+        messages.push(json!({
+            "status": format!("Status: Image is up to date for {}", reference)
+        }));
+        return messages.iter().map(json_line).collect();
+    }
+
+    let id = state.new_id();
+    let layers = [&id[..12], &id[12..24]];
+    for layer in &layers {
+        messages.push(json!({ "status": "Pulling fs layer", "progressDetail": {}, "id": layer }));
+    }
+    for layer in &layers {
+        for current in &[512u64, 1024] {
+            messages.push(json!({
+                "status": "Downloading",
+                "progressDetail": { "current": current, "total": 1024 },
+                "progress": progress_bar(*current, 1024),
+                "id": layer,
+            }));
+        }
+        messages.push(json!({ "status": "Verifying Checksum", "progressDetail": {}, "id": layer }));
+        messages.push(json!({ "status": "Download complete", "progressDetail": {}, "id": layer }));
+    }
+    for layer in &layers {
+        messages.push(json!({
+            "status": "Extracting",
+            "progressDetail": { "current": 1024, "total": 1024 },
+            "progress": progress_bar(1024, 1024),
+            "id": layer,
+        }));
+        messages.push(json!({ "status": "Pull complete", "progressDetail": {}, "id": layer }));
+    }
+    messages.push(json!({ "status": format!("Digest: sha256:{}", id) }));
+    messages.push(json!({
+        "status": format!("Status: Downloaded newer image for {}", reference)
+    }));
+
+    state.untag(reference);
+    state.images.insert(
+        id.clone(),
+        MockImage::new(
+            id.clone(),
+            vec![reference.to_owned()],
+            vec!["sh".to_owned()],
+        ),
+    );
+    state.event("image", "pull", reference, json!({ "name": repo }));
+
+    messages.iter().map(json_line).collect()
+}
+
+/// Pushes the local image `reference`, returning the progress messages to stream back
+fn push(
+    state: &Shared,
+    reference: &str,
+) -> Result<Vec<Bytes>, Fault> {
+    let state = state.lock();
+    let image = state.image(reference).map_err(|_| {
+        Fault::new(
+            StatusCode::NOT_FOUND,
+            format!(
+                "An image does not exist locally with the tag: {}",
+                reference
+            ),
+        )
+    })?;
+    let (repo, tag) = split_reference(reference);
+    let layer = &image.id[..12];
+    let digest = format!("sha256:{}", image.id);
+    let messages = [
+        json!({ "status": format!("The push refers to repository [{}]", repo) }),
+        json!({ "status": "Preparing", "progressDetail": {}, "id": layer }),
+        json!({
+            "status": "Pushing",
+            "progressDetail": { "current": 1024, "total": 1024 },
+            "progress": progress_bar(1024, 1024),
+            "id": layer,
+        }),
+        json!({ "status": "Pushed", "progressDetail": {}, "id": layer }),
+        json!({ "status": format!("{}: digest: {} size: 528", tag, digest) }),
+        json!({
+            "progressDetail": {},
+            "aux": { "Tag": tag, "Digest": digest, "Size": 528 },
+        }),
+    ];
+    Ok(messages.iter().map(json_line).collect())
+}
+
+/// Checks the `X-Registry-Auth` header of a pull or push against [`MockDaemon::require_auth`]
+fn authorized(
+    state: &Shared,
+    req: &Request<Body>,
+) -> bool {
+    let (username, password) = match state.lock().registry_auth.clone() {
+        Some(expected) => expected,
+        None => return true,
+    };
+    req.headers()
+        .get("X-Registry-Auth")
+        .and_then(|header| base64::decode_config(header.as_bytes(), base64::URL_SAFE).ok())
+        .and_then(|json| serde_json::from_slice::<Value>(&json).ok())
+        .map(|auth| auth["username"] == username.as_str() && auth["password"] == password.as_str())
+        .unwrap_or(false)
+}
+
+fn progress_bar(
+    current: u64,
+    total: u64,
+) -> String {
+    let width = 50;
+    let done = (current * width / total) as usize;
+    format!(
+        "[{}>{}] {}B/{}B",
+        "=".repeat(done.saturating_sub(1)),
+        " ".repeat(width as usize - done),
+        current,
+        total
+    )
+}
+
+/// Builds a `docker save` style tarball describing `images`
+fn save(images: &[&MockImage]) -> Vec<u8> {
+    let manifest: Vec<Value> = images
+        .iter()
+        .map(|i| json!({ "Config": format!("{}.json", i.id), "RepoTags": i.tags, "Layers": [] }))
+        .collect();
+    let manifest = serde_json::to_vec(&manifest).unwrap();
+    let configs: Vec<(String, Vec<u8>)> = images
+        .iter()
+        .map(|i| {
+            (
+                format!("{}.json", i.id),
+                serde_json::to_vec(&i.details()).unwrap(),
+            )
+        })
+        .collect();
+    tarball(
+        std::iter::once(("manifest.json", &manifest[..]))
+            .chain(configs.iter().map(|(p, d)| (p.as_str(), &d[..]))),
+    )
+}
+
+//################################################################################
+// Build
+//################################################################################
+
+async fn build(
+    state: &Shared,
+    req: Request<Body>,
+) -> Reply {
+    if req.method() != Method::POST {
+        return Err(method_not_allowed());
+    }
+    let query = Query::parse(req.uri().query());
+    #[cfg(feature = "buildkit")]
+    if query.get("version") == Some("2") {
+        return buildkit(state, &query).await;
+    }
+    let body = read_bytes(req.into_body()).await?;
+    let files = untar(&body).map_err(|e| Fault::bad_request(e.to_string()))?;
+
+    let dockerfile_name = query.get("dockerfile").unwrap_or("Dockerfile");
+    // later entries replace earlier ones when the context is unpacked
+    let dockerfile = match files.iter().rev().find(|(name, _)| name == dockerfile_name) {
+        Some((_, data)) => String::from_utf8_lossy(data).into_owned(),
+        None => {
+            return Ok(stream_response(
+                "application/json",
+                stream::iter(vec![error_line(
+                    &format!("Cannot locate specified Dockerfile: {}", dockerfile_name),
+                    None,
+                )]),
+            ))
+        }
+    };
+    let tags: Vec<String> = query
+        .all("t")
+        .into_iter()
+        .map(normalize_reference)
+        .collect();
+
+    Ok(stream_response(
+        "application/json",
+        stream::iter(run_dockerfile(state, &dockerfile, &tags)),
+    ))
+}
+
+/// Interprets a Dockerfile, returning the progress messages of the build
+fn run_dockerfile(
+    state: &Shared,
+    dockerfile: &str,
+    tags: &[String],
+) -> Vec<Bytes> {
+    let instructions = parse_dockerfile(dockerfile);
+    let mut state = state.lock();
+    let mut messages = Vec::new();
+    let stream = |s: String| json_line(&json!({ "stream": s }));
+
+    let mut image: Option<MockImage> = None;
+    for (step, (instruction, args)) in instructions.iter().enumerate() {
+        messages.push(stream(format!(
+            "Step {}/{} : {} {}\n",
+            step + 1,
+            instructions.len(),
+            instruction,
+            args
+        )));
+        let layer = state.new_id();
+
+        match (instruction.as_str(), image.as_mut()) {
+            ("FROM", _) => {
+                let next = match base_image(&state, args, &layer) {
+                    Ok(next) => next,
+                    Err(message) => {
+                        messages.push(error_line(&message, None));
+                        return messages;
+                    }
+                };
+                messages.push(stream(format!(" ---> {}\n", &next.id[..12])));
+                image = Some(next);
+                continue;
+            }
+            (_, None) => {
+                messages.push(error_line("No build stage in current context", None));
+                return messages;
+            }
+            ("RUN", Some(_)) => {
+                messages.push(stream(format!(" ---> Running in {}\n", &layer[..12])));
+                let code = args
+                    .strip_prefix("exit ")
+                    .and_then(|c| c.trim().parse::<i64>().ok())
+                    .unwrap_or(0);
+                if code != 0 {
+                    messages.push(error_line(
+                        &format!(
+                            "The command '/bin/sh -c {}' returned a non-zero code: {}",
+                            args, code
+                        ),
+                        Some(code),
+                    ));
+                    return messages;
+                }
+                if let Some(text) = args.strip_prefix("echo ") {
+                    messages.push(stream(format!("{}\n", text.trim_matches('"'))));
+                }
+                messages.push(stream(format!(
+                    "Removing intermediate container {}\n",
+                    &layer[..12]
+                )));
+            }
+            (instruction, Some(image)) => configure(image, instruction, args),
+        }
+
+        let image = image.as_mut().unwrap();
+        image.parent = format!("sha256:{}", image.id);
+        image.id = layer.clone();
+        image
+            .history
+            .push(format!("/bin/sh -c {} {}", instruction, args));
+        messages.push(stream(format!(" ---> {}\n", &layer[..12])));
+    }
+
+    let mut image = match image {
+        Some(image) => image,
+        None => {
+            messages.push(error_line("the Dockerfile cannot be empty", None));
+            return messages;
+        }
+    };
+    let id = image.id.clone();
+    messages.push(json_line(
+        &json!({ "aux": { "ID": format!("sha256:{}", id) } }),
+    ));
+    messages.push(stream(format!("Successfully built {}\n", &id[..12])));
+    for tag in tags {
+        state.untag(tag);
+        messages.push(stream(format!("Successfully tagged {}\n", tag)));
+    }
+    image.tags = tags.to_vec();
+    image.created = now().0;
+    state.images.insert(id, image);
+    messages
+}
+
+/// The image a `FROM` instruction starts from, as the first layer `layer` of a new image
+fn base_image(
+    state: &State,
+    args: &str,
+    layer: &str,
+) -> Result<MockImage, String> {
+    let base = args.split_whitespace().next().unwrap_or_default();
+    if base == "scratch" {
+        return Ok(MockImage::new(layer.to_owned(), Vec::new(), Vec::new()));
+    }
+    match state.image(base) {
+        Ok(base) => Ok(MockImage {
+            id: layer.to_owned(),
+            tags: Vec::new(),
+            parent: format!("sha256:{}", base.id),
+            created: now().0,
+            size: base.size,
+            cmd: base.cmd.clone(),
+            labels: base.labels.clone(),
+            history: base.history.clone(),
+        }),
+        Err(_) => Err(format!(
+            "pull access denied for {}, repository does not exist or may require 'docker login'",
+            repository(&normalize_reference(base))
+        )),
+    }
+}
+
+/// Applies the instructions that only change the config of an image
+fn configure(
+    image: &mut MockImage,
+    instruction: &str,
+    args: &str,
+) {
+    match instruction {
+        "CMD" | "ENTRYPOINT" => {
+            image.cmd = serde_json::from_str::<Vec<String>>(args)
+                .unwrap_or_else(|_| vec!["/bin/sh".to_owned(), "-c".to_owned(), args.to_owned()]);
+        }
+        "LABEL" => {
+            if !image.labels.is_object() {
+                image.labels = json!({});
+            }
+            for pair in args.split_whitespace() {
+                let mut kv = pair.splitn(2, '=');
+                if let (Some(k), Some(v)) = (kv.next(), kv.next()) {
+                    image.labels[k] = json!(v.trim_matches('"'));
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Splits a Dockerfile into `(INSTRUCTION, arguments)` pairs
+fn parse_dockerfile(dockerfile: &str) -> Vec<(String, String)> {
+    let mut instructions = Vec::new();
+    let mut current = String::new();
+    for line in dockerfile.lines() {
+        let line = line.trim();
+        if current.is_empty() && (line.is_empty() || line.starts_with('#')) {
+            continue;
+        }
+        match line.strip_suffix('\\') {
+            Some(continued) => {
+                current.push_str(continued);
+                current.push(' ');
+            }
+            None => {
+                current.push_str(line);
+                let mut parts = current.trim().splitn(2, char::is_whitespace);
+                let instruction = parts.next().unwrap_or_default().to_uppercase();
+                let args = parts.next().unwrap_or_default().trim().to_owned();
+                instructions.push((instruction, args));
+                current.clear();
+            }
+        }
+    }
+    instructions
+}
+
+//################################################################################
+// BuildKit
+//################################################################################
+
+/// The client side of a session, which the daemon calls into
+#[cfg(feature = "buildkit")]
+#[derive(Clone)]
+struct MockSession {
+    client: Arc<tokio::sync::Mutex<hyper::client::conn::SendRequest<Body>>>,
+    methods: Vec<String>,
+}
+
+#[cfg(feature = "buildkit")]
+fn session(
+    state: &Shared,
+    mut req: Request<Body>,
+) -> Reply {
+    if req.method() != Method::POST {
+        return Err(method_not_allowed());
+    }
+    let headers = req.headers();
+    let protocol = headers
+        .get(header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if protocol != "h2c" {
+        return Err(Fault::bad_request(format!(
+            "protocol {} not supported",
+            protocol
+        )));
+    }
+    let id = headers
+        .get("X-Docker-Expose-Session-Uuid")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned)
+        .ok_or_else(|| Fault::bad_request("no session uuid"))?;
+    let methods = headers
+        .get_all("X-Docker-Expose-Session-Grpc-Method")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .map(str::to_owned)
+        .collect();
+
+    let on_upgrade = hyper::upgrade::on(&mut req);
+    let state = state.clone();
+    tokio::spawn(async move {
+        let upgraded = match on_upgrade.await {
+            Ok(upgraded) => upgraded,
+            Err(_) => return,
+        };
+        // the daemon is the gRPC client of the session
+        let (client, connection) = match hyper::client::conn::Builder::new()
+            .http2_only(true)
+            .handshake::<_, Body>(upgraded)
+            .await
+        {
+            Ok(handshake) => handshake,
+            Err(_) => return,
+        };
+        state.lock().sessions.insert(
+            id.clone(),
+            MockSession {
+                client: Arc::new(tokio::sync::Mutex::new(client)),
+                methods,
+            },
+        );
+        let _ = connection.await;
+        state.lock().sessions.remove(&id);
+    });
+    Ok(Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "Upgrade")
+        .header(header::UPGRADE, "h2c")
+        .body(Body::empty())
+        .unwrap())
+}
+
+/// A gRPC call into a session
+#[cfg(feature = "buildkit")]
+struct Call {
+    tx: Option<hyper::body::Sender>,
+    body: Body,
+    decoder: session::Decoder,
+}
+
+#[cfg(feature = "buildkit")]
+impl Call {
+    async fn open(
+        session: &MockSession,
+        method: &str,
+        metadata: &[(&str, &str)],
+    ) -> Result<Call, String> {
+        if !session.methods.iter().any(|m| m == method) {
+            return Err(format!("method {} not exposed by the session", method));
+        }
+        let mut req = Request::post(format!("http://session{}", method))
+            .header(header::CONTENT_TYPE, "application/grpc")
+            .header(header::TE, "trailers");
+        for (key, value) in metadata {
+            req = req.header(*key, *value);
+        }
+        let (tx, body) = Body::channel();
+        let response = {
+            let mut client = session.client.lock().await;
+            futures_util::future::poll_fn(|cx| client.poll_ready(cx))
+                .await
+                .map_err(|e| e.to_string())?;
+            client.send_request(req.body(body).unwrap())
+        };
+        let response = response.await.map_err(|e| e.to_string())?;
+        Ok(Call {
+            tx: Some(tx),
+            body: response.into_body(),
+            decoder: session::Decoder::default(),
+        })
+    }
+
+    async fn send(
+        &mut self,
+        message: &[u8],
+    ) -> Result<(), String> {
+        match &mut self.tx {
+            Some(tx) => tx
+                .send_data(session::frame(message))
+                .await
+                .map_err(|e| e.to_string()),
+            None => Err("the call was closed".to_owned()),
+        }
+    }
+
+    /// Ends the requests of the call
+    fn close(&mut self) {
+        self.tx = None;
+    }
+
+    /// The next response, or `None` once the call ended successfully
+    async fn recv(&mut self) -> Result<Option<Bytes>, String> {
+        use hyper::body::HttpBody;
+        loop {
+            if let Some(message) = self.decoder.next().map_err(|e| e.to_string())? {
+                return Ok(Some(message));
+            }
+            match self.body.data().await {
+                Some(chunk) => self.decoder.push(&chunk.map_err(|e| e.to_string())?),
+                None => break,
+            }
+        }
+        let trailers = self
+            .body
+            .trailers()
+            .await
+            .map_err(|e| e.to_string())?
+            .unwrap_or_default();
+        let trailer = |key| {
+            trailers
+                .get(key)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+        };
+        match trailer("grpc-status") {
+            "0" => Ok(None),
+            _ => Err(
+                form_urlencoded::parse(format!("m={}", trailer("grpc-message")).as_bytes())
+                    .map(|(_, message)| message.into_owned())
+                    .next()
+                    .unwrap_or_default(),
+            ),
+        }
+    }
+
+    async fn unary(
+        session: &MockSession,
+        method: &str,
+        metadata: &[(&str, &str)],
+        request: &[u8],
+    ) -> Result<Bytes, String> {
+        let mut call = Call::open(session, method, metadata).await?;
+        call.send(request).await?;
+        call.close();
+        let response = call.recv().await?.ok_or("missing response")?;
+        call.recv().await?;
+        Ok(response)
+    }
+}
+
+/// Receives the files of the directory `dir` of a session with the fsutil protocol
+#[cfg(feature = "buildkit")]
+async fn receive_dir(
+    session: &MockSession,
+    dir: &str,
+    includes: &[&str],
+    excludes: &[String],
+) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let mut metadata = vec![("dir-name", dir)];
+    metadata.extend(includes.iter().map(|i| ("include-patterns", *i)));
+    metadata.extend(excludes.iter().map(|e| ("exclude-patterns", e.as_str())));
+    let mut call = Call::open(session, session::DIFF_COPY, &metadata).await?;
+
+    let mut stats = Vec::new();
+    loop {
+        let message = call.recv().await?.ok_or("the sender ended early")?;
+        let packet = session::Packet::decode(&message).map_err(|e| e.to_string())?;
+        match (packet.kind, packet.stat) {
+            (session::PacketType::Stat, Some(stat)) => stats.push(stat),
+            (session::PacketType::Stat, None) => break,
+            (kind, _) => return Err(format!("unexpected {:?} packet", kind)),
+        }
+    }
+
+    let mut files = Vec::new();
+    for (id, stat) in stats.iter().enumerate() {
+        if stat.mode & (session::MODE_DIR | session::MODE_SYMLINK) != 0 {
+            continue;
+        }
+        let request = session::Packet {
+            kind: session::PacketType::Req,
+            id: id as u32,
+            ..Default::default()
+        };
+        call.send(&request.encode()).await?;
+        let mut data = Vec::new();
+        loop {
+            let message = call.recv().await?.ok_or("the sender ended early")?;
+            let packet = session::Packet::decode(&message).map_err(|e| e.to_string())?;
+            if packet.kind != session::PacketType::Data || packet.id != id as u32 {
+                return Err(format!("unexpected {:?} packet", packet.kind));
+            }
+            if packet.data.is_empty() {
+                break;
+            }
+            data.extend_from_slice(&packet.data);
+        }
+        files.push((stat.path.clone(), data));
+    }
+
+    let fin = session::Packet {
+        kind: session::PacketType::Fin,
+        ..Default::default()
+    };
+    call.send(&fin.encode()).await?;
+    call.close();
+    while let Some(message) = call.recv().await? {
+        let packet = session::Packet::decode(&message).map_err(|e| e.to_string())?;
+        if packet.kind == session::PacketType::Fin {
+            break;
+        }
+    }
+    call.recv().await?;
+    Ok(files)
+}
+
+/// Asks the forwarded SSH agent `id` for its identities, like `ssh-add -l`
+#[cfg(feature = "buildkit")]
+async fn list_identities(
+    session: &MockSession,
+    id: &str,
+) -> Result<String, String> {
+    let check = crate::protobuf::Encoder::new().string(1, id).finish();
+    Call::unary(session, session::CHECK_AGENT, &[], &check).await?;
+
+    let mut call = Call::open(
+        session,
+        session::FORWARD_AGENT,
+        &[(session::SSH_ID_KEY, id)],
+    )
+    .await?;
+    // SSH_AGENTC_REQUEST_IDENTITIES
+    let request = crate::protobuf::Encoder::new()
+        .bytes(1, &[0, 0, 0, 1, 11])
+        .finish();
+    call.send(&request).await?;
+    let mut reply = Vec::new();
+    while reply.len() < 4
+        || reply.len() < 4 + u32::from_be_bytes([reply[0], reply[1], reply[2], reply[3]]) as usize
+    {
+        let message = call.recv().await?.ok_or("the agent hung up")?;
+        for field in crate::protobuf::fields(&message) {
+            if let Ok((1, value)) = field {
+                reply.extend_from_slice(value.bytes().map_err(|e| e.to_string())?);
+            }
+        }
+    }
+    call.close();
+    while call.recv().await?.is_some() {}
+
+    // SSH_AGENT_IDENTITIES_ANSWER
+    match reply.get(4..9) {
+        Some([12, count @ ..]) => {
+            match u32::from_be_bytes([count[0], count[1], count[2], count[3]]) {
+                0 => Ok("The agent has no identities.\n".to_owned()),
+                n => Ok(format!("The agent has {} identities.\n", n)),
+            }
+        }
+        _ => Err("error fetching identities: agent refused operation".to_owned()),
+    }
+}
+
+#[cfg(all(feature = "buildkit", feature = "chrono"))]
+fn timestamp() -> chrono::DateTime<chrono::Utc> {
+    chrono::Utc::now()
+}
+
+#[cfg(all(feature = "buildkit", not(feature = "chrono")))]
+fn timestamp() -> SystemTime {
+    SystemTime::now()
+}
+
+/// Runs a BuildKit build, pulling the Dockerfile and context from the client's session
+#[cfg(feature = "buildkit")]
+async fn buildkit(
+    state: &Shared,
+    query: &Query,
+) -> Reply {
+    if query.get("remote") != Some("client-session") {
+        return Err(Fault::bad_request(
+            "only builds from a client session are supported",
+        ));
+    }
+    let id = query
+        .get("session")
+        .ok_or_else(|| Fault::bad_request("BuildKit builds need a session"))?;
+    // like the daemon, give the session a moment to connect
+    let mut session = None;
+    for _ in 0..100 {
+        session = state.lock().sessions.get(id).cloned();
+        if session.is_some() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    let session =
+        session.ok_or_else(|| Fault::bad_request(format!("no active session for {}", id)))?;
+    let dockerfile = query.get("dockerfile").unwrap_or("Dockerfile").to_owned();
+    let tags: Vec<String> = query
+        .all("t")
+        .into_iter()
+        .map(normalize_reference)
+        .collect();
+
+    let messages = match run_buildkit(state, &session, &dockerfile, &tags).await {
+        Ok(messages) => messages,
+        Err(message) => vec![error_line(&message, None)],
+    };
+    Ok(stream_response("application/json", stream::iter(messages)))
+}
+
+/// Interprets a Dockerfile pulled from a session, returning the progress messages of the build
+#[cfg(feature = "buildkit")]
+async fn run_buildkit(
+    state: &Shared,
+    session: &MockSession,
+    dockerfile_name: &str,
+    tags: &[String],
+) -> Result<Vec<Bytes>, String> {
+    use crate::rep::{BuildEvent, SolveStatus, Vertex, VertexLog, VertexStatus};
+
+    let trace = |status: SolveStatus| json_line(&Value::from(BuildEvent::Trace(status)));
+    let mut messages = Vec::new();
+
+    let dockerfile = receive_dir(session, "dockerfile", &[dockerfile_name], &[])
+        .await?
+        .into_iter()
+        .find(|(path, _)| path == dockerfile_name)
+        .map(|(_, data)| String::from_utf8_lossy(&data).into_owned())
+        .ok_or_else(|| {
+            format!(
+                "failed to solve: failed to read dockerfile: open {}: no such file or directory",
+                dockerfile_name
+            )
+        })?;
+    // the frontend reads the .dockerignore before asking for the rest of the context
+    let excludes: Vec<String> = receive_dir(session, "context", &[".dockerignore"], &[])
+        .await?
+        .into_iter()
+        .flat_map(|(_, data)| {
+            String::from_utf8_lossy(&data)
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    let context = receive_dir(session, "context", &[], &excludes).await?;
+
+    let instructions = parse_dockerfile(&dockerfile);
+    let mut image: Option<MockImage> = None;
+    for (step, (instruction, args)) in instructions.iter().enumerate() {
+        let layer = state.lock().new_id();
+        let mut vertex = Vertex {
+            digest: format!("sha256:{}", layer),
+            name: format!(
+                "[{}/{}] {} {}",
+                step + 1,
+                instructions.len(),
+                instruction,
+                args
+            ),
+            started: Some(timestamp()),
+            ..Vertex::default()
+        };
+        messages.push(trace(SolveStatus {
+            vertexes: vec![vertex.clone()],
+            ..SolveStatus::default()
+        }));
+
+        let mut output = String::new();
+        let result = match (instruction.as_str(), image.as_mut()) {
+            ("FROM", _) => base_image(&state.lock(), args, &layer).map(|base| {
+                image = Some(base);
+            }),
+            (_, None) => Err("no build stage in current context".to_owned()),
+            ("COPY", Some(_)) => {
+                let source = args.split_whitespace().next().unwrap_or_default();
+                let copied: Vec<_> = context
+                    .iter()
+                    .filter(|(path, _)| {
+                        source == "." || path == source || path.starts_with(&format!("{}/", source))
+                    })
+                    .collect();
+                for (path, _) in &copied {
+                    output.push_str(&format!("copied {}\n", path));
+                }
+                if copied.is_empty() {
+                    Err(format!(
+                        "failed to compute cache key: \"/{}\": not found",
+                        source
+                    ))
+                } else {
+                    Ok(())
+                }
+            }
+            ("RUN", Some(_)) => run_buildkit_command(session, args, &mut output).await,
+            (instruction, Some(image)) => {
+                configure(image, instruction, args);
+                Ok(())
+            }
+        };
+
+        let mut status = SolveStatus::default();
+        if !output.is_empty() {
+            status.logs.push(VertexLog {
+                vertex: vertex.digest.clone(),
+                timestamp: Some(timestamp()),
+                stream: 1,
+                data: output.into_bytes(),
+            });
+        }
+        vertex.completed = Some(timestamp());
+        vertex.error = result.as_ref().err().cloned();
+        status.vertexes.push(vertex);
+        messages.push(trace(status));
+        if let Err(message) = result {
+            messages.push(error_line(&message, None));
+            return Ok(messages);
+        }
+
+        let image = image.as_mut().unwrap();
+        image.parent = format!("sha256:{}", image.id);
+        image.id = layer;
+        image.history.push(format!("{} {}", instruction, args));
+    }
+
+    let mut image = image.ok_or("the Dockerfile cannot be empty")?;
+    let id = image.id.clone();
+    let exporter = Vertex {
+        digest: format!("sha256:{}", state.lock().new_id()),
+        name: "exporting to image".to_owned(),
+        started: Some(timestamp()),
+        completed: Some(timestamp()),
+        ..Vertex::default()
+    };
+    messages.push(trace(SolveStatus {
+        statuses: tags
+            .iter()
+            .map(|tag| VertexStatus {
+                id: format!("naming to {}", qualified_reference(tag)),
+                vertex: exporter.digest.clone(),
+                name: format!("naming to {}", qualified_reference(tag)),
+                completed: Some(timestamp()),
+                ..VertexStatus::default()
+            })
+            .collect(),
+        vertexes: vec![exporter],
+        ..SolveStatus::default()
+    }));
+    messages.push(json_line(
+        &json!({ "id": "moby.image.id", "aux": { "ID": format!("sha256:{}", id) } }),
+    ));
+    let mut state = state.lock();
+    for tag in tags {
+        state.untag(tag);
+    }
+    image.tags = tags.to_vec();
+    image.created = now().0;
+    state.images.insert(id, image);
+    Ok(messages)
+}
+
+/// Runs the command of a `RUN` instruction, with its `--mount` flags
+#[cfg(feature = "buildkit")]
+async fn run_buildkit_command(
+    session: &MockSession,
+    args: &str,
+    output: &mut String,
+) -> Result<(), String> {
+    let mut secrets = HashMap::new();
+    let mut command = args;
+    while let Some(rest) = command.strip_prefix("--mount=") {
+        let (mount, rest) = rest.split_at(rest.find(' ').unwrap_or(rest.len()));
+        command = rest.trim_start();
+        let options: HashMap<&str, &str> = mount
+            .split(',')
+            .filter_map(|option| {
+                let mut kv = option.splitn(2, '=');
+                Some((kv.next()?, kv.next()?))
+            })
+            .collect();
+        match options.get("type") {
+            Some(&"secret") => {
+                let id = options.get("id").copied().unwrap_or_default();
+                let request = crate::protobuf::Encoder::new().string(1, id).finish();
+                let response = Call::unary(session, session::GET_SECRET, &[], &request).await?;
+                let mut data = Vec::new();
+                for field in crate::protobuf::fields(&response) {
+                    if let Ok((1, value)) = field {
+                        data = value.bytes().map_err(|e| e.to_string())?.to_vec();
+                    }
+                }
+                secrets.insert(format!("/run/secrets/{}", id), data);
+            }
+            Some(&"ssh") => {
+                let id = options.get("id").copied().unwrap_or("default");
+                if command.starts_with("ssh-add -l") {
+                    output.push_str(&list_identities(session, id).await?);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    if let Some(code) = command
+        .strip_prefix("exit ")
+        .and_then(|c| c.trim().parse::<i64>().ok())
+    {
+        if code != 0 {
+            return Err(format!(
+                "process \"/bin/sh -c {}\" did not complete successfully: exit code: {}",
+                command, code
+            ));
+        }
+    }
+    if let Some(text) = command.strip_prefix("echo ") {
+        output.push_str(&format!("{}\n", text.trim_matches('"')));
+    }
+    if let Some(path) = command.strip_prefix("cat ") {
+        let data = secrets
+            .get(path.trim())
+            .ok_or_else(|| format!("cat: can't open '{}': No such file or directory", path))?;
+        output.push_str(&String::from_utf8_lossy(data));
+    }
+    Ok(())
+}
+
+//################################################################################
+// Networks and volumes
+//################################################################################
+
+async fn networks(
+    state: &Shared,
+    segments: &[&str],
+    req: Request<Body>,
+) -> Reply {
+    let method = req.method().clone();
+    match (&method, segments) {
+        (&Method::GET, [""]) | (&Method::GET, []) => {
+            let state = state.lock();
+            let list: Vec<Value> = state.networks.values().map(MockNetwork::details).collect();
+            Ok(json_response(StatusCode::OK, &Value::Array(list)))
+        }
+        (&Method::POST, ["create"]) => {
+            let body = read_json(req.into_body()).await?;
+            let name = body["Name"]
+                .as_str()
+                .ok_or_else(|| Fault::bad_request("network name is required"))?
+                .to_owned();
+            let mut state = state.lock();
+            if state.networks.values().any(|n| n.name == name) {
+                return Err(Fault::conflict(format!(
+                    "network with name {} already exists",
+                    name
+                )));
+            }
+            let id = state.new_id();
+            state.networks.insert(
+                id.clone(),
+                MockNetwork {
+                    id: id.clone(),
+                    name: name.clone(),
+                    driver: body["Driver"].as_str().unwrap_or("bridge").to_owned(),
+                    labels: body["Labels"].clone(),
+                    internal: body["Internal"].as_bool().unwrap_or(false),
+                    attachable: body["Attachable"].as_bool().unwrap_or(false),
+                    predefined: false,
+                    containers: BTreeSet::new(),
+                },
+            );
+            state.event("network", "create", &id, json!({ "name": name }));
+            Ok(json_response(
+                StatusCode::CREATED,
+                &json!({ "Id": id, "Warning": "" }),
+            ))
+        }
+        (&Method::GET, [id]) => {
+            let state = state.lock();
+            let id = state.network_id(id)?;
+            Ok(json_response(
+                StatusCode::OK,
+                &state.networks[&id].details(),
+            ))
+        }
+        (&Method::DELETE, [id]) => {
+            let mut state = state.lock();
+            let id = state.network_id(id)?;
+            let network = &state.networks[&id];
+            if network.predefined {
+                return Err(Fault::new(
+                    StatusCode::FORBIDDEN,
+                    format!(
+                        "{} is a pre-defined network and cannot be removed",
+                        network.name
+                    ),
+                ));
+            }
+            if !network.containers.is_empty() {
+                return Err(Fault::new(
+                    StatusCode::FORBIDDEN,
+                    format!(
+                        "error while removing network: network {} id {} has active endpoints",
+                        network.name, id
+                    ),
+                ));
+            }
+            let name = network.name.clone();
+            state.networks.remove(&id);
+            state.event("network", "destroy", &id, json!({ "name": name }));
+            Ok(empty_response(StatusCode::NO_CONTENT))
+        }
+        (&Method::POST, [id, action @ "connect"])
+        | (&Method::POST, [id, action @ "disconnect"]) => {
+            let body = read_json(req.into_body()).await?;
+            let container = body["Container"]
+                .as_str()
+                .ok_or_else(|| Fault::bad_request("container is required"))?;
+            let mut state = state.lock();
+            let network_id = state.network_id(id)?;
+            let container_id = state.container_id(container)?;
+            let network = state.networks.get_mut(&network_id).unwrap();
+            if *action == "connect" {
+                if !network.containers.insert(container_id.clone()) {
+                    return Err(Fault::new(
+                        StatusCode::FORBIDDEN,
+                        format!(
+                            "endpoint with name {} already exists in network {}",
+                            container, network.name
+                        ),
+                    ));
+                }
+            } else if !network.containers.remove(&container_id) {
+                return Err(Fault::new(
+                    StatusCode::FORBIDDEN,
+                    format!(
+                        "container {} is not connected to network {}",
+                        container, network.name
+                    ),
+                ));
+            }
+            state.event(
+                "network",
+                action,
+                &network_id,
+                json!({ "container": container_id }),
+            );
+            Ok(empty_response(StatusCode::OK))
+        }
+        _ => Err(not_found(req.uri().path())),
+    }
+}
+
+async fn volumes(
+    state: &Shared,
+    segments: &[&str],
+    req: Request<Body>,
+) -> Reply {
+    let query = Query::parse(req.uri().query());
+    let method = req.method().clone();
+    match (&method, segments) {
+        (&Method::GET, [""]) | (&Method::GET, []) => {
+            let state = state.lock();
+            let list: Vec<Value> = state.volumes.values().map(MockVolume::details).collect();
+            Ok(json_response(
+                StatusCode::OK,
+                &json!({ "Volumes": list, "Warnings": null }),
+            ))
+        }
+        (&Method::POST, ["create"]) => {
+            let body = read_json(req.into_body()).await?;
+            let mut state = state.lock();
+            let name = match body["Name"].as_str() {
+                Some(name) if !name.is_empty() => name.to_owned(),
+                _ => state.new_id(),
+            };
+            let volume = state
+                .volumes
+                .entry(name.clone())
+                .or_insert_with(|| MockVolume {
+                    name: name.clone(),
+                    driver: body["Driver"].as_str().unwrap_or("local").to_owned(),
+                    labels: body["Labels"].clone(),
+                    created: now().0,
+                });
+            let details = volume.details();
+            state.event(
+                "volume",
+                "create",
+                &name,
+                json!({ "driver": details["Driver"] }),
+            );
+            Ok(json_response(StatusCode::CREATED, &details))
+        }
+        (&Method::GET, [name]) => {
+            let state = state.lock();
+            let volume = state.volumes.get(*name).ok_or_else(|| {
+                Fault::new(
+                    StatusCode::NOT_FOUND,
+                    format!("get {}: no such volume", name),
+                )
+            })?;
+            Ok(json_response(StatusCode::OK, &volume.details()))
+        }
+        (&Method::DELETE, [name]) => {
+            let mut state = state.lock();
+            if state.volumes.remove(*name).is_none() && !query.flag("force") {
+                return Err(Fault::new(
+                    StatusCode::NOT_FOUND,
+                    format!("get {}: no such volume", name),
+                ));
+            }
+            state.event("volume", "destroy", name, json!({}));
+            Ok(empty_response(StatusCode::NO_CONTENT))
+        }
+        _ => Err(not_found(req.uri().path())),
+    }
+}
+
+//################################################################################
+// Helpers
+//################################################################################
+
+fn json_response(
+    status: StatusCode,
+    value: &Value,
+) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(format!("{}\n", value)))
+        .unwrap()
+}
+
+fn empty_response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn tar_response(data: Vec<u8>) -> Response<Body> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-tar")
+        .body(Body::from(data))
+        .unwrap()
+}
+
+/// Streams `chunks` back to the client, one HTTP chunk per item
+fn stream_response<S>(
+    content_type: &str,
+    chunks: S,
+) -> Response<Body>
+where
+    S: Stream<Item = Bytes> + Send + 'static,
+{
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Body::wrap_stream(chunks.map(Ok::<_, Infallible>)))
+        .unwrap()
+}
+
+fn raw_stream_type(tty: bool) -> &'static str {
+    if tty {
+        "application/vnd.docker.raw-stream"
+    } else {
+        "application/vnd.docker.multiplexed-stream"
+    }
+}
+
+fn json_line(value: &Value) -> Bytes {
+    Bytes::from(format!("{}\r\n", value))
+}
+
+fn error_line(
+    message: &str,
+    code: Option<i64>,
+) -> Bytes {
+    let detail = match code {
+        Some(code) => json!({ "code": code, "message": message }),
+        None => json!({ "message": message }),
+    };
+    json_line(&json!({ "errorDetail": detail, "error": message }))
+}
+
+/// Encodes a chunk of container output, adding the 8 byte multiplexing header unless the
+/// container has a TTY attached
+fn frame(
+    tty: bool,
+    stream: u8,
+    data: &[u8],
+) -> Bytes {
+    if tty {
+        return Bytes::copy_from_slice(data);
+    }
+    let mut framed = Vec::with_capacity(8 + data.len());
+    framed.extend_from_slice(&[stream, 0, 0, 0]);
+    framed.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    framed.extend_from_slice(data);
+    Bytes::from(framed)
+}
+
+async fn read_bytes(body: Body) -> Result<Bytes, Fault> {
+    hyper::body::to_bytes(body)
+        .await
+        .map_err(|e| Fault::bad_request(e.to_string()))
+}
+
+async fn read_json(body: Body) -> Result<Value, Fault> {
+    let bytes = read_bytes(body).await?;
+    serde_json::from_slice(&bytes).map_err(|e| Fault::bad_request(e.to_string()))
+}
+
+/// Builds an uncompressed tarball from `(path, contents)` pairs
+fn tarball<'a, I>(files: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = (&'a str, &'a [u8])>,
+{
+    let mut archive = tar::Builder::new(Vec::new());
+    for (path, data) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(now().0);
+        header.set_cksum();
+        archive.append_data(&mut header, path, data).unwrap();
+    }
+    archive.into_inner().unwrap()
+}
+
+/// Reads the regular files of a plain or gzipped tarball
+fn untar(data: &[u8]) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let reader: Box<dyn Read + '_> = if data.starts_with(&[0x1f, 0x8b]) {
+        Box::new(GzDecoder::new(data))
+    } else {
+        Box::new(data)
+    };
+    let mut archive = tar::Archive::new(reader);
+    let mut files = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry
+            .path()?
+            .to_string_lossy()
+            .trim_start_matches("./")
+            .to_owned();
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents)?;
+        files.push((path, contents));
+    }
+    Ok(files)
+}
+
+fn container_config(
+    hostname: &str,
+    config: &Value,
+) -> Value {
+    json!({
+        "Hostname": hostname,
+        "Domainname": "",
+        "User": config["User"].as_str().unwrap_or_default(),
+        "AttachStdin": config["AttachStdin"].as_bool().unwrap_or(false),
+        "AttachStdout": config["AttachStdout"].as_bool().unwrap_or(false),
+        "AttachStderr": config["AttachStderr"].as_bool().unwrap_or(false),
+        "ExposedPorts": config["ExposedPorts"],
+        "Tty": config["Tty"].as_bool().unwrap_or(false),
+        "OpenStdin": config["OpenStdin"].as_bool().unwrap_or(false),
+        "StdinOnce": false,
+        "Env": config["Env"],
+        "Cmd": config["Cmd"],
+        "Image": config["Image"].as_str().unwrap_or_default(),
+        "Volumes": null,
+        "WorkingDir": config["WorkingDir"].as_str().unwrap_or_default(),
+        "Entrypoint": config["Entrypoint"],
+        "OnBuild": null,
+        "Labels": labels(&config["Labels"]),
+        "StopSignal": config["StopSignal"],
+    })
+}
+
+fn stats() -> Value {
+    const MEMORY_STATS: &[&str] = &[
+        "total_pgmajfault",
+        "cache",
+        "mapped_file",
+        "total_inactive_file",
+        "pgpgout",
+        "rss",
+        "total_mapped_file",
+        "writeback",
+        "unevictable",
+        "pgpgin",
+        "total_unevictable",
+        "pgmajfault",
+        "total_rss",
+        "total_rss_huge",
+        "total_writeback",
+        "total_inactive_anon",
+        "rss_huge",
+        "hierarchical_memory_limit",
+        "hierarchical_memsw_limit",
+        "total_pgfault",
+        "total_active_file",
+        "active_anon",
+        "total_active_anon",
+        "total_pgpgout",
+        "total_cache",
+        "inactive_anon",
+        "active_file",
+        "pgfault",
+        "inactive_file",
+        "total_pgpgin",
+    ];
+    let memory_stats: serde_json::Map<String, Value> = MEMORY_STATS
+        .iter()
+        .map(|k| ((*k).to_owned(), json!(0)))
+        .collect();
+    json!({
+        "read": rfc3339(now().0),
+        "networks": {
+            "eth0": {
+                "rx_bytes": 0, "rx_packets": 0, "rx_errors": 0, "rx_dropped": 0,
+                "tx_bytes": 0, "tx_packets": 0, "tx_errors": 0, "tx_dropped": 0,
+            }
+        },
+        "memory_stats": {
+            "usage": 0, "max_usage": 0, "limit": 8_589_934_592u64, "stats": memory_stats,
+        },
+        "blkio_stats": {
+            "io_service_bytes_recursive": [],
+            "io_serviced_recursive": [],
+            "io_queue_recursive": [],
+            "io_service_time_recursive": [],
+            "io_wait_time_recursive": [],
+            "io_merged_recursive": [],
+            "io_time_recursive": [],
+            "sectors_recursive": [],
+        },
+        "cpu_stats": {
+            "cpu_usage": {
+                "percpu_usage": [0, 0, 0, 0],
+                "usage_in_usermode": 0,
+                "total_usage": 0,
+                "usage_in_kernelmode": 0,
+            },
+            "system_cpu_usage": 0,
+            "throttling_data": { "periods": 0, "throttled_periods": 0, "throttled_time": 0 },
+        },
+    })
+}
+
+fn string_list(value: &Value) -> Vec<String> {
+    match value {
+        Value::Array(items) => items
+            .iter()
+            .filter_map(|v| v.as_str().map(str::to_owned))
+            .collect(),
+        Value::String(s) => vec![s.clone()],
+        _ => Vec::new(),
+    }
+}
+
+fn labels(value: &Value) -> Value {
+    if value.is_object() {
+        value.clone()
+    } else {
+        json!({})
+    }
+}
+
+/// Whether `labels` satisfies a `key` or `key=value` label filter
+fn has_label(
+    labels: &Value,
+    filter: &str,
+) -> bool {
+    let mut kv = filter.splitn(2, '=');
+    let key = kv.next().unwrap_or_default();
+    match (labels.get(key), kv.next()) {
+        (Some(_), None) => true,
+        (Some(actual), Some(expected)) => actual.as_str() == Some(expected),
+        _ => false,
+    }
+}
+
+/// Adds the implicit `latest` tag to references without a tag or digest
+fn normalize_reference(reference: &str) -> String {
+    let name = reference.rsplit('/').next().unwrap_or(reference);
+    if name.contains(':') || name.contains('@') {
+        reference.to_owned()
+    } else {
+        format!("{}:latest", reference)
+    }
+}
+
+/// The reference BuildKit names an image with, e.g. `docker.io/library/app:1` for `app:1`
+#[cfg(feature = "buildkit")]
+fn qualified_reference(reference: &str) -> String {
+    match reference.split_once('/') {
+        Some((domain, _)) if domain.contains(['.', ':']) || domain == "localhost" => {
+            reference.to_owned()
+        }
+        Some(_) => format!("docker.io/{}", reference),
+        None => format!("docker.io/library/{}", reference),
+    }
+}
+
+fn split_reference(reference: &str) -> (&str, &str) {
+    let repo = repository(reference);
+    let tag = reference[repo.len()..].trim_start_matches([':', '@']);
+    (repo, tag)
+}
+
+fn repository(reference: &str) -> &str {
+    let name_start = reference.rfind('/').map(|i| i + 1).unwrap_or(0);
+    match reference[name_start..].find([':', '@']) {
+        Some(i) => &reference[..name_start + i],
+        None => reference,
+    }
+}
+
+fn now() -> (u64, u128) {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (elapsed.as_secs(), elapsed.as_nanos())
+}
+
+/// Formats a unix timestamp the way the daemon does, e.g. `2021-03-08T12:34:56Z`
+fn rfc3339(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
@@ -0,0 +1,266 @@
+//! Just enough of the protocol buffers wire format for BuildKit sessions and progress
+
+use std::io;
+
+/// Appends fields to an encoded message
+///
+/// Like proto3, scalars with their default value are left out.
+#[derive(Debug, Default)]
+pub(crate) struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub(crate) fn new() -> Self {
+        Encoder::default()
+    }
+
+    fn key(
+        &mut self,
+        field: u32,
+        wire_type: u8,
+    ) {
+        put_varint(&mut self.buf, u64::from(field) << 3 | u64::from(wire_type));
+    }
+
+    pub(crate) fn uint(
+        &mut self,
+        field: u32,
+        value: u64,
+    ) -> &mut Self {
+        if value != 0 {
+            self.key(field, 0);
+            put_varint(&mut self.buf, value);
+        }
+        self
+    }
+
+    pub(crate) fn int(
+        &mut self,
+        field: u32,
+        value: i64,
+    ) -> &mut Self {
+        self.uint(field, value as u64)
+    }
+
+    pub(crate) fn bool(
+        &mut self,
+        field: u32,
+        value: bool,
+    ) -> &mut Self {
+        self.uint(field, value as u64)
+    }
+
+    pub(crate) fn bytes(
+        &mut self,
+        field: u32,
+        value: &[u8],
+    ) -> &mut Self {
+        if !value.is_empty() {
+            self.message(field, value);
+        }
+        self
+    }
+
+    pub(crate) fn string(
+        &mut self,
+        field: u32,
+        value: &str,
+    ) -> &mut Self {
+        self.bytes(field, value.as_bytes())
+    }
+
+    /// Appends an embedded message, which unlike a scalar is written even when empty
+    pub(crate) fn message(
+        &mut self,
+        field: u32,
+        value: &[u8],
+    ) -> &mut Self {
+        self.key(field, 2);
+        put_varint(&mut self.buf, value.len() as u64);
+        self.buf.extend_from_slice(value);
+        self
+    }
+
+    pub(crate) fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+}
+
+fn put_varint(
+    buf: &mut Vec<u8>,
+    mut value: u64,
+) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// The value of a decoded field
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Field<'a> {
+    Varint(u64),
+    /// A 32 or 64 bit fixed width value
+    Fixed(u64),
+    Bytes(&'a [u8]),
+}
+
+impl<'a> Field<'a> {
+    pub(crate) fn uint(self) -> io::Result<u64> {
+        match self {
+            Field::Varint(value) | Field::Fixed(value) => Ok(value),
+            Field::Bytes(_) => Err(invalid("expected a number")),
+        }
+    }
+
+    pub(crate) fn int(self) -> io::Result<i64> {
+        self.uint().map(|value| value as i64)
+    }
+
+    pub(crate) fn bool(self) -> io::Result<bool> {
+        self.uint().map(|value| value != 0)
+    }
+
+    pub(crate) fn bytes(self) -> io::Result<&'a [u8]> {
+        match self {
+            Field::Bytes(bytes) => Ok(bytes),
+            _ => Err(invalid("expected a length delimited field")),
+        }
+    }
+
+    pub(crate) fn string(self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| invalid("invalid UTF-8 in string"))
+    }
+}
+
+/// Iterates over the `(number, value)` pairs of an encoded message
+pub(crate) fn fields(buf: &[u8]) -> Fields<'_> {
+    Fields { buf }
+}
+
+pub(crate) struct Fields<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn varint(&mut self) -> io::Result<u64> {
+        let mut value = 0;
+        for (i, &byte) in self.buf.iter().enumerate().take(10) {
+            value |= u64::from(byte & 0x7f) << (7 * i);
+            if byte < 0x80 {
+                self.buf = &self.buf[i + 1..];
+                return Ok(value);
+            }
+        }
+        Err(invalid("truncated varint"))
+    }
+
+    fn take(
+        &mut self,
+        len: usize,
+    ) -> io::Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(invalid("truncated field"));
+        }
+        let (taken, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    fn field(&mut self) -> io::Result<(u32, Field<'a>)> {
+        let key = self.varint()?;
+        let number = (key >> 3) as u32;
+        let field = match key & 7 {
+            0 => Field::Varint(self.varint()?),
+            1 => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(self.take(8)?);
+                Field::Fixed(u64::from_le_bytes(bytes))
+            }
+            2 => {
+                let len = self.varint()? as usize;
+                Field::Bytes(self.take(len)?)
+            }
+            5 => {
+                let mut bytes = [0; 4];
+                bytes.copy_from_slice(self.take(4)?);
+                Field::Fixed(u64::from(u32::from_le_bytes(bytes)))
+            }
+            wire_type => return Err(invalid(&format!("unsupported wire type {}", wire_type))),
+        };
+        Ok((number, field))
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = io::Result<(u32, Field<'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        let field = self.field();
+        if field.is_err() {
+            // don't keep failing on the same bytes
+            self.buf = &[];
+        }
+        Some(field)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid protobuf message: {}", message),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_fields() {
+        let nested = Encoder::new().string(1, "inner").finish();
+        let encoded = Encoder::new()
+            .uint(1, 300)
+            .int(2, -1)
+            .bool(3, true)
+            .uint(4, 0)
+            .string(5, "")
+            .bytes(6, b"data")
+            .message(7, &nested)
+            .message(8, &[])
+            .finish();
+        assert_eq!(&encoded[..3], &[0x08, 0xac, 0x02]);
+
+        let decoded = fields(&encoded).collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(
+            decoded,
+            vec![
+                (1, Field::Varint(300)),
+                (2, Field::Varint(u64::MAX)),
+                (3, Field::Varint(1)),
+                (6, Field::Bytes(b"data")),
+                (7, Field::Bytes(&nested)),
+                (8, Field::Bytes(&[])),
+            ]
+        );
+        assert_eq!(decoded[1].1.int().unwrap(), -1);
+        assert_eq!(
+            fields(&nested).next().unwrap().unwrap().1.string().unwrap(),
+            "inner"
+        );
+
+        // fixed width fields of other messages are skipped over correctly
+        let fixed = [0x09, 1, 0, 0, 0, 0, 0, 0, 0, 0x15, 2, 0, 0, 0];
+        let decoded = fields(&fixed).collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(decoded, vec![(1, Field::Fixed(1)), (2, Field::Fixed(2))]);
+
+        let mut truncated = fields(&encoded[..encoded.len() - 1]);
+        assert!(truncated.any(|field| field.is_err()));
+        assert!(truncated.next().is_none());
+    }
+}
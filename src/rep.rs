@@ -1,9 +1,17 @@
 //! Rust representations of docker json structures
 
+use crate::{protobuf, version::ApiVersion};
 #[cfg(feature = "chrono")]
 use chrono::{DateTime, Utc};
+use futures_util::{
+    future,
+    stream::{Stream, StreamExt},
+};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::{json, Value};
+#[cfg(not(feature = "chrono"))]
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{collections::HashMap, io};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SearchResult {
@@ -452,6 +460,21 @@ pub struct Top {
     pub processes: Vec<Vec<String>>,
 }
 
+/// Daemon details advertised in the headers of a `/_ping` response
+#[derive(Clone, Debug, PartialEq)]
+pub struct PingInfo {
+    /// The `Api-Version` header, the highest API version the daemon supports
+    pub api_version: Option<ApiVersion>,
+    /// The `OSType` header, e.g. `linux` or `windows`
+    pub os_type: Option<String>,
+    /// The `Docker-Experimental` header
+    pub experimental: bool,
+    /// The `Builder-Version` header, `1` for the classic builder and `2` for BuildKit
+    pub builder_version: Option<String>,
+    /// The `Swarm` header, e.g. `inactive`, `active/worker` or `active/manager`
+    pub swarm: Option<String>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct Version {
@@ -795,6 +818,678 @@ pub struct ServiceCreateInfo {
     pub warning: Option<String>,
 }
 
+/// Byte counts of a layer transfer, the `progressDetail` of progress messages
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProgressDetail {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+}
+
+/// A progress message of [`Images::pull`](crate::Images::pull) or
+/// [`Image::push`](crate::Image::push)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(from = "Value", into = "Value")]
+pub enum PullEvent {
+    /// The status of a single layer, e.g. `Pulling fs layer`, `Downloading` or `Pull complete`
+    Layer {
+        id: String,
+        status: String,
+        progress: ProgressDetail,
+    },
+    /// A message about the image as a whole, e.g. `Pulling from library/busybox` or
+    /// `Digest: sha256:...`. Some of these carry the tag they refer to as `id`
+    Status { id: Option<String>, status: String },
+    /// The tag, digest and size of a pushed image
+    Pushed {
+        tag: String,
+        digest: String,
+        size: u64,
+    },
+    /// A message of a shape this version of shiplift doesn't know
+    Other(Value),
+}
+
+impl From<Value> for PullEvent {
+    fn from(value: Value) -> Self {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct PushAux {
+            tag: String,
+            digest: String,
+            size: u64,
+        }
+
+        if let Some(Ok(aux)) = value
+            .get("aux")
+            .map(|aux| PushAux::deserialize(aux.clone()))
+        {
+            return PullEvent::Pushed {
+                tag: aux.tag,
+                digest: aux.digest,
+                size: aux.size,
+            };
+        }
+        let string = |key: &str| value[key].as_str().map(str::to_owned);
+        match (string("id"), string("status"), value.get("progressDetail")) {
+            // only layer messages come with progress, even if it's empty
+            (Some(id), Some(status), Some(progress)) => PullEvent::Layer {
+                id,
+                status,
+                progress: ProgressDetail::deserialize(progress.clone()).unwrap_or_default(),
+            },
+            (id, Some(status), _) => PullEvent::Status { id, status },
+            _ => PullEvent::Other(value),
+        }
+    }
+}
+
+impl From<PullEvent> for Value {
+    fn from(event: PullEvent) -> Self {
+        match event {
+            PullEvent::Layer {
+                id,
+                status,
+                progress,
+            } => json!({ "id": id, "status": status, "progressDetail": progress }),
+            PullEvent::Status {
+                id: Some(id),
+                status,
+            } => json!({ "id": id, "status": status }),
+            PullEvent::Status { id: None, status } => json!({ "status": status }),
+            PullEvent::Pushed { tag, digest, size } => json!({
+                "progressDetail": {},
+                "aux": { "Tag": tag, "Digest": digest, "Size": size },
+            }),
+            PullEvent::Other(value) => value,
+        }
+    }
+}
+
+/// A progress message of [`Images::build`](crate::Images::build) or
+/// [`Images::import`](crate::Images::import)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(from = "Value", into = "Value")]
+pub enum BuildEvent {
+    /// Output of the build, e.g. `Step 1/3 : FROM busybox` or what a `RUN` instruction prints
+    Output(String),
+    /// A warning, e.g. about build arguments that were not consumed
+    Warning(String),
+    /// Progress of pulling a base image, or of loading the layers of an imported image
+    Pull(PullEvent),
+    /// The ID of the built image
+    ImageId(String),
+    /// Progress of a BuildKit build
+    Trace(SolveStatus),
+    /// A message of a shape this version of shiplift doesn't know
+    Other(Value),
+}
+
+const WARNING_PREFIX: &str = "[Warning] ";
+
+impl From<Value> for BuildEvent {
+    fn from(value: Value) -> Self {
+        if let Some(output) = value["stream"].as_str() {
+            return match output.strip_prefix(WARNING_PREFIX) {
+                Some(warning) => BuildEvent::Warning(warning.to_owned()),
+                None => BuildEvent::Output(output.to_owned()),
+            };
+        }
+        if value["id"] == TRACE_ID {
+            // BuildKit sends its progress as a base64 encoded protobuf message
+            if let Some(status) = value["aux"]
+                .as_str()
+                .and_then(|aux| base64::decode(aux).ok())
+                .and_then(|raw| SolveStatus::decode(&raw).ok())
+            {
+                return BuildEvent::Trace(status);
+            }
+        }
+        if let Some(id) = value["aux"]["ID"].as_str() {
+            return BuildEvent::ImageId(id.to_owned());
+        }
+        if value["status"].is_string() {
+            return BuildEvent::Pull(PullEvent::from(value));
+        }
+        BuildEvent::Other(value)
+    }
+}
+
+impl From<BuildEvent> for Value {
+    fn from(event: BuildEvent) -> Self {
+        match event {
+            BuildEvent::Output(output) => json!({ "stream": output }),
+            BuildEvent::Warning(warning) => {
+                json!({ "stream": format!("{}{}", WARNING_PREFIX, warning) })
+            }
+            BuildEvent::Pull(event) => event.into(),
+            BuildEvent::ImageId(id) => json!({ "aux": { "ID": id } }),
+            BuildEvent::Trace(status) => {
+                json!({ "id": TRACE_ID, "aux": base64::encode(status.encode()) })
+            }
+            BuildEvent::Other(value) => value,
+        }
+    }
+}
+
+const TRACE_ID: &str = "moby.buildkit.trace";
+
+/// A batch of progress of a BuildKit build, see [`BuildEvent::Trace`]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SolveStatus {
+    pub vertexes: Vec<Vertex>,
+    pub statuses: Vec<VertexStatus>,
+    pub logs: Vec<VertexLog>,
+    pub warnings: Vec<VertexWarning>,
+}
+
+/// A step of a BuildKit build, e.g. `[2/3] RUN make`
+///
+/// The same vertex is reported again when it starts, finishes or fails.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Vertex {
+    pub digest: String,
+    /// The digests of the vertexes this one depends on
+    pub inputs: Vec<String>,
+    pub name: String,
+    pub cached: bool,
+    #[cfg(feature = "chrono")]
+    pub started: Option<DateTime<Utc>>,
+    #[cfg(not(feature = "chrono"))]
+    pub started: Option<SystemTime>,
+    #[cfg(feature = "chrono")]
+    pub completed: Option<DateTime<Utc>>,
+    #[cfg(not(feature = "chrono"))]
+    pub completed: Option<SystemTime>,
+    pub error: Option<String>,
+}
+
+/// Progress of a part of a vertex, e.g. a layer being pulled or files being transferred
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VertexStatus {
+    pub id: String,
+    /// The digest of the vertex
+    pub vertex: String,
+    pub name: String,
+    pub current: i64,
+    /// The expected total, or 0 when unknown
+    pub total: i64,
+    #[cfg(feature = "chrono")]
+    pub timestamp: Option<DateTime<Utc>>,
+    #[cfg(not(feature = "chrono"))]
+    pub timestamp: Option<SystemTime>,
+    #[cfg(feature = "chrono")]
+    pub started: Option<DateTime<Utc>>,
+    #[cfg(not(feature = "chrono"))]
+    pub started: Option<SystemTime>,
+    #[cfg(feature = "chrono")]
+    pub completed: Option<DateTime<Utc>>,
+    #[cfg(not(feature = "chrono"))]
+    pub completed: Option<SystemTime>,
+}
+
+/// Output of a vertex, e.g. what a `RUN` instruction prints
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VertexLog {
+    /// The digest of the vertex
+    pub vertex: String,
+    #[cfg(feature = "chrono")]
+    pub timestamp: Option<DateTime<Utc>>,
+    #[cfg(not(feature = "chrono"))]
+    pub timestamp: Option<SystemTime>,
+    /// 1 for stdout, 2 for stderr
+    pub stream: i64,
+    pub data: Vec<u8>,
+}
+
+/// A warning about a vertex, e.g. about a deprecated Dockerfile syntax
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VertexWarning {
+    /// The digest of the vertex
+    pub vertex: String,
+    pub level: i64,
+    pub short: String,
+    pub detail: Vec<String>,
+    pub url: String,
+}
+
+impl SolveStatus {
+    /// Decodes a `moby.buildkit.v1.StatusResponse`
+    pub(crate) fn decode(buf: &[u8]) -> io::Result<Self> {
+        let mut status = SolveStatus::default();
+        for field in protobuf::fields(buf) {
+            match field? {
+                (1, value) => status.vertexes.push(Vertex::decode(value.bytes()?)?),
+                (2, value) => status.statuses.push(VertexStatus::decode(value.bytes()?)?),
+                (3, value) => status.logs.push(VertexLog::decode(value.bytes()?)?),
+                (4, value) => status.warnings.push(VertexWarning::decode(value.bytes()?)?),
+                _ => (),
+            }
+        }
+        Ok(status)
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut encoder = protobuf::Encoder::new();
+        for vertex in &self.vertexes {
+            encoder.message(1, &vertex.encode());
+        }
+        for status in &self.statuses {
+            encoder.message(2, &status.encode());
+        }
+        for log in &self.logs {
+            encoder.message(3, &log.encode());
+        }
+        for warning in &self.warnings {
+            encoder.message(4, &warning.encode());
+        }
+        encoder.finish()
+    }
+}
+
+impl Vertex {
+    fn decode(buf: &[u8]) -> io::Result<Self> {
+        let mut vertex = Vertex::default();
+        for field in protobuf::fields(buf) {
+            match field? {
+                (1, value) => vertex.digest = value.string()?,
+                (2, value) => vertex.inputs.push(value.string()?),
+                (3, value) => vertex.name = value.string()?,
+                (4, value) => vertex.cached = value.bool()?,
+                (5, value) => vertex.started = Some(decode_timestamp(value.bytes()?)?),
+                (6, value) => vertex.completed = Some(decode_timestamp(value.bytes()?)?),
+                (7, value) => vertex.error = Some(value.string()?).filter(|e| !e.is_empty()),
+                _ => (),
+            }
+        }
+        Ok(vertex)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut encoder = protobuf::Encoder::new();
+        encoder.string(1, &self.digest);
+        for input in &self.inputs {
+            encoder.message(2, input.as_bytes());
+        }
+        encoder.string(3, &self.name).bool(4, self.cached);
+        if let Some(started) = &self.started {
+            encoder.message(5, &encode_timestamp(started));
+        }
+        if let Some(completed) = &self.completed {
+            encoder.message(6, &encode_timestamp(completed));
+        }
+        encoder
+            .string(7, self.error.as_deref().unwrap_or_default())
+            .finish()
+    }
+}
+
+impl VertexStatus {
+    fn decode(buf: &[u8]) -> io::Result<Self> {
+        let mut status = VertexStatus::default();
+        for field in protobuf::fields(buf) {
+            match field? {
+                (1, value) => status.id = value.string()?,
+                (2, value) => status.vertex = value.string()?,
+                (3, value) => status.name = value.string()?,
+                (4, value) => status.current = value.int()?,
+                (5, value) => status.total = value.int()?,
+                (6, value) => status.timestamp = Some(decode_timestamp(value.bytes()?)?),
+                (7, value) => status.started = Some(decode_timestamp(value.bytes()?)?),
+                (8, value) => status.completed = Some(decode_timestamp(value.bytes()?)?),
+                _ => (),
+            }
+        }
+        Ok(status)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut encoder = protobuf::Encoder::new();
+        encoder
+            .string(1, &self.id)
+            .string(2, &self.vertex)
+            .string(3, &self.name)
+            .int(4, self.current)
+            .int(5, self.total);
+        for (field, time) in [
+            (6, &self.timestamp),
+            (7, &self.started),
+            (8, &self.completed),
+        ] {
+            if let Some(time) = time {
+                encoder.message(field, &encode_timestamp(time));
+            }
+        }
+        encoder.finish()
+    }
+}
+
+impl VertexLog {
+    fn decode(buf: &[u8]) -> io::Result<Self> {
+        let mut log = VertexLog::default();
+        for field in protobuf::fields(buf) {
+            match field? {
+                (1, value) => log.vertex = value.string()?,
+                (2, value) => log.timestamp = Some(decode_timestamp(value.bytes()?)?),
+                (3, value) => log.stream = value.int()?,
+                (4, value) => log.data = value.bytes()?.to_vec(),
+                _ => (),
+            }
+        }
+        Ok(log)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut encoder = protobuf::Encoder::new();
+        encoder.string(1, &self.vertex);
+        if let Some(timestamp) = &self.timestamp {
+            encoder.message(2, &encode_timestamp(timestamp));
+        }
+        encoder.int(3, self.stream).bytes(4, &self.data).finish()
+    }
+}
+
+impl VertexWarning {
+    fn decode(buf: &[u8]) -> io::Result<Self> {
+        let mut warning = VertexWarning::default();
+        for field in protobuf::fields(buf) {
+            match field? {
+                (1, value) => warning.vertex = value.string()?,
+                (2, value) => warning.level = value.int()?,
+                (3, value) => warning.short = String::from_utf8_lossy(value.bytes()?).into_owned(),
+                (4, value) => warning
+                    .detail
+                    .push(String::from_utf8_lossy(value.bytes()?).into_owned()),
+                (5, value) => warning.url = value.string()?,
+                _ => (),
+            }
+        }
+        Ok(warning)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut encoder = protobuf::Encoder::new();
+        encoder
+            .string(1, &self.vertex)
+            .int(2, self.level)
+            .string(3, &self.short);
+        for detail in &self.detail {
+            encoder.message(4, detail.as_bytes());
+        }
+        encoder.string(5, &self.url).finish()
+    }
+}
+
+#[cfg(feature = "chrono")]
+type Timestamp = DateTime<Utc>;
+#[cfg(not(feature = "chrono"))]
+type Timestamp = SystemTime;
+
+/// Decodes a `google.protobuf.Timestamp`
+fn decode_timestamp(buf: &[u8]) -> io::Result<Timestamp> {
+    let (mut seconds, mut nanos) = (0, 0);
+    for field in protobuf::fields(buf) {
+        match field? {
+            (1, value) => seconds = value.int()?,
+            (2, value) => nanos = value.uint()? as u32,
+            _ => (),
+        }
+    }
+    let out_of_range = || io::Error::new(io::ErrorKind::InvalidData, "timestamp out of range");
+    #[cfg(feature = "chrono")]
+    return DateTime::<Utc>::from_timestamp(seconds, nanos).ok_or_else(out_of_range);
+    #[cfg(not(feature = "chrono"))]
+    return if seconds >= 0 {
+        UNIX_EPOCH.checked_add(Duration::new(seconds as u64, nanos))
+    } else {
+        UNIX_EPOCH
+            .checked_sub(Duration::from_secs(seconds.unsigned_abs()))
+            .and_then(|time| time.checked_add(Duration::from_nanos(u64::from(nanos))))
+    }
+    .ok_or_else(out_of_range);
+}
+
+fn encode_timestamp(time: &Timestamp) -> Vec<u8> {
+    #[cfg(feature = "chrono")]
+    let (seconds, nanos) = (time.timestamp(), time.timestamp_subsec_nanos());
+    #[cfg(not(feature = "chrono"))]
+    let (seconds, nanos) = match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => (since.as_secs() as i64, since.subsec_nanos()),
+        Err(e) => {
+            let before = e.duration();
+            match before.subsec_nanos() {
+                0 => (-(before.as_secs() as i64), 0),
+                n => (-(before.as_secs() as i64) - 1, 1_000_000_000 - n),
+            }
+        }
+    };
+    protobuf::Encoder::new()
+        .int(1, seconds)
+        .uint(2, u64::from(nanos))
+        .finish()
+}
+
+/// The transfer progress of a single layer of a pull or push
+#[derive(Clone, Debug, PartialEq)]
+pub struct LayerProgress {
+    pub id: String,
+    /// The latest status, e.g. `Downloading` or `Pull complete`
+    pub status: String,
+    /// Bytes transferred so far
+    pub current: u64,
+    /// The size of the layer, once the daemon reported it
+    pub total: Option<u64>,
+    /// Whether the layer is done transferring, or didn't need to be transferred at all
+    pub complete: bool,
+}
+
+/// The progress of every layer of a pull or push, folded from its [`PullEvent`]s
+///
+/// Useful for rendering aggregate progress bars:
+///
+/// ```no_run
+/// # async {
+/// use futures::StreamExt;
+/// use shiplift::{rep::PullProgress, Docker, PullOptions};
+///
+/// let docker = Docker::new();
+/// let pull = docker
+///     .images()
+///     .pull(&PullOptions::builder().image("busybox").build());
+/// let mut progress = PullProgress::track(pull);
+/// while let Some(Ok(progress)) = progress.next().await {
+///     println!("{}/{} bytes", progress.current(), progress.total());
+/// }
+/// # };
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PullProgress {
+    /// The layers, in the order the daemon first mentioned them
+    pub layers: Vec<LayerProgress>,
+}
+
+impl PullProgress {
+    /// Updates the progress of the layer an event refers to
+    pub fn update(
+        &mut self,
+        event: &PullEvent,
+    ) {
+        let (id, status, progress) = match event {
+            PullEvent::Layer {
+                id,
+                status,
+                progress,
+            } => (id, status, progress),
+            _ => return,
+        };
+        let layer = match self.layers.iter().position(|layer| &layer.id == id) {
+            Some(index) => &mut self.layers[index],
+            None => {
+                self.layers.push(LayerProgress {
+                    id: id.clone(),
+                    status: String::new(),
+                    current: 0,
+                    total: None,
+                    complete: false,
+                });
+                self.layers.last_mut().unwrap()
+            }
+        };
+
+        layer.status = status.clone();
+        match status.as_str() {
+            "Downloading" | "Pushing" => {
+                layer.current = progress.current.unwrap_or(layer.current);
+                layer.total = progress.total.or(layer.total);
+            }
+            "Download complete"
+            | "Pull complete"
+            | "Already exists"
+            | "Pushed"
+            | "Layer already exists" => {
+                layer.complete = true;
+                layer.current = layer.total.unwrap_or(layer.current);
+            }
+            status if status.starts_with("Mounted from") => layer.complete = true,
+            // extraction and verification don't transfer anything
+            _ => (),
+        }
+    }
+
+    /// Bytes transferred so far across all layers
+    pub fn current(&self) -> u64 {
+        self.layers.iter().map(|layer| layer.current).sum()
+    }
+
+    /// The combined size of the layers whose size is known so far
+    pub fn total(&self) -> u64 {
+        self.layers.iter().filter_map(|layer| layer.total).sum()
+    }
+
+    /// Returns true once every layer is complete
+    pub fn is_complete(&self) -> bool {
+        self.layers.iter().all(|layer| layer.complete)
+    }
+
+    /// Folds a pull or push stream into a stream of its progress after each event
+    pub fn track<S>(events: S) -> impl Stream<Item = crate::Result<PullProgress>>
+    where
+        S: Stream<Item = crate::Result<PullEvent>>,
+    {
+        events.scan(PullProgress::default(), |progress, event| {
+            future::ready(Some(event.map(|event| {
+                progress.update(&event);
+                progress.clone()
+            })))
+        })
+    }
+}
+
+/// The outcome of a finished build, collected from its [`BuildEvent`]s
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BuildResult {
+    /// The ID of the built image
+    pub id: String,
+    /// The tags applied to the image
+    pub tags: Vec<String>,
+    pub warnings: Vec<String>,
+    /// Everything the build printed, including warnings
+    pub log: String,
+}
+
+impl BuildResult {
+    /// Waits for a build to finish, failing with [`Error::Build`](crate::Error::Build) and the
+    /// step that was running when the daemon reports an error
+    ///
+    /// BuildKit builds report their tags through the `naming to` statuses of the image exporter,
+    /// and their log only holds what the vertexes printed.
+    pub async fn collect<S>(events: S) -> crate::Result<BuildResult>
+    where
+        S: Stream<Item = crate::Result<BuildEvent>>,
+    {
+        let mut events = Box::pin(events);
+        let mut result = BuildResult::default();
+        let mut step = None;
+        // the first BuildKit vertex that failed, as others may still be running
+        let mut failed = None;
+        // older daemons only print the short ID
+        let mut built = None;
+
+        while let Some(event) = events.next().await {
+            match event {
+                Ok(BuildEvent::Output(output)) => {
+                    for line in output.lines() {
+                        if line.starts_with("Step ") {
+                            step = Some(line.to_owned());
+                        } else if let Some(tag) = line.strip_prefix("Successfully tagged ") {
+                            result.tags.push(tag.to_owned());
+                        } else if let Some(id) = line.strip_prefix("Successfully built ") {
+                            built = Some(id.to_owned());
+                        }
+                    }
+                    result.log.push_str(&output);
+                }
+                Ok(BuildEvent::Warning(warning)) => {
+                    result.log.push_str(WARNING_PREFIX);
+                    result.log.push_str(&warning);
+                    result.warnings.push(warning.trim_end().to_owned());
+                }
+                Ok(BuildEvent::ImageId(id)) => result.id = id,
+                Ok(BuildEvent::Trace(status)) => {
+                    for vertex in status.vertexes {
+                        if vertex.error.is_some() && failed.is_none() {
+                            failed = Some(vertex.name);
+                        } else if vertex.started.is_some() && vertex.completed.is_none() {
+                            step = Some(vertex.name);
+                        }
+                    }
+                    // the exporter repeats its statuses while it makes progress
+                    for status in status.statuses {
+                        if let Some(tag) = status.id.strip_prefix("naming to ") {
+                            let tag = familiar_name(tag);
+                            if !result.tags.iter().any(|t| t == tag) {
+                                result.tags.push(tag.to_owned());
+                            }
+                        }
+                    }
+                    for log in status.logs {
+                        result.log.push_str(&String::from_utf8_lossy(&log.data));
+                    }
+                    result
+                        .warnings
+                        .extend(status.warnings.into_iter().map(|warning| warning.short));
+                }
+                Ok(_) => (),
+                Err(crate::Error::Stream { message, code }) => {
+                    return Err(crate::Error::Build {
+                        step: failed.or(step),
+                        message,
+                        code,
+                        log: result.log,
+                    })
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        if result.id.is_empty() {
+            result.id = built.ok_or_else(|| {
+                crate::Error::InvalidResponse("the build finished without an image ID".to_owned())
+            })?;
+        }
+        Ok(result)
+    }
+}
+
+/// Shortens a reference the way `docker` prints it, e.g. `docker.io/library/app:1` to `app:1`
+fn familiar_name(reference: &str) -> &str {
+    reference
+        .strip_prefix("docker.io/library/")
+        .or_else(|| reference.strip_prefix("docker.io/"))
+        .unwrap_or(reference)
+}
+
 //################################################################################
 
 #[cfg(feature = "chrono")]
@@ -802,8 +1497,9 @@ fn datetime_from_unix_timestamp<'de, D>(deserializer: D) -> Result<DateTime<Utc>
 where
     D: serde::Deserializer<'de>,
 {
-    let timestamp = chrono::NaiveDateTime::from_timestamp(i64::deserialize(deserializer)?, 0);
-    Ok(DateTime::<Utc>::from_utc(timestamp, Utc))
+    let timestamp = i64::deserialize(deserializer)?;
+    DateTime::<Utc>::from_timestamp(timestamp, 0)
+        .ok_or_else(|| serde::de::Error::custom("timestamp out of range"))
 }
 
 #[cfg(feature = "chrono")]
@@ -812,9 +1508,138 @@ where
     D: serde::Deserializer<'de>,
 {
     let timestamp_nano = u64::deserialize(deserializer)?;
-    let timestamp = chrono::NaiveDateTime::from_timestamp(
+    DateTime::<Utc>::from_timestamp(
         (timestamp_nano / 1_000_000_000) as i64,
         (timestamp_nano % 1_000_000_000) as u32,
-    );
-    Ok(DateTime::<Utc>::from_utc(timestamp, Utc))
+    )
+    .ok_or_else(|| serde::de::Error::custom("timestamp out of range"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_progress_messages() {
+        let event = |value: Value| serde_json::from_value::<BuildEvent>(value).unwrap();
+
+        assert_eq!(
+            event(json!({"stream": "[Warning] One or more build-args [FOO] were not consumed\n"})),
+            BuildEvent::Warning("One or more build-args [FOO] were not consumed\n".to_owned())
+        );
+        assert_eq!(
+            event(json!({"status": "Pulling from library/busybox", "id": "latest"})),
+            BuildEvent::Pull(PullEvent::Status {
+                id: Some("latest".to_owned()),
+                status: "Pulling from library/busybox".to_owned()
+            })
+        );
+        assert_eq!(
+            event(
+                json!({"status": "Extracting", "progressDetail": {"current": 1, "total": 2}, "id": "a"})
+            ),
+            BuildEvent::Pull(PullEvent::Layer {
+                id: "a".to_owned(),
+                status: "Extracting".to_owned(),
+                progress: ProgressDetail {
+                    current: Some(1),
+                    total: Some(2)
+                }
+            })
+        );
+        assert_eq!(
+            event(json!({"aux": {"ID": "sha256:abc"}})),
+            BuildEvent::ImageId("sha256:abc".to_owned())
+        );
+        assert!(matches!(event(json!({"foo": 1})), BuildEvent::Other(_)));
+
+        // a vertex with a digest, a name and a start time of one second past the epoch
+        let vertex = b"\x0a\x08sha256:a\x1a\x0c[1/2] FROM x\x2a\x02\x08\x01";
+        let mut raw = vec![0x0a, vertex.len() as u8];
+        raw.extend_from_slice(vertex);
+        raw.extend_from_slice(b"\x1a\x10\x0a\x08sha256:a\x18\x01\x22\x02hi");
+        match event(json!({"id": "moby.buildkit.trace", "aux": base64::encode(&raw)})) {
+            BuildEvent::Trace(status) => {
+                assert_eq!(status.vertexes.len(), 1);
+                assert_eq!(status.vertexes[0].digest, "sha256:a");
+                assert_eq!(status.vertexes[0].name, "[1/2] FROM x");
+                assert!(status.vertexes[0].started.is_some());
+                assert!(status.vertexes[0].completed.is_none());
+                assert_eq!(status.logs[0].vertex, "sha256:a");
+                assert_eq!(status.logs[0].stream, 1);
+                assert_eq!(status.logs[0].data, b"hi");
+                assert_eq!(status.encode(), raw);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert!(matches!(
+            event(json!({"id": "moby.buildkit.trace", "aux": "not base64"})),
+            BuildEvent::Other(_)
+        ));
+
+        for value in [
+            json!({"stream": "Step 1/2 : FROM busybox\n"}),
+            json!({"status": "Pushed", "progressDetail": {}, "id": "a"}),
+            json!({"progressDetail": {}, "aux": {"Tag": "1", "Digest": "sha256:abc", "Size": 528}}),
+        ] {
+            assert_eq!(serde_json::to_value(event(value.clone())).unwrap(), value);
+        }
+    }
+
+    #[tokio::test]
+    async fn collect_build_results() {
+        let output = |s: &str| Ok(BuildEvent::Output(s.to_owned()));
+        let result = BuildResult::collect(futures_util::stream::iter(vec![
+            output("Step 1/1 : FROM busybox\n"),
+            Ok(BuildEvent::Warning("SECURITY WARNING\n".to_owned())),
+            output("Successfully built 0123456789ab\nSuccessfully tagged app:1\n"),
+        ]))
+        .await
+        .unwrap();
+        assert_eq!(
+            result,
+            BuildResult {
+                id: "0123456789ab".to_owned(),
+                tags: vec!["app:1".to_owned()],
+                warnings: vec!["SECURITY WARNING".to_owned()],
+                log: "Step 1/1 : FROM busybox\n[Warning] SECURITY WARNING\n\
+                      Successfully built 0123456789ab\nSuccessfully tagged app:1\n"
+                    .to_owned(),
+            }
+        );
+
+        let err = BuildResult::collect(futures_util::stream::iter(vec![output(
+            "Step 1/2 : FROM busybox\n",
+        )]))
+        .await
+        .unwrap_err();
+        assert!(matches!(err, crate::Error::InvalidResponse(_)));
+
+        let naming = |tag: &str| VertexStatus {
+            id: format!("naming to {}", tag),
+            name: format!("naming to {}", tag),
+            ..VertexStatus::default()
+        };
+        let result = BuildResult::collect(futures_util::stream::iter(vec![
+            Ok(BuildEvent::Trace(SolveStatus {
+                statuses: vec![naming("docker.io/library/app:1")],
+                ..SolveStatus::default()
+            })),
+            Ok(BuildEvent::Trace(SolveStatus {
+                statuses: vec![
+                    naming("docker.io/library/app:1"),
+                    naming("docker.io/me/app:latest"),
+                    naming("registry.example.com/app:1"),
+                ],
+                ..SolveStatus::default()
+            })),
+            Ok(BuildEvent::ImageId("sha256:0123".to_owned())),
+        ]))
+        .await
+        .unwrap();
+        assert_eq!(
+            result.tags,
+            ["app:1", "me/app:latest", "registry.example.com/app:1"]
+        );
+    }
 }
@@ -0,0 +1,1082 @@
+//! BuildKit sessions, through which the daemon pulls the build context, secrets and SSH agents
+//! from the client while it builds
+//!
+//! A session is a gRPC server the client runs over a connection hijacked through `/session`.
+//! [`Images::build_with_buildkit`](crate::Images::build_with_buildkit) keeps one open for the
+//! length of a build.
+
+use crate::{dockerignore::Excludes, protobuf, Docker, Result};
+use bytes::{Buf, BytesMut};
+use futures_util::{
+    future::{self, Either},
+    pin_mut,
+    stream::{self, Stream, StreamExt},
+};
+use hyper::{
+    body::{Bytes, Sender},
+    header::{self, HeaderMap, HeaderValue},
+    server::conn::Http,
+    service::service_fn,
+    Body, Request, Response,
+};
+use std::{
+    collections::{
+        hash_map::{DefaultHasher, RandomState},
+        HashMap,
+    },
+    convert::Infallible,
+    fmt, fs,
+    hash::{BuildHasher, Hash, Hasher},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::UNIX_EPOCH,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc,
+    task::{self, JoinHandle},
+};
+
+pub(crate) const HEALTH_CHECK: &str = "/grpc.health.v1.Health/Check";
+pub(crate) const DIFF_COPY: &str = "/moby.filesync.v1.FileSync/DiffCopy";
+pub(crate) const GET_SECRET: &str = "/moby.buildkit.secrets.v1.Secrets/GetSecret";
+pub(crate) const CHECK_AGENT: &str = "/moby.sshforward.v1.SSH/CheckAgent";
+pub(crate) const FORWARD_AGENT: &str = "/moby.sshforward.v1.SSH/ForwardAgent";
+
+/// The metadata key naming the agent a `ForwardAgent` call is for
+pub(crate) const SSH_ID_KEY: &str = "buildkit.ssh.id";
+
+/// Size of the chunks files are sent in
+const CHUNK_SIZE: usize = 32 * 1024;
+
+/// Where the value of a secret comes from
+#[derive(Clone)]
+enum Secret {
+    Data(Bytes),
+    File(PathBuf),
+    Env(String),
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        match self {
+            Secret::Data(_) => f.write_str("Data(..)"),
+            Secret::File(path) => f.debug_tuple("File").field(path).finish(),
+            Secret::Env(var) => f.debug_tuple("Env").field(var).finish(),
+        }
+    }
+}
+
+/// Secrets and SSH agents a BuildKit build may use through `RUN --mount=type=secret` and
+/// `RUN --mount=type=ssh`
+#[derive(Clone, Debug, Default)]
+pub struct SessionOptions {
+    secrets: HashMap<String, Secret>,
+    ssh: HashMap<String, PathBuf>,
+}
+
+impl SessionOptions {
+    /// return a new instance of a builder for options
+    pub fn builder() -> SessionOptionsBuilder {
+        SessionOptionsBuilder::default()
+    }
+}
+
+#[derive(Default)]
+pub struct SessionOptionsBuilder {
+    secrets: HashMap<String, Secret>,
+    ssh: HashMap<String, PathBuf>,
+}
+
+impl SessionOptionsBuilder {
+    /// provide the secret `id` with the given value
+    pub fn secret<I, D>(
+        &mut self,
+        id: I,
+        data: D,
+    ) -> &mut Self
+    where
+        I: Into<String>,
+        D: Into<Bytes>,
+    {
+        self.secrets.insert(id.into(), Secret::Data(data.into()));
+        self
+    }
+
+    /// provide the secret `id` from the contents of a file, read when the build asks for it
+    pub fn secret_file<I, P>(
+        &mut self,
+        id: I,
+        path: P,
+    ) -> &mut Self
+    where
+        I: Into<String>,
+        P: Into<PathBuf>,
+    {
+        self.secrets.insert(id.into(), Secret::File(path.into()));
+        self
+    }
+
+    /// provide the secret `id` from an environment variable of this process
+    pub fn secret_env<I, V>(
+        &mut self,
+        id: I,
+        var: V,
+    ) -> &mut Self
+    where
+        I: Into<String>,
+        V: Into<String>,
+    {
+        self.secrets.insert(id.into(), Secret::Env(var.into()));
+        self
+    }
+
+    /// forward the SSH agent listening on the unix socket at `socket` as `id`. builds use the
+    /// agent with id `default` unless they ask for another one
+    ///
+    /// the socket of the agent of the current user is usually named by `SSH_AUTH_SOCK`
+    pub fn ssh<I, P>(
+        &mut self,
+        id: I,
+        socket: P,
+    ) -> &mut Self
+    where
+        I: Into<String>,
+        P: Into<PathBuf>,
+    {
+        self.ssh.insert(id.into(), socket.into());
+        self
+    }
+
+    pub fn build(&self) -> SessionOptions {
+        SessionOptions {
+            secrets: self.secrets.clone(),
+            ssh: self.ssh.clone(),
+        }
+    }
+}
+
+/// A random session ID
+pub(crate) fn new_id() -> String {
+    let random = || RandomState::new().build_hasher().finish();
+    format!("{:016x}{:016x}", random(), random())
+}
+
+/// An open session, which is closed when dropped
+pub(crate) struct Session(JoinHandle<()>);
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Opens the session `id`, serving the build context at `context` with `dockerfile` relative to
+/// it
+pub(crate) async fn open(
+    docker: &Docker,
+    id: String,
+    context: PathBuf,
+    dockerfile: &str,
+    excludes: Vec<String>,
+    options: SessionOptions,
+) -> Result<Session> {
+    let dockerfile_dir = context
+        .join(dockerfile)
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| context.clone());
+    // the shared key lets the daemon reuse what it synced for earlier builds of the same context
+    let mut hasher = DefaultHasher::new();
+    context
+        .canonicalize()
+        .unwrap_or_else(|_| context.clone())
+        .hash(&mut hasher);
+    let name = context
+        .file_name()
+        .and_then(|name| name.to_str())
+        .filter(|name| name.is_ascii())
+        .unwrap_or("context")
+        .to_owned();
+
+    let provider = Provider {
+        context,
+        dockerfile: dockerfile_dir,
+        excludes,
+        options,
+    };
+    let mut headers = vec![
+        (header::UPGRADE.as_str(), "h2c".to_owned()),
+        ("X-Docker-Expose-Session-Uuid", id),
+        ("X-Docker-Expose-Session-Name", name),
+        (
+            "X-Docker-Expose-Session-Sharedkey",
+            format!("{:016x}", hasher.finish()),
+        ),
+    ];
+    headers.extend(
+        provider
+            .methods()
+            .map(|method| ("X-Docker-Expose-Session-Grpc-Method", method.to_owned())),
+    );
+
+    let io = docker.upgrade("/session", headers).await?;
+    Ok(Session(tokio::spawn(serve(io, Arc::new(provider)))))
+}
+
+async fn serve<I>(
+    io: I,
+    provider: Arc<Provider>,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |req| {
+        let provider = provider.clone();
+        async move { Ok::<_, Infallible>(handle(provider, req)) }
+    });
+    // the daemon closes the connection when it is done with the session
+    let _ = Http::new()
+        .http2_only(true)
+        .serve_connection(io, service)
+        .await;
+}
+
+/// Starts a call, answering it from a separate task as messages come in
+fn handle(
+    provider: Arc<Provider>,
+    req: Request<Body>,
+) -> Response<Body> {
+    let (parts, body) = req.into_parts();
+    let (tx, response) = Body::channel();
+    let mut responder = Responder(tx);
+    tokio::spawn(async move {
+        let requests = messages(body);
+        let result = match parts.uri.path() {
+            HEALTH_CHECK => {
+                // SERVING
+                let response = protobuf::Encoder::new().uint(1, 1).finish();
+                responder.send(&response).await
+            }
+            DIFF_COPY => {
+                provider
+                    .diff_copy(&parts.headers, requests, &mut responder)
+                    .await
+            }
+            GET_SECRET => match unary(requests).await {
+                Ok(request) => match provider.get_secret(&request).await {
+                    Ok(response) => responder.send(&response).await,
+                    Err(status) => Err(status),
+                },
+                Err(status) => Err(status),
+            },
+            CHECK_AGENT => match unary(requests).await {
+                Ok(request) => match provider.check_agent(&request) {
+                    Ok(()) => responder.send(&[]).await,
+                    Err(status) => Err(status),
+                },
+                Err(status) => Err(status),
+            },
+            FORWARD_AGENT => {
+                provider
+                    .forward_agent(&parts.headers, requests, &mut responder)
+                    .await
+            }
+            method => Err(Status::new(
+                Status::UNIMPLEMENTED,
+                format!("unknown method {}", method),
+            )),
+        };
+        responder.finish(result).await;
+    });
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/grpc")
+        .body(response)
+        .unwrap()
+}
+
+/// Waits for the single request message of a unary call
+async fn unary<S>(requests: S) -> std::result::Result<Bytes, Status>
+where
+    S: Stream<Item = io::Result<Bytes>>,
+{
+    pin_mut!(requests);
+    match requests.next().await {
+        Some(request) => Ok(request?),
+        None => Err(Status::new(Status::INVALID_ARGUMENT, "missing request")),
+    }
+}
+
+/// Serves the client's side of a session
+struct Provider {
+    context: PathBuf,
+    /// The directory holding the Dockerfile
+    dockerfile: PathBuf,
+    /// Patterns the context is filtered with on top of what the daemon asks for
+    excludes: Vec<String>,
+    options: SessionOptions,
+}
+
+impl Provider {
+    /// The methods exposed to the daemon
+    fn methods(&self) -> impl Iterator<Item = &'static str> {
+        let ssh: &[&str] = if self.options.ssh.is_empty() {
+            &[]
+        } else {
+            &[CHECK_AGENT, FORWARD_AGENT]
+        };
+        [HEALTH_CHECK, DIFF_COPY, GET_SECRET]
+            .iter()
+            .chain(ssh)
+            .copied()
+    }
+
+    /// Sends the files of a directory with the fsutil protocol: the stats of every file, then
+    /// the contents of the files the daemon asks for
+    async fn diff_copy<S>(
+        &self,
+        metadata: &HeaderMap,
+        requests: S,
+        responder: &mut Responder,
+    ) -> std::result::Result<(), Status>
+    where
+        S: Stream<Item = io::Result<Bytes>>,
+    {
+        let values = |key: &str| {
+            metadata
+                .get_all(key)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+        };
+        let dir_name = values("dir-name").into_iter().next().unwrap_or_default();
+        let (root, mut excludes) = match dir_name.as_str() {
+            "context" => (self.context.clone(), self.excludes.clone()),
+            "dockerfile" => (self.dockerfile.clone(), Vec::new()),
+            name => {
+                return Err(Status::new(
+                    Status::NOT_FOUND,
+                    format!("no access allowed to dir {:?}", name),
+                ))
+            }
+        };
+        excludes.extend(values("exclude-patterns"));
+        let mut includes = values("include-patterns");
+        includes.extend(values("followpaths"));
+
+        let filter = Filter {
+            includes: Some(includes)
+                .filter(|includes| !includes.is_empty())
+                .map(|includes| Excludes::from_patterns(&includes)),
+            excludes: Excludes::from_patterns(&excludes),
+        };
+        let entries = task::spawn_blocking(move || walk(&root, &filter))
+            .await
+            .map_err(|e| Status::new(Status::INTERNAL, e.to_string()))??;
+
+        for (stat, _) in &entries {
+            let packet = Packet {
+                kind: PacketType::Stat,
+                stat: Some(stat.clone()),
+                ..Packet::default()
+            };
+            responder.send(&packet.encode()).await?;
+        }
+        // an empty stat ends the listing
+        responder.send(&Packet::default().encode()).await?;
+
+        pin_mut!(requests);
+        while let Some(request) = requests.next().await {
+            let packet = Packet::decode(&request?)?;
+            match packet.kind {
+                PacketType::Req => {
+                    let path = match entries.get(packet.id as usize) {
+                        Some((_, path)) => path.clone(),
+                        None => {
+                            return Err(Status::new(
+                                Status::INVALID_ARGUMENT,
+                                format!("invalid file request {}", packet.id),
+                            ))
+                        }
+                    };
+                    send_file(path, packet.id, responder).await?;
+                }
+                PacketType::Fin => {
+                    let fin = Packet {
+                        kind: PacketType::Fin,
+                        ..Packet::default()
+                    };
+                    return responder.send(&fin.encode()).await;
+                }
+                PacketType::Err => {
+                    return Err(Status::new(
+                        Status::UNKNOWN,
+                        String::from_utf8_lossy(&packet.data).into_owned(),
+                    ))
+                }
+                PacketType::Stat | PacketType::Data => (),
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_secret(
+        &self,
+        request: &[u8],
+    ) -> std::result::Result<Vec<u8>, Status> {
+        let mut id = String::new();
+        for field in protobuf::fields(request) {
+            if let (1, value) = field? {
+                id = value.string()?;
+            }
+        }
+        let not_found = |message| Status::new(Status::NOT_FOUND, message);
+        let data = match self.options.secrets.get(&id) {
+            Some(Secret::Data(data)) => data.to_vec(),
+            Some(Secret::File(path)) => {
+                let path = path.clone();
+                task::spawn_blocking(move || fs::read(path))
+                    .await
+                    .map_err(|e| Status::new(Status::INTERNAL, e.to_string()))??
+            }
+            Some(Secret::Env(var)) => std::env::var(var)
+                .map_err(|_| {
+                    not_found(format!(
+                        "environment variable {} of secret {} is not set",
+                        var, id
+                    ))
+                })?
+                .into_bytes(),
+            None => return Err(not_found(format!("secret {} not found", id))),
+        };
+        Ok(protobuf::Encoder::new().bytes(1, &data).finish())
+    }
+
+    fn check_agent(
+        &self,
+        request: &[u8],
+    ) -> std::result::Result<(), Status> {
+        let mut id = String::new();
+        for field in protobuf::fields(request) {
+            if let (1, value) = field? {
+                id = value.string()?;
+            }
+        }
+        self.agent(&id).map(|_| ())
+    }
+
+    fn agent(
+        &self,
+        id: &str,
+    ) -> std::result::Result<&Path, Status> {
+        let id = if id.is_empty() { "default" } else { id };
+        self.options
+            .ssh
+            .get(id)
+            .map(PathBuf::as_path)
+            .ok_or_else(|| Status::new(Status::NOT_FOUND, format!("unset ssh forward key {}", id)))
+    }
+
+    /// Relays `BytesMessage`s between the daemon and a local SSH agent
+    #[cfg(unix)]
+    async fn forward_agent<S>(
+        &self,
+        metadata: &HeaderMap,
+        requests: S,
+        responder: &mut Responder,
+    ) -> std::result::Result<(), Status>
+    where
+        S: Stream<Item = io::Result<Bytes>>,
+    {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let id = metadata
+            .get(SSH_ID_KEY)
+            .and_then(|id| id.to_str().ok())
+            .unwrap_or_default();
+        let (mut reader, mut writer) = tokio::net::UnixStream::connect(self.agent(id)?)
+            .await?
+            .into_split();
+
+        let upstream = async move {
+            pin_mut!(requests);
+            while let Some(request) = requests.next().await {
+                for field in protobuf::fields(&request?) {
+                    if let (1, value) = field? {
+                        writer.write_all(value.bytes()?).await?;
+                    }
+                }
+            }
+            writer.shutdown().await?;
+            Ok::<_, Status>(())
+        };
+        let downstream = async move {
+            let mut buf = vec![0; CHUNK_SIZE];
+            loop {
+                let n = reader.read(&mut buf).await?;
+                if n == 0 {
+                    return Ok::<_, Status>(());
+                }
+                let message = protobuf::Encoder::new().bytes(1, &buf[..n]).finish();
+                responder.send(&message).await?;
+            }
+        };
+        pin_mut!(upstream, downstream);
+
+        // the call ends once the agent hangs up, which it does after the daemon did
+        match future::select(upstream, downstream).await {
+            Either::Left((Ok(()), downstream)) => downstream.await,
+            Either::Left((Err(status), _)) => Err(status),
+            Either::Right((result, _)) => result,
+        }
+    }
+
+    #[cfg(not(unix))]
+    async fn forward_agent<S>(
+        &self,
+        _: &HeaderMap,
+        _: S,
+        _: &mut Responder,
+    ) -> std::result::Result<(), Status>
+    where
+        S: Stream<Item = io::Result<Bytes>>,
+    {
+        Err(Status::new(
+            Status::UNIMPLEMENTED,
+            "forwarding SSH agents needs unix sockets",
+        ))
+    }
+}
+
+/// Reads the file at `path` on a blocking task, sending it as `DATA` packets for request `id`
+async fn send_file(
+    path: PathBuf,
+    id: u32,
+    responder: &mut Responder,
+) -> std::result::Result<(), Status> {
+    let (tx, mut rx) = mpsc::channel(4);
+    let reader = task::spawn_blocking(move || -> io::Result<()> {
+        let mut file = fs::File::open(path)?;
+        loop {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            let n = file.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(());
+            }
+            chunk.truncate(n);
+            if tx.blocking_send(chunk).is_err() {
+                return Ok(());
+            }
+        }
+    });
+
+    while let Some(data) = rx.recv().await {
+        let packet = Packet {
+            kind: PacketType::Data,
+            id,
+            data,
+            ..Packet::default()
+        };
+        responder.send(&packet.encode()).await?;
+    }
+    reader
+        .await
+        .map_err(|e| Status::new(Status::INTERNAL, e.to_string()))??;
+
+    // empty data ends the file
+    let packet = Packet {
+        kind: PacketType::Data,
+        id,
+        ..Packet::default()
+    };
+    responder.send(&packet.encode()).await
+}
+
+/// Which files of a directory the daemon asked for
+struct Filter {
+    includes: Option<Excludes>,
+    excludes: Excludes,
+}
+
+/// Lists the files the daemon asked for under `root` with their paths on disk, in the order
+/// fsutil expects: depth first with the entries of each directory sorted by name
+fn walk(
+    root: &Path,
+    filter: &Filter,
+) -> io::Result<Vec<(Stat, PathBuf)>> {
+    walk_dir(root, "", filter)
+}
+
+fn walk_dir(
+    dir: &Path,
+    prefix: &str,
+    filter: &Filter,
+) -> io::Result<Vec<(Stat, PathBuf)>> {
+    let mut names = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<io::Result<Vec<_>>>()?;
+    names.sort();
+
+    let mut entries = Vec::new();
+    for name in names {
+        let path = dir.join(&name);
+        let relative = match prefix {
+            "" => name.to_string_lossy().into_owned(),
+            prefix => format!("{}/{}", prefix, name.to_string_lossy()),
+        };
+        let metadata = fs::symlink_metadata(&path)?;
+        let excluded = filter.excludes.matches(&relative);
+        let included = filter
+            .includes
+            .as_ref()
+            .is_none_or(|includes| includes.matches(&relative));
+
+        if metadata.is_dir() {
+            let descend = (!excluded || filter.excludes.may_keep_within(Path::new(&relative)))
+                && filter
+                    .includes
+                    .as_ref()
+                    .is_none_or(|includes| includes.may_match_within(&relative));
+            let children = if descend {
+                walk_dir(&path, &relative, filter)?
+            } else {
+                Vec::new()
+            };
+            // parent directories are sent for anything sent from within them
+            if (!excluded && included) || !children.is_empty() {
+                entries.push((Stat::new(relative, &metadata, &path)?, path));
+                entries.extend(children);
+            }
+        } else if !excluded && included && (metadata.is_file() || metadata.file_type().is_symlink())
+        {
+            entries.push((Stat::new(relative, &metadata, &path)?, path));
+        }
+    }
+    Ok(entries)
+}
+
+/// Bits of go's `os.FileMode`, which fsutil sends as the mode of a file
+pub(crate) const MODE_DIR: u32 = 1 << 31;
+pub(crate) const MODE_SYMLINK: u32 = 1 << 27;
+#[cfg(unix)]
+const MODE_SETUID: u32 = 1 << 23;
+#[cfg(unix)]
+const MODE_SETGID: u32 = 1 << 22;
+#[cfg(unix)]
+const MODE_STICKY: u32 = 1 << 20;
+
+/// The `fsutil.types.Stat` of a file
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Stat {
+    /// The slash separated path relative to the synced directory
+    pub(crate) path: String,
+    pub(crate) mode: u32,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) size: i64,
+    /// Nanoseconds since the epoch
+    pub(crate) mod_time: i64,
+    pub(crate) linkname: String,
+}
+
+impl Stat {
+    fn new(
+        path: String,
+        metadata: &fs::Metadata,
+        on_disk: &Path,
+    ) -> io::Result<Stat> {
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            let unix = metadata.permissions().mode();
+            [
+                (0o4000, MODE_SETUID),
+                (0o2000, MODE_SETGID),
+                (0o1000, MODE_STICKY),
+            ]
+            .iter()
+            .filter(|(bit, _)| unix & bit != 0)
+            .fold(unix & 0o777, |mode, (_, go)| mode | go)
+        };
+        #[cfg(not(unix))]
+        let mode = match (metadata.is_dir(), metadata.permissions().readonly()) {
+            (true, _) => 0o755,
+            (false, true) => 0o444,
+            (false, false) => 0o644,
+        };
+
+        let mut stat = Stat {
+            path,
+            mode,
+            mod_time: metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |since| since.as_nanos() as i64),
+            ..Stat::default()
+        };
+        let file_type = metadata.file_type();
+        if file_type.is_dir() {
+            stat.mode |= MODE_DIR;
+        } else if file_type.is_symlink() {
+            stat.mode |= MODE_SYMLINK;
+            stat.linkname = fs::read_link(on_disk)?.to_string_lossy().into_owned();
+        } else {
+            stat.size = metadata.len() as i64;
+        }
+        Ok(stat)
+    }
+
+    pub(crate) fn decode(buf: &[u8]) -> io::Result<Stat> {
+        let mut stat = Stat::default();
+        for field in protobuf::fields(buf) {
+            match field? {
+                (1, value) => stat.path = value.string()?,
+                (2, value) => stat.mode = value.uint()? as u32,
+                (3, value) => stat.uid = value.uint()? as u32,
+                (4, value) => stat.gid = value.uint()? as u32,
+                (5, value) => stat.size = value.int()?,
+                (6, value) => stat.mod_time = value.int()?,
+                (7, value) => stat.linkname = value.string()?,
+                _ => (),
+            }
+        }
+        Ok(stat)
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        protobuf::Encoder::new()
+            .string(1, &self.path)
+            .uint(2, u64::from(self.mode))
+            .uint(3, u64::from(self.uid))
+            .uint(4, u64::from(self.gid))
+            .int(5, self.size)
+            .int(6, self.mod_time)
+            .string(7, &self.linkname)
+            .finish()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum PacketType {
+    #[default]
+    Stat,
+    Req,
+    Data,
+    Fin,
+    Err,
+}
+
+/// A message of the fsutil file sync protocol
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Packet {
+    pub(crate) kind: PacketType,
+    pub(crate) stat: Option<Stat>,
+    /// The index of the stat of the file a request or its data is for
+    pub(crate) id: u32,
+    pub(crate) data: Vec<u8>,
+}
+
+impl Packet {
+    pub(crate) fn decode(buf: &[u8]) -> io::Result<Packet> {
+        let mut packet = Packet::default();
+        for field in protobuf::fields(buf) {
+            match field? {
+                (1, value) => {
+                    packet.kind = match value.uint()? {
+                        0 => PacketType::Stat,
+                        1 => PacketType::Req,
+                        2 => PacketType::Data,
+                        3 => PacketType::Fin,
+                        4 => PacketType::Err,
+                        kind => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("unknown packet type {}", kind),
+                            ))
+                        }
+                    }
+                }
+                (2, value) => packet.stat = Some(Stat::decode(value.bytes()?)?),
+                (3, value) => packet.id = value.uint()? as u32,
+                (4, value) => packet.data = value.bytes()?.to_vec(),
+                _ => (),
+            }
+        }
+        Ok(packet)
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut encoder = protobuf::Encoder::new();
+        encoder.uint(1, self.kind as u64);
+        if let Some(stat) = &self.stat {
+            encoder.message(2, &stat.encode());
+        }
+        encoder
+            .uint(3, u64::from(self.id))
+            .bytes(4, &self.data)
+            .finish()
+    }
+}
+
+/// A gRPC status
+#[derive(Debug)]
+pub(crate) struct Status {
+    code: u32,
+    message: String,
+}
+
+impl Status {
+    const UNKNOWN: u32 = 2;
+    const INVALID_ARGUMENT: u32 = 3;
+    const NOT_FOUND: u32 = 5;
+    const PERMISSION_DENIED: u32 = 7;
+    const UNIMPLEMENTED: u32 = 12;
+    const INTERNAL: u32 = 13;
+    const UNAVAILABLE: u32 = 14;
+
+    fn new<S>(
+        code: u32,
+        message: S,
+    ) -> Self
+    where
+        S: Into<String>,
+    {
+        Status {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<io::Error> for Status {
+    fn from(error: io::Error) -> Self {
+        let code = match error.kind() {
+            io::ErrorKind::NotFound => Status::NOT_FOUND,
+            io::ErrorKind::PermissionDenied => Status::PERMISSION_DENIED,
+            io::ErrorKind::InvalidData => Status::INVALID_ARGUMENT,
+            _ => Status::INTERNAL,
+        };
+        Status::new(code, error.to_string())
+    }
+}
+
+/// Writes the responses of a call
+struct Responder(Sender);
+
+impl Responder {
+    async fn send(
+        &mut self,
+        message: &[u8],
+    ) -> std::result::Result<(), Status> {
+        self.0
+            .send_data(frame(message))
+            .await
+            .map_err(|e| Status::new(Status::UNAVAILABLE, e.to_string()))
+    }
+
+    /// Ends the call with the status in its trailers
+    async fn finish(
+        mut self,
+        result: std::result::Result<(), Status>,
+    ) {
+        let status = result.err().unwrap_or_else(|| Status::new(0, ""));
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from(status.code));
+        if let Ok(message) = HeaderValue::from_str(&percent_encode(&status.message)) {
+            if !message.is_empty() {
+                trailers.insert("grpc-message", message);
+            }
+        }
+        let _ = self.0.send_trailers(trailers).await;
+    }
+}
+
+/// Encodes a `grpc-message` the way the gRPC spec asks for
+fn percent_encode(message: &str) -> String {
+    message
+        .bytes()
+        .map(|b| match b {
+            b' '..=b'~' if b != b'%' => char::from(b).to_string(),
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Prefixes a message with the header of gRPC's length prefixed framing
+pub(crate) fn frame(message: &[u8]) -> Bytes {
+    let mut framed = Vec::with_capacity(5 + message.len());
+    framed.push(0);
+    framed.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    framed.extend_from_slice(message);
+    Bytes::from(framed)
+}
+
+/// Splits the body of a gRPC call into its messages, regardless of how they are split across
+/// chunks
+#[derive(Debug, Default)]
+pub(crate) struct Decoder {
+    buf: BytesMut,
+}
+
+impl Decoder {
+    pub(crate) fn push(
+        &mut self,
+        chunk: &[u8],
+    ) {
+        self.buf.extend_from_slice(chunk);
+    }
+
+    /// Takes the next complete message off the buffer
+    pub(crate) fn next(&mut self) -> io::Result<Option<Bytes>> {
+        if self.buf.len() < 5 {
+            return Ok(None);
+        }
+        if self.buf[0] != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "compressed gRPC messages aren't supported",
+            ));
+        }
+        let len = u32::from_be_bytes([self.buf[1], self.buf[2], self.buf[3], self.buf[4]]) as usize;
+        if self.buf.len() < 5 + len {
+            return Ok(None);
+        }
+        self.buf.advance(5);
+        Ok(Some(self.buf.split_to(len).freeze()))
+    }
+
+    /// Whether a message was cut off
+    pub(crate) fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+}
+
+fn messages(body: Body) -> Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>> {
+    Box::pin(stream::unfold(
+        Some((body, Decoder::default())),
+        |state| async move {
+            let (mut body, mut decoder) = state?;
+            loop {
+                match decoder.next() {
+                    Ok(Some(message)) => return Some((Ok(message), Some((body, decoder)))),
+                    Ok(None) => (),
+                    Err(e) => return Some((Err(e), None)),
+                }
+                match body.next().await {
+                    Some(Ok(chunk)) => decoder.push(&chunk),
+                    Some(Err(e)) => return Some((Err(io::Error::other(e)), None)),
+                    None if decoder.is_empty() => return None,
+                    None => {
+                        let e = io::Error::new(io::ErrorKind::UnexpectedEof, "truncated message");
+                        return Some((Err(e), None));
+                    }
+                }
+            }
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_requested_files() {
+        let dir = std::env::temp_dir().join(format!("shiplift-session-{}", std::process::id()));
+        fs::create_dir_all(dir.join("src/bin")).unwrap();
+        fs::create_dir_all(dir.join("target/debug")).unwrap();
+        for file in &[
+            "Dockerfile",
+            "README.md",
+            "src/lib.rs",
+            "src/bin/main.rs",
+            "target/debug/app",
+            "target/keep",
+        ] {
+            fs::write(dir.join(file), file).unwrap();
+        }
+
+        let list = |includes: &[&str], excludes: &[&str]| {
+            let filter = Filter {
+                includes: Some(includes)
+                    .filter(|includes| !includes.is_empty())
+                    .map(Excludes::from_patterns),
+                excludes: Excludes::from_patterns(excludes),
+            };
+            walk(&dir, &filter)
+                .unwrap()
+                .into_iter()
+                .map(|(stat, _)| stat.path)
+                .collect::<Vec<_>>()
+        };
+        let everything = list(&[], &["target", "!target/keep"]);
+        let dockerfile = list(&["Dockerfile"], &[]);
+        let sources = list(&["src/bin"], &[]);
+        let stats = walk(
+            &dir,
+            &Filter {
+                includes: Some(Excludes::from_patterns(&["src/lib.rs"])),
+                excludes: Excludes::from_patterns(&[] as &[&str]),
+            },
+        )
+        .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            everything,
+            vec![
+                "Dockerfile",
+                "README.md",
+                "src",
+                "src/bin",
+                "src/bin/main.rs",
+                "src/lib.rs",
+                "target",
+                "target/keep",
+            ]
+        );
+        assert_eq!(dockerfile, vec!["Dockerfile"]);
+        assert_eq!(sources, vec!["src", "src/bin", "src/bin/main.rs"]);
+
+        let (dir_stat, file_stat) = (&stats[0].0, &stats[1].0);
+        assert_ne!(dir_stat.mode & MODE_DIR, 0);
+        assert_eq!(file_stat.mode & MODE_DIR, 0);
+        assert_eq!(file_stat.size, "src/lib.rs".len() as i64);
+        let packet = Packet {
+            kind: PacketType::Stat,
+            stat: Some(file_stat.clone()),
+            ..Packet::default()
+        };
+        assert_eq!(Packet::decode(&packet.encode()).unwrap(), packet);
+    }
+
+    #[test]
+    fn split_messages() {
+        let mut body = frame(b"first").to_vec();
+        body.extend_from_slice(&frame(b""));
+        body.extend_from_slice(&frame(b"third"));
+
+        for split in 0..=body.len() {
+            let mut decoder = Decoder::default();
+            let mut messages = Vec::new();
+            for chunk in &[&body[..split], &body[split..]] {
+                decoder.push(chunk);
+                while let Some(message) = decoder.next().unwrap() {
+                    messages.push(message);
+                }
+            }
+            assert_eq!(messages, vec![&b"first"[..], b"", b"third"]);
+            assert!(decoder.is_empty());
+        }
+
+        let mut decoder = Decoder::default();
+        decoder.push(&[1, 0, 0, 0, 0]);
+        assert!(decoder.next().is_err());
+    }
+}
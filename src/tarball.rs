@@ -1,71 +1,650 @@
-use flate2::{write::GzEncoder, Compression};
+//! Tarring up of build contexts
+
+use crate::{builder::ContextCompression, dockerignore::Excludes};
+use bytes::{Bytes, BytesMut};
+use flate2::{Compression, GzBuilder};
+use hyper::Body;
 use std::{
-    fs::{self, File},
-    io::{self, Write},
-    path::{Path, MAIN_SEPARATOR},
+    fmt, fs,
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
 };
-use tar::Builder;
+use tar::{Builder, EntryType, Header, HeaderMode};
+use tokio::sync::mpsc;
+
+/// Size of the chunks the tarball is sent in
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Number of chunks buffered ahead of the daemon reading them
+const CHUNKS_IN_FLIGHT: usize = 8;
+/// The timestamp of entries without one, the same `tar` uses for deterministic headers
+const FIXED_MTIME: u64 = 1_153_704_088;
+
+/// The files sent to the daemon to build an image from
+///
+/// A context is put together from files in memory, files and directories on disk and existing
+/// tarballs, in the order they are added. Later entries replace earlier ones with the same path.
+///
+/// ```no_run
+/// use shiplift::{BuildContext, BuildOptions, Docker};
+///
+/// let docker = Docker::new();
+/// let context = BuildContext::new()
+///     .dockerfile("FROM busybox\nCOPY . /app\n")
+///     .file("config.json", &b"{}"[..], 0o644)
+///     .path("src", "./src");
+/// let events = docker
+///     .images()
+///     .build_from_context(context, &BuildOptions::default());
+/// ```
+#[derive(Default)]
+pub struct BuildContext {
+    sources: Vec<Source>,
+    dockerfile: Option<Bytes>,
+}
+
+enum Source {
+    Memory {
+        path: PathBuf,
+        data: Bytes,
+        mode: u32,
+    },
+    Disk {
+        path: PathBuf,
+        src: PathBuf,
+    },
+    Tar(Box<dyn Read + Send>),
+}
+
+impl BuildContext {
+    /// Creates an empty build context
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file with the given contents and permissions at `path`
+    pub fn file<P, D>(
+        mut self,
+        path: P,
+        data: D,
+        mode: u32,
+    ) -> Self
+    where
+        P: AsRef<Path>,
+        D: Into<Bytes>,
+    {
+        self.sources.push(Source::Memory {
+            path: relative(path.as_ref()),
+            data: data.into(),
+            mode,
+        });
+        self
+    }
+
+    /// Adds the file or directory at `src` on disk at `path`, which is `""` for the root of the
+    /// context
+    ///
+    /// Directories are filtered by their own `.dockerignore` and the excludes of the build options.
+    pub fn path<P, S>(
+        mut self,
+        path: P,
+        src: S,
+    ) -> Self
+    where
+        P: AsRef<Path>,
+        S: Into<PathBuf>,
+    {
+        self.sources.push(Source::Disk {
+            path: relative(path.as_ref()),
+            src: src.into(),
+        });
+        self
+    }
+
+    /// Adds the entries of an uncompressed tarball read from `reader`
+    pub fn tar<R>(
+        mut self,
+        reader: R,
+    ) -> Self
+    where
+        R: Read + Send + 'static,
+    {
+        self.sources.push(Source::Tar(Box::new(reader)));
+        self
+    }
+
+    /// Uses `contents` as the Dockerfile, at the path named by the build options
+    pub fn dockerfile<D>(
+        mut self,
+        contents: D,
+    ) -> Self
+    where
+        D: Into<Bytes>,
+    {
+        self.dockerfile = Some(contents.into());
+        self
+    }
+
+    /// Makes sure the paths on disk exist, so a missing one fails before anything is sent
+    pub(crate) fn check(&self) -> io::Result<()> {
+        for source in &self.sources {
+            if let Source::Disk { src, .. } = source {
+                fs::symlink_metadata(src)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for BuildContext {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        let sources = self
+            .sources
+            .iter()
+            .map(|source| match source {
+                Source::Memory { path, .. } => format!("{} (memory)", path.display()),
+                Source::Disk { path, src } => format!("{} ({})", path.display(), src.display()),
+                Source::Tar(_) => "(tarball)".to_owned(),
+            })
+            .collect::<Vec<_>>();
+        f.debug_struct("BuildContext")
+            .field("sources", &sources)
+            .field("dockerfile", &self.dockerfile.is_some())
+            .finish()
+    }
+}
+
+/// Keeps only the named components of `path`, so it is relative to the root of the context and
+/// can't escape it
+fn relative(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
+
+/// How a build context is tarred up
+#[derive(Debug, Clone, Default)]
+pub struct ContextOptions {
+    /// The path of the Dockerfile within the context, which is always included
+    pub dockerfile: String,
+    /// Patterns followed after those of the `.dockerignore`
+    pub excludes: Vec<String>,
+    pub compression: ContextCompression,
+    /// Whether to leave out ownership and timestamps, so identical files give identical tarballs
+    pub deterministic: bool,
+}
+
+/// Tars up `context` on a blocking task, streaming the tarball as it is written
+///
+/// At most `CHUNKS_IN_FLIGHT` chunks are buffered, so memory use doesn't depend on the size of the
+/// context. If tarring fails, the body ends with the error.
+pub fn stream(
+    context: BuildContext,
+    options: ContextOptions,
+) -> Body {
+    let (tx, mut rx) = mpsc::channel(CHUNKS_IN_FLIGHT);
+    tokio::task::spawn_blocking(move || {
+        let mut writer = ChannelWriter {
+            buf: BytesMut::with_capacity(CHUNK_SIZE),
+            tx,
+        };
+        let result = write(&mut writer, context, &options).and_then(|()| writer.flush());
+        if let Err(e) = result {
+            // if the body was dropped there is nobody left to tell
+            let _ = writer.tx.blocking_send(Err(e));
+        }
+    });
+
+    Body::wrap_stream(futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx)))
+}
+
+/// Sends what is written to it over a channel in chunks of `CHUNK_SIZE`, blocking while the
+/// channel is full
+struct ChannelWriter {
+    buf: BytesMut,
+    tx: mpsc::Sender<io::Result<Bytes>>,
+}
+
+impl Write for ChannelWriter {
+    fn write(
+        &mut self,
+        data: &[u8],
+    ) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = self.buf.split().freeze();
+        self.tx
+            .blocking_send(Ok(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "build context was dropped"))
+    }
+}
 
-// todo: this is pretty involved. (re)factor this into its own crate
-pub fn dir<W>(
+/// Writes a tarball of `context` to `buf`
+///
+/// Directory entries are sorted by path and symlinks are kept as symlinks.
+pub fn write<W>(
     buf: W,
-    path: &str,
+    context: BuildContext,
+    options: &ContextOptions,
 ) -> io::Result<()>
 where
     W: Write,
 {
-    let mut archive = Builder::new(GzEncoder::new(buf, Compression::best()));
-    fn bundle<F>(
-        dir: &Path,
-        f: &mut F,
-        bundle_dir: bool,
-    ) -> io::Result<()>
-    where
-        F: FnMut(&Path) -> io::Result<()>,
-    {
-        if fs::metadata(dir)?.is_dir() {
-            if bundle_dir {
-                f(&dir)?;
+    match options.compression {
+        ContextCompression::None => {
+            let mut archive = archive(buf, options);
+            append_context(&mut archive, context, options)?;
+            archive.into_inner()?;
+        }
+        ContextCompression::Gzip(level) => {
+            // the default header has no file name or timestamp, so equal tarballs gzip equally
+            let gzip = GzBuilder::new().write(buf, Compression::new(level));
+            let mut archive = archive(gzip, options);
+            append_context(&mut archive, context, options)?;
+            archive.into_inner()?.finish()?;
+        }
+    }
+    Ok(())
+}
+
+fn archive<W>(
+    buf: W,
+    options: &ContextOptions,
+) -> Builder<W>
+where
+    W: Write,
+{
+    let mut archive = Builder::new(buf);
+    archive.follow_symlinks(false);
+    if options.deterministic {
+        // a fixed mtime, no ownership, and only the executable bit of the permissions
+        archive.mode(HeaderMode::Deterministic);
+    }
+    archive
+}
+
+fn append_context<W>(
+    archive: &mut Builder<W>,
+    context: BuildContext,
+    options: &ContextOptions,
+) -> io::Result<()>
+where
+    W: Write,
+{
+    for source in context.sources {
+        match source {
+            Source::Memory { path, data, mode } => {
+                append_memory(archive, &path, &data, mode, options)?
             }
-            for entry in fs::read_dir(dir)? {
-                let entry = entry?;
-                if fs::metadata(entry.path())?.is_dir() {
-                    bundle(&entry.path(), f, true)?;
+            // the named path itself is followed if it is a symlink, unlike those inside it
+            Source::Disk { path, src } => {
+                if fs::metadata(&src)?.is_dir() {
+                    let excludes = Excludes::load(&src, &options.dockerfile, &options.excludes)?;
+                    append_dir(archive, &path, &src, &excludes)?;
+                } else if path.as_os_str().is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{} is not a directory", src.display()),
+                    ));
                 } else {
-                    f(&entry.path().as_path())?;
+                    archive.append_file(&path, &mut fs::File::open(&src)?)?;
                 }
             }
+            Source::Tar(reader) => append_tar(archive, reader, options)?,
         }
-        Ok(())
     }
+    if let Some(dockerfile) = context.dockerfile {
+        append_memory(
+            archive,
+            &relative(Path::new(&options.dockerfile)),
+            &dockerfile,
+            0o644,
+            options,
+        )?;
+    }
+    Ok(())
+}
 
-    {
-        let base_path = Path::new(path).canonicalize()?;
-        // todo: don't unwrap
-        let mut base_path_str = base_path.to_str().unwrap().to_owned();
-        if let Some(last) = base_path_str.chars().last() {
-            if last != MAIN_SEPARATOR {
-                base_path_str.push(MAIN_SEPARATOR)
+fn append_memory<W>(
+    archive: &mut Builder<W>,
+    path: &Path,
+    data: &[u8],
+    mode: u32,
+    options: &ContextOptions,
+) -> io::Result<()>
+where
+    W: Write,
+{
+    let mut header = Header::new_gnu();
+    header.set_entry_type(EntryType::Regular);
+    header.set_size(data.len() as u64);
+    header.set_mode(mode);
+    header.set_mtime(FIXED_MTIME);
+    if options.deterministic {
+        normalize(&mut header)?;
+    }
+    archive.append_data(&mut header, path, data)
+}
+
+/// Copies the entries of the tarball read from `reader`
+fn append_tar<W>(
+    archive: &mut Builder<W>,
+    reader: Box<dyn Read + Send>,
+    options: &ContextOptions,
+) -> io::Result<()>
+where
+    W: Write,
+{
+    let mut tarball = tar::Archive::new(reader);
+    for entry in tarball.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let mut header = entry.header().clone();
+        if options.deterministic {
+            normalize(&mut header)?;
+        }
+        match entry.link_name()? {
+            Some(target) => {
+                let target = target.into_owned();
+                archive.append_link(&mut header, &path, &target)?
             }
+            None => archive.append_data(&mut header, &path, &mut entry)?,
         }
+    }
+    Ok(())
+}
 
-        let mut append = |path: &Path| {
-            let canonical = path.canonicalize()?;
-            // todo: don't unwrap
-            let relativized = canonical
-                .to_str()
-                .unwrap()
-                .trim_start_matches(&base_path_str[..]);
-            if path.is_dir() {
-                archive.append_dir(Path::new(relativized), &canonical)?
-            } else {
-                archive.append_file(Path::new(relativized), &mut File::open(&canonical)?)?
+/// Clears the timestamp, ownership and permissions but the executable bit like
+/// [`HeaderMode::Deterministic`] does for files on disk
+fn normalize(header: &mut Header) -> io::Result<()> {
+    header.set_mtime(FIXED_MTIME);
+    header.set_uid(0);
+    header.set_gid(0);
+    if header.as_ustar().is_some() || header.as_gnu().is_some() {
+        header.set_username("")?;
+        header.set_groupname("")?;
+    }
+    let executable = header.entry_type().is_dir() || header.mode()? & 0o100 != 0;
+    header.set_mode(if executable { 0o755 } else { 0o644 });
+    Ok(())
+}
+
+/// Appends everything under the directory `src` that isn't excluded at `path`
+fn append_dir<W>(
+    archive: &mut Builder<W>,
+    path: &Path,
+    src: &Path,
+    excludes: &Excludes,
+) -> io::Result<()>
+where
+    W: Write,
+{
+    fn walk<W>(
+        archive: &mut Builder<W>,
+        root: &Path,
+        dir: &Path,
+        prefix: &Path,
+        excludes: &Excludes,
+    ) -> io::Result<()>
+    where
+        W: Write,
+    {
+        let mut entries = fs::read_dir(root.join(dir))?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let relative = dir.join(entry.file_name());
+            let excluded = excludes.excludes(&relative);
+            if !excluded {
+                archive.append_path_with_name(entry.path(), prefix.join(&relative))?;
+            }
+            if entry.file_type()?.is_dir() && (!excluded || excludes.may_keep_within(&relative)) {
+                walk(archive, root, &relative, prefix, excludes)?;
             }
-            Ok(())
+        }
+        Ok(())
+    }
+
+    if !path.as_os_str().is_empty() {
+        archive.append_dir(path, src)?;
+    }
+    walk(archive, &src.canonicalize()?, Path::new(""), path, excludes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::{fs::File, io::Read};
+
+    fn entries(tarball: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut archive = tar::Archive::new(tarball);
+        let mut entries = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let path = entry.path().unwrap().to_string_lossy().into_owned();
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data).unwrap();
+                (path, data)
+            })
+            .collect::<Vec<_>>();
+        entries.sort();
+        entries
+    }
+
+    #[tokio::test]
+    async fn streams_directories() {
+        let dir = std::env::temp_dir().join(format!("shiplift-tarball-{}", std::process::id()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("Dockerfile"), "FROM scratch\n").unwrap();
+        fs::write(dir.join(".dockerignore"), "target\n").unwrap();
+        fs::create_dir_all(dir.join("target").join("debug")).unwrap();
+        fs::write(dir.join("target").join("debug").join("app"), "binary").unwrap();
+        fs::write(dir.join("build.log"), "log").unwrap();
+        // spans several chunks
+        let big = (0..CHUNK_SIZE * 3)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        fs::write(dir.join("src").join("big"), &big).unwrap();
+        let path = dir.to_string_lossy().into_owned();
+
+        let stream = |compression| {
+            stream(
+                BuildContext::new().path("", &path),
+                ContextOptions {
+                    dockerfile: "Dockerfile".to_owned(),
+                    excludes: vec!["*.log".to_owned()],
+                    compression,
+                    deterministic: false,
+                },
+            )
         };
-        bundle(Path::new(path), &mut append, false)?;
+        let plain = hyper::body::to_bytes(stream(ContextCompression::None))
+            .await
+            .unwrap();
+        let gzipped = hyper::body::to_bytes(stream(ContextCompression::Gzip(1)))
+            .await
+            .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut unzipped = Vec::new();
+        GzDecoder::new(&gzipped[..])
+            .read_to_end(&mut unzipped)
+            .unwrap();
+        assert_eq!(unzipped, plain);
+        assert!(gzipped.len() < plain.len());
+
+        let entries = entries(&plain);
+        let files = entries
+            .iter()
+            .filter(|(_, data)| !data.is_empty())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            [
+                &(".dockerignore".to_owned(), b"target\n".to_vec()),
+                &("Dockerfile".to_owned(), b"FROM scratch\n".to_vec()),
+                &("src/big".to_owned(), big)
+            ]
+        );
     }
-    archive.finish()?;
 
-    Ok(())
+    #[tokio::test]
+    async fn stream_errors_end_the_body() {
+        let body = stream(
+            BuildContext::new().path("", "/nonexistent/shiplift/context"),
+            ContextOptions::default(),
+        );
+        assert!(hyper::body::to_bytes(body).await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn deterministic_tarballs() {
+        use std::{
+            os::unix::fs::{symlink, PermissionsExt},
+            time::{Duration, SystemTime},
+        };
+
+        let dir = std::env::temp_dir().join(format!(
+            "shiplift-tarball-deterministic-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(dir.join("b")).unwrap();
+        fs::write(dir.join("c"), "c").unwrap();
+        fs::write(dir.join("b").join("run.sh"), "#!/bin/sh\n").unwrap();
+        fs::set_permissions(
+            dir.join("b").join("run.sh"),
+            fs::Permissions::from_mode(0o700),
+        )
+        .unwrap();
+        fs::write(dir.join("a"), "a").unwrap();
+        symlink("b/run.sh", dir.join("link")).unwrap();
+
+        let options = ContextOptions {
+            dockerfile: "Dockerfile".to_owned(),
+            deterministic: true,
+            ..Default::default()
+        };
+        let tar = || {
+            let mut tarball = Vec::new();
+            super::write(&mut tarball, BuildContext::new().path("", &dir), &options).unwrap();
+            tarball
+        };
+        let first = tar();
+        File::options()
+            .write(true)
+            .open(dir.join("a"))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(3600))
+            .unwrap();
+        let second = tar();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(first, second);
+
+        let mut unzipped = Vec::new();
+        GzDecoder::new(&first[..])
+            .read_to_end(&mut unzipped)
+            .unwrap();
+        let mut archive = tar::Archive::new(&unzipped[..]);
+        let headers = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().header().clone())
+            .collect::<Vec<_>>();
+        let paths = headers
+            .iter()
+            .map(|h| h.path().unwrap().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["a", "b", "b/run.sh", "c", "link"]);
+        assert!(headers
+            .iter()
+            .all(|h| h.uid().unwrap() == 0 && h.mtime().unwrap() == headers[0].mtime().unwrap()));
+        assert_eq!(headers[0].mode().unwrap(), 0o644);
+        assert_eq!(headers[2].mode().unwrap(), 0o755);
+        assert!(headers[4].entry_type().is_symlink());
+        assert_eq!(
+            headers[4].link_name().unwrap().unwrap(),
+            Path::new("b/run.sh")
+        );
+    }
+
+    #[test]
+    fn combine_sources() {
+        let dir =
+            std::env::temp_dir().join(format!("shiplift-tarball-sources-{}", std::process::id()));
+        fs::create_dir_all(dir.join("vendor")).unwrap();
+        fs::write(dir.join("vendor").join("lib.rs"), "pub fn vendored() {}").unwrap();
+        fs::write(dir.join("single.txt"), "single").unwrap();
+
+        let mut existing = Builder::new(Vec::new());
+        let mut header = Header::new_gnu();
+        header.set_size(8);
+        header.set_mode(0o600);
+        header.set_uid(1000);
+        existing
+            .append_data(&mut header, "from/tar.txt", &b"from tar"[..])
+            .unwrap();
+        let existing = existing.into_inner().unwrap();
+
+        let context = BuildContext::new()
+            .file("Dockerfile", &b"FROM replaced"[..], 0o644)
+            .file("/../bin/run", &b"#!/bin/sh"[..], 0o750)
+            .path("copied", dir.join("vendor"))
+            .path("./single.txt", dir.join("single.txt"))
+            .tar(io::Cursor::new(existing))
+            .dockerfile("FROM scratch\n");
+        let options = ContextOptions {
+            dockerfile: "Dockerfile".to_owned(),
+            compression: ContextCompression::None,
+            deterministic: true,
+            ..Default::default()
+        };
+        let mut tarball = Vec::new();
+        write(&mut tarball, context, &options).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut archive = tar::Archive::new(&tarball[..]);
+        let entries = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut data = String::new();
+                entry.read_to_string(&mut data).unwrap();
+                let header = entry.header();
+                (
+                    entry.path().unwrap().to_string_lossy().into_owned(),
+                    header.mode().unwrap(),
+                    header.uid().unwrap(),
+                    data,
+                )
+            })
+            .collect::<Vec<_>>();
+        let expected = [
+            ("Dockerfile", 0o644, 0, "FROM replaced"),
+            ("bin/run", 0o755, 0, "#!/bin/sh"),
+            ("copied", 0o755, 0, ""),
+            ("copied/lib.rs", 0o644, 0, "pub fn vendored() {}"),
+            ("single.txt", 0o644, 0, "single"),
+            ("from/tar.txt", 0o644, 0, "from tar"),
+            ("Dockerfile", 0o644, 0, "FROM scratch\n"),
+        ];
+        assert_eq!(entries.len(), expected.len());
+        for (entry, expected) in entries.iter().zip(&expected) {
+            assert_eq!((&entry.0[..], entry.1, entry.2, &entry.3[..]), *expected);
+        }
+    }
 }
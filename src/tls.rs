@@ -0,0 +1,335 @@
+//! TLS settings for connecting to a docker daemon over tcp
+//!
+//! The `tls` feature builds encrypted transports on OpenSSL, the `rustls-tls` feature on
+//! rustls. OpenSSL is used when both are enabled.
+
+use crate::errors::ConfigError;
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// PEM encoded certificates or keys, either in a file or in memory
+#[derive(Clone, Debug)]
+pub(crate) enum Pem {
+    File(PathBuf),
+    Memory(Vec<u8>),
+}
+
+impl Pem {
+    /// Returns the PEM contents, reporting a missing file as such rather than as an
+    /// unreadable certificate
+    #[cfg_attr(not(any(feature = "tls", feature = "rustls-tls")), allow(dead_code))]
+    pub fn read(&self) -> Result<Vec<u8>, ConfigError> {
+        match self {
+            Pem::File(path) => fs::read(path).map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => ConfigError::MissingCertificate(path.clone()),
+                _ => ConfigError::InvalidCertificate {
+                    path: path.clone(),
+                    message: e.to_string(),
+                },
+            }),
+            Pem::Memory(pem) => Ok(pem.clone()),
+        }
+    }
+
+    /// Returns the path reported in errors, a placeholder for in-memory PEM
+    #[cfg_attr(not(any(feature = "tls", feature = "rustls-tls")), allow(dead_code))]
+    pub fn path(&self) -> PathBuf {
+        match self {
+            Pem::File(path) => path.clone(),
+            Pem::Memory(_) => PathBuf::from("<memory>"),
+        }
+    }
+}
+
+/// Certificates and verification settings for an encrypted tcp transport
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TlsConfig {
+    /// CA certificates used to verify the daemon
+    pub ca: Option<Pem>,
+    /// Client certificate presented to the daemon, optionally followed by its chain
+    pub cert: Option<Pem>,
+    /// Private key of the client certificate
+    pub key: Option<Pem>,
+    /// Disables verification of the daemon's certificate
+    pub skip_verify: bool,
+}
+
+impl TlsConfig {
+    /// Reads `DOCKER_CERT_PATH` and `DOCKER_TLS_VERIFY`, returning `None` when TLS is not
+    /// configured
+    pub fn from_env() -> Option<TlsConfig> {
+        let dir = PathBuf::from(env::var_os("DOCKER_CERT_PATH")?);
+        Some(TlsConfig {
+            ca: env::var_os("DOCKER_TLS_VERIFY").map(|_| Pem::File(dir.join("ca.pem"))),
+            cert: Some(Pem::File(dir.join("cert.pem"))),
+            key: Some(Pem::File(dir.join("key.pem"))),
+            skip_verify: false,
+        })
+    }
+
+    /// Uses whichever of `ca.pem`, `cert.pem` and `key.pem` exist in `dir`, returning `None`
+    /// if there are none
+    pub fn from_dir(
+        dir: &Path,
+        skip_verify: bool,
+    ) -> Option<TlsConfig> {
+        let existing = |name: &str| {
+            Some(dir.join(name))
+                .filter(|path| path.is_file())
+                .map(Pem::File)
+        };
+        let tls = TlsConfig {
+            ca: existing("ca.pem"),
+            cert: existing("cert.pem"),
+            key: existing("key.pem"),
+            skip_verify,
+        };
+        if tls.is_empty() {
+            None
+        } else {
+            Some(tls)
+        }
+    }
+
+    /// Returns true if no certificates are configured
+    pub fn is_empty(&self) -> bool {
+        self.ca.is_none() && self.cert.is_none() && self.key.is_none()
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn connector(
+        &self,
+        http: hyper::client::HttpConnector,
+    ) -> Result<hyper_openssl::HttpsConnector<hyper::client::HttpConnector>, ConfigError> {
+        use openssl::{
+            pkey::PKey,
+            ssl::{SslConnector, SslMethod, SslVerifyMode},
+        };
+
+        let tls_error = |e: openssl::error::ErrorStack| ConfigError::Tls(e.to_string());
+        let mut connector = SslConnector::builder(SslMethod::tls()).map_err(tls_error)?;
+        connector.set_cipher_list("DEFAULT").map_err(tls_error)?;
+        if let Some(cert) = &self.cert {
+            let mut chain = certificates(cert)?.into_iter();
+            let leaf = chain.next().expect("certificates are never empty");
+            connector.set_certificate(&leaf).map_err(invalid(cert))?;
+            for intermediate in chain {
+                connector
+                    .add_extra_chain_cert(intermediate)
+                    .map_err(invalid(cert))?;
+            }
+        }
+        if let Some(key) = &self.key {
+            let pkey = PKey::private_key_from_pem(&key.read()?).map_err(invalid(key))?;
+            connector.set_private_key(&pkey).map_err(invalid(key))?;
+            connector.check_private_key().map_err(invalid(key))?;
+        }
+        if let Some(ca) = &self.ca {
+            for cert in certificates(ca)? {
+                connector
+                    .cert_store_mut()
+                    .add_cert(cert)
+                    .map_err(invalid(ca))?;
+            }
+        }
+        if self.skip_verify {
+            connector.set_verify(SslVerifyMode::NONE);
+        }
+
+        hyper_openssl::HttpsConnector::with_connector(http, connector).map_err(tls_error)
+    }
+}
+
+/// Parses the certificates in `pem`, failing if there are none
+#[cfg(feature = "tls")]
+fn certificates(pem: &Pem) -> Result<Vec<openssl::x509::X509>, ConfigError> {
+    let certs = openssl::x509::X509::stack_from_pem(&pem.read()?).map_err(invalid(pem))?;
+    if certs.is_empty() {
+        return Err(ConfigError::InvalidCertificate {
+            path: pem.path(),
+            message: "no PEM encoded certificate found".to_owned(),
+        });
+    }
+    Ok(certs)
+}
+
+#[cfg(feature = "tls")]
+fn invalid(pem: &Pem) -> impl Fn(openssl::error::ErrorStack) -> ConfigError + '_ {
+    move |e| ConfigError::InvalidCertificate {
+        path: pem.path(),
+        message: e.to_string(),
+    }
+}
+
+#[cfg(all(feature = "rustls-tls", not(feature = "tls")))]
+impl TlsConfig {
+    pub fn connector(
+        &self,
+        http: hyper::client::HttpConnector,
+    ) -> Result<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>, ConfigError> {
+        use rustls::ClientConfig;
+        use std::sync::Arc;
+
+        let mut roots = rustls::RootCertStore::empty();
+        if let Some(ca) = &self.ca {
+            for cert in rustls_certificates(ca)? {
+                roots.add(&cert).map_err(rustls_invalid(ca))?;
+            }
+        } else if !self.skip_verify {
+            // like OpenSSL's default verify paths, fall back on the system's certificates
+            let native = rustls_native_certs::load_native_certs()
+                .map_err(|e| ConfigError::Tls(e.to_string()))?;
+            roots.add_parsable_certificates(
+                &native.into_iter().map(|cert| cert.0).collect::<Vec<_>>(),
+            );
+        }
+
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let cert = match &self.cert {
+            Some(pem) => Some((pem, rustls_certificates(pem)?)),
+            None => None,
+        };
+        let key = match &self.key {
+            Some(pem) => Some((pem, rustls_private_key(pem)?)),
+            None => None,
+        };
+        let unpaired = |pem: &Pem| ConfigError::InvalidCertificate {
+            path: pem.path(),
+            message: "client certificates need both a certificate and a private key".to_owned(),
+        };
+        let mut config = match (cert, key) {
+            (Some((_, cert)), Some((pem, key))) => builder
+                .with_client_auth_cert(cert, key)
+                .map_err(rustls_invalid(pem))?,
+            (Some((pem, _)), None) => return Err(unpaired(pem)),
+            (None, Some((pem, _))) => return Err(unpaired(pem)),
+            (None, None) => builder.with_no_client_auth(),
+        };
+        if self.skip_verify {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(NoVerification));
+        }
+
+        Ok(hyper_rustls::HttpsConnectorBuilder::new()
+            .with_tls_config(config)
+            .https_or_http()
+            .enable_http1()
+            .wrap_connector(http))
+    }
+}
+
+/// Parses the certificates in `pem`, failing if there are none
+#[cfg(all(feature = "rustls-tls", not(feature = "tls")))]
+fn rustls_certificates(pem: &Pem) -> Result<Vec<rustls::Certificate>, ConfigError> {
+    let certs = rustls_pemfile::certs(&mut pem.read()?.as_slice()).map_err(|e| {
+        ConfigError::InvalidCertificate {
+            path: pem.path(),
+            message: e.to_string(),
+        }
+    })?;
+    if certs.is_empty() {
+        return Err(ConfigError::InvalidCertificate {
+            path: pem.path(),
+            message: "no PEM encoded certificate found".to_owned(),
+        });
+    }
+    Ok(certs.into_iter().map(rustls::Certificate).collect())
+}
+
+/// Parses the first RSA, PKCS#8 or SEC1 private key in `pem`
+#[cfg(all(feature = "rustls-tls", not(feature = "tls")))]
+fn rustls_private_key(pem: &Pem) -> Result<rustls::PrivateKey, ConfigError> {
+    use rustls_pemfile::Item;
+
+    let invalid = |message: String| ConfigError::InvalidCertificate {
+        path: pem.path(),
+        message,
+    };
+    let bytes = pem.read()?;
+    let mut reader = bytes.as_slice();
+    loop {
+        match rustls_pemfile::read_one(&mut reader).map_err(|e| invalid(e.to_string()))? {
+            Some(Item::RSAKey(key)) | Some(Item::PKCS8Key(key)) | Some(Item::ECKey(key)) => {
+                return Ok(rustls::PrivateKey(key))
+            }
+            Some(_) => continue,
+            None => return Err(invalid("no PEM encoded private key found".to_owned())),
+        }
+    }
+}
+
+#[cfg(all(feature = "rustls-tls", not(feature = "tls")))]
+fn rustls_invalid(pem: &Pem) -> impl Fn(rustls::Error) -> ConfigError + '_ {
+    move |e| ConfigError::InvalidCertificate {
+        path: pem.path(),
+        message: e.to_string(),
+    }
+}
+
+/// Accepts any server certificate, for `tls_skip_verify`
+#[cfg(all(feature = "rustls-tls", not(feature = "tls")))]
+struct NoVerification;
+
+#[cfg(all(feature = "rustls-tls", not(feature = "tls")))]
+impl rustls::client::ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(all(test, any(feature = "tls", feature = "rustls-tls")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_certificate_errors() {
+        let dir = env::temp_dir().join(format!("shiplift-tls-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let http = hyper::client::HttpConnector::new;
+
+        let tls = TlsConfig {
+            cert: Some(Pem::File(dir.join("cert.pem"))),
+            ..TlsConfig::default()
+        };
+        assert!(matches!(
+            tls.connector(http()),
+            Err(ConfigError::MissingCertificate(path)) if path == dir.join("cert.pem")
+        ));
+
+        fs::write(dir.join("ca.pem"), "not a certificate").unwrap();
+        let tls = TlsConfig {
+            ca: Some(Pem::File(dir.join("ca.pem"))),
+            ..TlsConfig::default()
+        };
+        assert!(matches!(
+            tls.connector(http()),
+            Err(ConfigError::InvalidCertificate { path, .. }) if path == dir.join("ca.pem")
+        ));
+
+        let tls = TlsConfig {
+            key: Some(Pem::Memory(b"not a key".to_vec())),
+            ..TlsConfig::default()
+        };
+        assert!(matches!(
+            tls.connector(http()),
+            Err(ConfigError::InvalidCertificate { .. })
+        ));
+
+        assert!(TlsConfig::default().connector(http()).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,5 +1,7 @@
 //! Transports for communicating with the docker daemon
 
+#[cfg(feature = "ssh")]
+use crate::ssh::SshConnector;
 use crate::{Error, Result};
 use futures_util::{
     io::{AsyncRead, AsyncWrite},
@@ -8,11 +10,18 @@ use futures_util::{
 };
 use hyper::{
     body::Bytes,
-    client::{Client, HttpConnector},
-    header, Body, Method, Request, StatusCode,
+    client::{
+        connect::{Connected, Connection},
+        Client, HttpConnector,
+    },
+    header::{self, HeaderMap},
+    service::Service,
+    Body, Method, Request, StatusCode, Uri,
 };
 #[cfg(feature = "tls")]
 use hyper_openssl::HttpsConnector;
+#[cfg(all(feature = "rustls-tls", not(feature = "tls")))]
+use hyper_rustls::HttpsConnector;
 #[cfg(feature = "unix-socket")]
 use hyperlocal::UnixConnector;
 #[cfg(feature = "unix-socket")]
@@ -21,11 +30,24 @@ use mime::Mime;
 use pin_project::pin_project;
 use serde::{Deserialize, Serialize};
 use std::{
-    fmt, io, iter,
+    error::Error as StdError,
+    fmt,
+    future::Future,
+    io,
     pin::Pin,
     task::{Context, Poll},
 };
 
+/// Returns the method and the path and query of a request, for error reporting
+fn describe(req: &Request<Body>) -> (Method, String) {
+    let endpoint = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or_else(|| req.uri().path());
+    (req.method().clone(), endpoint.to_owned())
+}
+
 pub fn tar() -> Mime {
     "application/tar".parse().unwrap()
 }
@@ -43,7 +65,7 @@ pub enum Transport {
         host: String,
     },
     /// TCP/TLS
-    #[cfg(feature = "tls")]
+    #[cfg(any(feature = "tls", feature = "rustls-tls"))]
     EncryptedTcp {
         client: Client<HttpsConnector<HttpConnector>>,
         host: String,
@@ -54,6 +76,18 @@ pub enum Transport {
         client: Client<UnixConnector>,
         path: String,
     },
+    /// HTTP over the stdio of `ssh ... docker system dial-stdio`
+    #[cfg(feature = "ssh")]
+    Ssh {
+        client: Client<SshConnector>,
+        /// The command line spawned for each connection
+        command: String,
+    },
+    /// A user supplied connector
+    Custom {
+        client: Client<BoxedConnector>,
+        host: String,
+    },
 }
 
 impl fmt::Debug for Transport {
@@ -63,10 +97,13 @@ impl fmt::Debug for Transport {
     ) -> fmt::Result {
         match *self {
             Transport::Tcp { ref host, .. } => write!(f, "Tcp({})", host),
-            #[cfg(feature = "tls")]
+            #[cfg(any(feature = "tls", feature = "rustls-tls"))]
             Transport::EncryptedTcp { ref host, .. } => write!(f, "EncryptedTcp({})", host),
             #[cfg(feature = "unix-socket")]
             Transport::Unix { ref path, .. } => write!(f, "Unix({})", path),
+            #[cfg(feature = "ssh")]
+            Transport::Ssh { ref command, .. } => write!(f, "Ssh({})", command),
+            Transport::Custom { ref host, .. } => write!(f, "Custom({})", host),
         }
     }
 }
@@ -91,6 +128,23 @@ impl Transport {
         Ok(string)
     }
 
+    /// Make a request and return the response headers, discarding the body
+    pub async fn request_headers<H>(
+        &self,
+        method: Method,
+        endpoint: impl AsRef<str>,
+        headers: Option<H>,
+    ) -> Result<HeaderMap>
+    where
+        H: IntoIterator<Item = (&'static str, String)>,
+    {
+        let response = self
+            .get_response(method, endpoint, Option::<(Body, Mime)>::None, headers)
+            .await?;
+
+        Ok(response.headers().clone())
+    }
+
     async fn get_body<B, H>(
         &self,
         method: Method,
@@ -98,6 +152,22 @@ impl Transport {
         body: Option<(B, Mime)>,
         headers: Option<H>,
     ) -> Result<Body>
+    where
+        B: Into<Body>,
+        H: IntoIterator<Item = (&'static str, String)>,
+    {
+        let response = self.get_response(method, endpoint, body, headers).await?;
+
+        Ok(response.into_body())
+    }
+
+    async fn get_response<B, H>(
+        &self,
+        method: Method,
+        endpoint: impl AsRef<str>,
+        body: Option<(B, Mime)>,
+        headers: Option<H>,
+    ) -> Result<hyper::Response<Body>>
     where
         B: Into<Body>,
         H: IntoIterator<Item = (&'static str, String)>,
@@ -106,6 +176,7 @@ impl Transport {
             .build_request(method, endpoint, body, headers, Request::builder())
             .expect("Failed to build request!");
 
+        let (method, endpoint) = describe(&req);
         let response = self.send_request(req).await?;
 
         let status = response.status();
@@ -115,7 +186,7 @@ impl Transport {
             StatusCode::OK
             | StatusCode::CREATED
             | StatusCode::SWITCHING_PROTOCOLS
-            | StatusCode::NO_CONTENT => Ok(response.into_body()),
+            | StatusCode::NO_CONTENT => Ok(response),
             _ => {
                 let bytes = hyper::body::to_bytes(response.into_body()).await?;
                 let message_body = String::from_utf8(bytes.to_vec())?;
@@ -128,6 +199,8 @@ impl Transport {
                             .unwrap_or("unknown error code")
                             .to_owned()
                     }),
+                    method,
+                    endpoint,
                 })
             }
         }
@@ -183,7 +256,7 @@ impl Transport {
                     .method(method)
                     .uri(&format!("{}{}", host, endpoint.as_ref()))
             }
-            #[cfg(feature = "tls")]
+            #[cfg(any(feature = "tls", feature = "rustls-tls"))]
             Transport::EncryptedTcp { ref host, .. } => {
                 builder
                     .method(method)
@@ -191,9 +264,21 @@ impl Transport {
             }
             #[cfg(feature = "unix-socket")]
             Transport::Unix { ref path, .. } => {
-                let uri = DomainUri::new(&path, endpoint.as_ref());
+                let uri = DomainUri::new(path, endpoint.as_ref());
                 builder.method(method).uri(uri)
             }
+            #[cfg(feature = "ssh")]
+            Transport::Ssh { .. } => {
+                // the connector ignores the uri, the authority only fills in the Host header
+                builder
+                    .method(method)
+                    .uri(&format!("http://docker{}", endpoint.as_ref()))
+            }
+            Transport::Custom { ref host, .. } => {
+                builder
+                    .method(method)
+                    .uri(&format!("{}{}", host, endpoint.as_ref()))
+            }
         };
         let mut req = req.header(header::HOST, "");
 
@@ -216,39 +301,52 @@ impl Transport {
         &self,
         req: Request<hyper::Body>,
     ) -> Result<hyper::Response<Body>> {
-        match self {
-            Transport::Tcp { ref client, .. } => Ok(client.request(req).await?),
-            #[cfg(feature = "tls")]
-            Transport::EncryptedTcp { ref client, .. } => Ok(client.request(req).await?),
+        let (method, endpoint) = describe(&req);
+        let response = match self {
+            Transport::Tcp { ref client, .. } => client.request(req).await,
+            #[cfg(any(feature = "tls", feature = "rustls-tls"))]
+            Transport::EncryptedTcp { ref client, .. } => client.request(req).await,
             #[cfg(feature = "unix-socket")]
-            Transport::Unix { ref client, .. } => Ok(client.request(req).await?),
-        }
+            Transport::Unix { ref client, .. } => client.request(req).await,
+            #[cfg(feature = "ssh")]
+            Transport::Ssh { ref client, .. } => client.request(req).await,
+            Transport::Custom { ref client, .. } => client.request(req).await,
+        };
+
+        response.map_err(|source| Error::Request {
+            method,
+            endpoint,
+            source,
+        })
     }
 
     /// Makes an HTTP request, upgrading the connection to a TCP
     /// stream on success.
     ///
     /// This method can be used for operations such as viewing
-    /// docker container logs interactively.
-    async fn stream_upgrade_tokio<B>(
+    /// docker container logs interactively. Requests upgrade to `tcp` unless `headers` name
+    /// another protocol.
+    pub(crate) async fn stream_upgrade_tokio<B, H>(
         &self,
         method: Method,
         endpoint: impl AsRef<str>,
         body: Option<(B, Mime)>,
+        headers: Option<H>,
     ) -> Result<hyper::upgrade::Upgraded>
     where
         B: Into<Body>,
+        H: IntoIterator<Item = (&'static str, String)>,
     {
+        let headers: Vec<_> = headers.into_iter().flatten().collect();
+        let mut builder = Request::builder().header(header::CONNECTION, "Upgrade");
+        if !headers
+            .iter()
+            .any(|(k, _)| header::UPGRADE.as_str().eq_ignore_ascii_case(k))
+        {
+            builder = builder.header(header::UPGRADE, "tcp");
+        }
         let req = self
-            .build_request(
-                method,
-                endpoint,
-                body,
-                None::<iter::Empty<_>>,
-                Request::builder()
-                    .header(header::CONNECTION, "Upgrade")
-                    .header(header::UPGRADE, "tcp"),
-            )
+            .build_request(method, endpoint, body, Some(headers), builder)
             .expect("Failed to build request!");
 
         let response = self.send_request(req).await?;
@@ -259,16 +357,20 @@ impl Transport {
         }
     }
 
-    pub async fn stream_upgrade<B>(
+    pub async fn stream_upgrade<B, H>(
         &self,
         method: Method,
         endpoint: impl AsRef<str>,
         body: Option<(B, Mime)>,
+        headers: Option<H>,
     ) -> Result<impl AsyncRead + AsyncWrite>
     where
         B: Into<Body>,
+        H: IntoIterator<Item = (&'static str, String)>,
     {
-        let tokio_multiplexer = self.stream_upgrade_tokio(method, endpoint, body).await?;
+        let tokio_multiplexer = self
+            .stream_upgrade_tokio(method, endpoint, body, headers)
+            .await?;
 
         Ok(Compat { tokio_multiplexer })
     }
@@ -282,6 +384,150 @@ impl Transport {
     }
 }
 
+type BoxError = Box<dyn StdError + Send + Sync>;
+
+type ConnectFuture =
+    Pin<Box<dyn Future<Output = std::result::Result<BoxedIo, BoxError>> + Send + 'static>>;
+
+/// A type-erased connector backing [`Transport::Custom`]
+///
+/// Any `Service<Uri>` that yields a tokio IO stream implementing hyper's `Connection` can be
+/// boxed, which includes every hyper connector as well as closures wrapped with
+/// `hyper::service::service_fn`.
+pub struct BoxedConnector(Box<dyn DynConnect>);
+
+impl BoxedConnector {
+    pub fn new<C>(connector: C) -> Self
+    where
+        C: Service<Uri> + Clone + Send + Sync + 'static,
+        C::Response:
+            tokio::io::AsyncRead + tokio::io::AsyncWrite + Connection + Unpin + Send + 'static,
+        C::Future: Send + 'static,
+        C::Error: Into<BoxError>,
+    {
+        BoxedConnector(Box::new(connector))
+    }
+}
+
+impl Clone for BoxedConnector {
+    fn clone(&self) -> Self {
+        BoxedConnector(self.0.clone_box())
+    }
+}
+
+impl Service<Uri> for BoxedConnector {
+    type Response = BoxedIo;
+    type Error = BoxError;
+    type Future = ConnectFuture;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::result::Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(
+        &mut self,
+        uri: Uri,
+    ) -> Self::Future {
+        self.0.call(uri)
+    }
+}
+
+trait DynConnect: Send + Sync {
+    fn poll_ready(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::result::Result<(), BoxError>>;
+
+    fn call(
+        &mut self,
+        uri: Uri,
+    ) -> ConnectFuture;
+
+    fn clone_box(&self) -> Box<dyn DynConnect>;
+}
+
+impl<C> DynConnect for C
+where
+    C: Service<Uri> + Clone + Send + Sync + 'static,
+    C::Response: tokio::io::AsyncRead + tokio::io::AsyncWrite + Connection + Unpin + Send + 'static,
+    C::Future: Send + 'static,
+    C::Error: Into<BoxError>,
+{
+    fn poll_ready(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::result::Result<(), BoxError>> {
+        Service::poll_ready(self, cx).map_err(Into::into)
+    }
+
+    fn call(
+        &mut self,
+        uri: Uri,
+    ) -> ConnectFuture {
+        let connecting = Service::call(self, uri);
+        Box::pin(async move {
+            let io = connecting.await.map_err(Into::into)?;
+            Ok(BoxedIo(Box::pin(io)))
+        })
+    }
+
+    fn clone_box(&self) -> Box<dyn DynConnect> {
+        Box::new(self.clone())
+    }
+}
+
+trait Io: tokio::io::AsyncRead + tokio::io::AsyncWrite + Connection + Send {}
+
+impl<T> Io for T where T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Connection + Send {}
+
+/// A connection established by a [`BoxedConnector`]
+pub struct BoxedIo(Pin<Box<dyn Io>>);
+
+impl Connection for BoxedIo {
+    /// Reports what the wrapped connection does, such as whether it goes through a proxy or
+    /// negotiated HTTP/2
+    fn connected(&self) -> Connected {
+        self.0.connected()
+    }
+}
+
+impl tokio::io::AsyncRead for BoxedIo {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.0.as_mut().poll_read(cx, buf)
+    }
+}
+
+impl tokio::io::AsyncWrite for BoxedIo {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.0.as_mut().poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.0.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.0.as_mut().poll_shutdown(cx)
+    }
+}
+
 #[pin_project]
 struct Compat<S> {
     #[pin]
@@ -345,3 +591,85 @@ fn stream_body(body: Body) -> impl Stream<Item = Result<Bytes>> {
 
     futures_util::stream::unfold(body, unfold)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::future;
+    use tokio::io::DuplexStream;
+
+    /// A connection through a proxy
+    struct Proxied(DuplexStream);
+
+    impl Connection for Proxied {
+        fn connected(&self) -> Connected {
+            Connected::new().proxy(true)
+        }
+    }
+
+    impl tokio::io::AsyncRead for Proxied {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_read(cx, buf)
+        }
+    }
+
+    impl tokio::io::AsyncWrite for Proxied {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_write(cx, buf)
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_shutdown(cx)
+        }
+    }
+
+    #[derive(Clone)]
+    struct ProxyConnector;
+
+    impl Service<Uri> for ProxyConnector {
+        type Response = Proxied;
+        type Error = io::Error;
+        type Future = future::Ready<io::Result<Proxied>>;
+
+        fn poll_ready(
+            &mut self,
+            _: &mut Context<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(
+            &mut self,
+            _: Uri,
+        ) -> Self::Future {
+            future::ready(Ok(Proxied(tokio::io::duplex(64).0)))
+        }
+    }
+
+    #[tokio::test]
+    async fn boxed_connections_keep_connection_info() {
+        let mut connector = BoxedConnector::new(ProxyConnector);
+        let io = Service::call(&mut connector, "http://docker".parse().unwrap())
+            .await
+            .unwrap();
+        assert!(io.connected().is_proxied());
+    }
+}
@@ -1,33 +1,49 @@
 //! Types for working with docker TTY streams
 
-use crate::{Error, Result};
+use crate::{errors::TtyError, Error, Result};
+use bytes::{Buf, Bytes, BytesMut};
 use futures_util::{
+    future::Either,
     io::{AsyncRead, AsyncReadExt, AsyncWrite},
-    stream::{Stream, TryStreamExt},
+    stream::{self, Stream, StreamExt, TryStreamExt},
 };
 use pin_project::pin_project;
 use std::{convert::TryInto, io};
 
 /// An enum representing a chunk of TTY text streamed from a Docker container.
 ///
-/// For convenience, this type can deref to the contained `Vec<u8>`.
+/// For convenience, this type can deref to the contained bytes. The [`Bytes`] themselves are
+/// cheap to clone and can be forwarded without copying.
 #[derive(Debug, Clone)]
 pub enum TtyChunk {
-    StdIn(Vec<u8>),
-    StdOut(Vec<u8>),
-    StdErr(Vec<u8>),
+    StdIn(Bytes),
+    StdOut(Bytes),
+    StdErr(Bytes),
 }
 
-impl From<TtyChunk> for Vec<u8> {
-    fn from(tty_chunk: TtyChunk) -> Self {
-        match tty_chunk {
+impl TtyChunk {
+    /// Returns the contained bytes
+    pub fn into_bytes(self) -> Bytes {
+        match self {
             TtyChunk::StdIn(bytes) | TtyChunk::StdOut(bytes) | TtyChunk::StdErr(bytes) => bytes,
         }
     }
 }
 
-impl AsRef<Vec<u8>> for TtyChunk {
-    fn as_ref(&self) -> &Vec<u8> {
+impl From<TtyChunk> for Bytes {
+    fn from(tty_chunk: TtyChunk) -> Self {
+        tty_chunk.into_bytes()
+    }
+}
+
+impl From<TtyChunk> for Vec<u8> {
+    fn from(tty_chunk: TtyChunk) -> Self {
+        tty_chunk.into_bytes().to_vec()
+    }
+}
+
+impl AsRef<[u8]> for TtyChunk {
+    fn as_ref(&self) -> &[u8] {
         match self {
             TtyChunk::StdIn(bytes) | TtyChunk::StdOut(bytes) | TtyChunk::StdErr(bytes) => bytes,
         }
@@ -35,60 +51,206 @@ impl AsRef<Vec<u8>> for TtyChunk {
 }
 
 impl std::ops::Deref for TtyChunk {
-    type Target = Vec<u8>;
+    type Target = [u8];
     fn deref(&self) -> &Self::Target {
         self.as_ref()
     }
 }
 
-impl std::ops::DerefMut for TtyChunk {
-    fn deref_mut(&mut self) -> &mut Vec<u8> {
-        match self {
-            TtyChunk::StdIn(bytes) | TtyChunk::StdOut(bytes) | TtyChunk::StdErr(bytes) => bytes,
+/// Size of the header in front of every frame
+const HEADER_LEN: usize = 8;
+
+/// Bytes read from a reader at a time
+const READ_SIZE: usize = 8 * 1024;
+
+/// Reads from `reader` into the spare capacity of `buf`, returning the number of bytes read
+async fn read_buf<R>(
+    reader: &mut R,
+    buf: &mut BytesMut,
+) -> io::Result<usize>
+where
+    R: AsyncRead + Unpin,
+{
+    let len = buf.len();
+    buf.resize(len + READ_SIZE, 0);
+    loop {
+        match reader.read(&mut buf[len..]).await {
+            Ok(n) => {
+                buf.truncate(len + n);
+                return Ok(n);
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                buf.truncate(len);
+                return Err(e);
+            }
         }
     }
 }
 
-async fn decode_chunk<S>(mut stream: S) -> Option<(Result<TtyChunk>, S)>
-where
-    S: AsyncRead + Unpin,
-{
-    let mut header_bytes = [0u8; 8];
+/// Buffers multiplexed output and splits the frames off it without copying their data
+#[derive(Default)]
+struct Frames {
+    buf: BytesMut,
+}
 
-    match stream.read_exact(&mut header_bytes).await {
-        Err(e) if e.kind() == futures_util::io::ErrorKind::UnexpectedEof => return None,
-        Err(e) => return Some((Err(Error::IO(e)), stream)),
-        _ => (),
-    }
+impl Frames {
+    /// Takes the next complete frame off the buffer, yielding a chunk or a daemon error. Fails
+    /// once the framing is lost
+    fn next(&mut self) -> Result<Option<Result<TtyChunk>>> {
+        if self.buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let stream_type = self.buf[0];
+        if stream_type > 3 {
+            return Err(TtyError::UnknownStream(stream_type).into());
+        }
+        let data_length = self.data_length();
+        if self.buf.len() < HEADER_LEN + data_length {
+            return Ok(None);
+        }
 
-    let size_bytes = &header_bytes[4..];
-    let data_length = u32::from_be_bytes(size_bytes.try_into().unwrap());
+        self.buf.advance(HEADER_LEN);
+        let data = self.buf.split_to(data_length).freeze();
+        Ok(Some(match stream_type {
+            0 => Ok(TtyChunk::StdIn(data)),
+            1 => Ok(TtyChunk::StdOut(data)),
+            2 => Ok(TtyChunk::StdErr(data)),
+            _ => Err(TtyError::Daemon(String::from_utf8_lossy(&data).into_owned()).into()),
+        }))
+    }
 
-    let mut data = vec![0u8; data_length as usize];
+    fn data_length(&self) -> usize {
+        u32::from_be_bytes(self.buf[4..HEADER_LEN].try_into().unwrap()) as usize
+    }
 
-    if stream.read_exact(&mut data).await.is_err() {
-        return None;
+    /// The error for a frame cut off by the end of the output
+    fn truncated(&self) -> Option<Error> {
+        let e = match self.buf.len() {
+            0 => return None,
+            received if received < HEADER_LEN => TtyError::Truncated {
+                expected: HEADER_LEN,
+                received,
+            },
+            received => TtyError::Truncated {
+                expected: self.data_length(),
+                received: received - HEADER_LEN,
+            },
+        };
+        Some(e.into())
     }
+}
+
+/// Decodes the multiplexed output of a container read from `reader`
+///
+/// Containers without a TTY interleave stdout and stderr in frames with an 8 byte header.
+/// Messages on the daemon's systemerr stream are yielded as [`TtyError::Daemon`]. A truncated
+/// frame, an unknown stream number or a read error yields an error and ends the stream.
+pub fn decode_reader<R>(reader: R) -> impl Stream<Item = Result<TtyChunk>>
+where
+    R: AsyncRead + Unpin,
+{
+    stream::unfold(Some((reader, Frames::default())), |state| async move {
+        let (mut reader, mut frames) = state?;
+        loop {
+            match frames.next() {
+                Ok(Some(chunk)) => return Some((chunk, Some((reader, frames)))),
+                Ok(None) => (),
+                Err(e) => return Some((Err(e), None)),
+            }
+            match read_buf(&mut reader, &mut frames.buf).await {
+                Ok(0) => return frames.truncated().map(|e| (Err(e), None)),
+                Ok(_) => (),
+                Err(e) => return Some((Err(Error::IO(e)), None)),
+            }
+        }
+    })
+}
 
-    let chunk = match header_bytes[0] {
-        0 => TtyChunk::StdIn(data),
-        1 => TtyChunk::StdOut(data),
-        2 => TtyChunk::StdErr(data),
-        n => panic!("invalid stream number from docker daemon: '{}'", n),
-    };
+/// Decodes the multiplexed output of a container from a stream of bytes, such as the body of a
+/// logs response, regardless of how frames are split across chunks
+///
+/// See [`decode_reader`] for how malformed output is reported. Errors of `chunks` are yielded
+/// as they are and end the stream.
+pub fn decode<S>(chunks: S) -> impl Stream<Item = Result<TtyChunk>>
+where
+    S: Stream<Item = Result<Bytes>> + Unpin,
+{
+    stream::unfold(Some((chunks, Frames::default())), |state| async move {
+        let (mut chunks, mut frames) = state?;
+        loop {
+            match frames.next() {
+                Ok(Some(chunk)) => return Some((chunk, Some((chunks, frames)))),
+                Ok(None) => (),
+                Err(e) => return Some((Err(e), None)),
+            }
+            match chunks.next().await {
+                Some(Ok(bytes)) => frames.buf.extend_from_slice(&bytes),
+                Some(Err(e)) => return Some((Err(e), None)),
+                None => return frames.truncated().map(|e| (Err(e), None)),
+            }
+        }
+    })
+}
 
-    Some((Ok(chunk), stream))
+/// How a container or exec instance writes its output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamMode {
+    /// Stdout and stderr interleaved in frames, used when no TTY is allocated
+    Multiplexed,
+    /// The raw bytes written to the TTY, with stdout and stderr already merged
+    Raw,
+}
+
+impl StreamMode {
+    /// The mode output is written in depending on whether a TTY is allocated
+    pub fn from_tty(tty: bool) -> Self {
+        if tty {
+            StreamMode::Raw
+        } else {
+            StreamMode::Multiplexed
+        }
+    }
 }
 
-pub(crate) fn decode<S>(hyper_chunk_stream: S) -> impl Stream<Item = Result<TtyChunk>>
+/// Wraps the raw output of a TTY read from `reader` in [`TtyChunk::StdOut`]s
+pub fn decode_raw_reader<R>(reader: R) -> impl Stream<Item = Result<TtyChunk>>
 where
-    S: Stream<Item = Result<hyper::body::Bytes>> + Unpin,
+    R: AsyncRead + Unpin,
 {
-    let stream = hyper_chunk_stream
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
-        .into_async_read();
+    stream::unfold(Some((reader, BytesMut::new())), |state| async move {
+        let (mut reader, mut buf) = state?;
+        match read_buf(&mut reader, &mut buf).await {
+            Ok(0) => None,
+            Ok(_) => {
+                let chunk = TtyChunk::StdOut(buf.split().freeze());
+                Some((Ok(chunk), Some((reader, buf))))
+            }
+            Err(e) => Some((Err(Error::IO(e)), None)),
+        }
+    })
+}
+
+/// Wraps the raw output of a TTY, such as the body of a logs response, in [`TtyChunk::StdOut`]s
+pub fn decode_raw<S>(chunks: S) -> impl Stream<Item = Result<TtyChunk>>
+where
+    S: Stream<Item = Result<Bytes>>,
+{
+    chunks.map_ok(TtyChunk::StdOut)
+}
 
-    futures_util::stream::unfold(stream, decode_chunk)
+/// Decodes output written in `mode`
+pub(crate) fn decode_as<S>(
+    mode: StreamMode,
+    chunks: S,
+) -> impl Stream<Item = Result<TtyChunk>>
+where
+    S: Stream<Item = Result<Bytes>> + Unpin,
+{
+    match mode {
+        StreamMode::Multiplexed => Either::Left(decode(chunks)),
+        StreamMode::Raw => Either::Right(decode_raw(chunks)),
+    }
 }
 
 type TtyReader<'a> = Pin<Box<dyn Stream<Item = Result<TtyChunk>> + Send + 'a>>;
@@ -106,16 +268,21 @@ pub struct Multiplexer<'a> {
 }
 
 impl<'a> Multiplexer<'a> {
-    pub(crate) fn new<T>(tcp_connection: T) -> Self
+    pub(crate) fn new<T>(
+        tcp_connection: T,
+        mode: StreamMode,
+    ) -> Self
     where
         T: AsyncRead + AsyncWrite + Send + 'a,
     {
         let (reader, writer) = tcp_connection.split();
+        let reader: TtyReader<'a> = match mode {
+            StreamMode::Multiplexed => Box::pin(decode_reader(reader)),
+            StreamMode::Raw => Box::pin(decode_raw_reader(reader)),
+        };
 
         Self {
-            reader: Box::pin(futures_util::stream::unfold(reader, |reader| {
-                decode_chunk(reader)
-            })),
+            reader,
             writer: Box::pin(writer),
         }
     }
@@ -169,3 +336,100 @@ impl<'a> Multiplexer<'a> {
         (self.reader, self.writer)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream::{self, StreamExt};
+
+    fn frame(
+        stream_type: u8,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let mut frame = vec![stream_type, 0, 0, 0];
+        frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    async fn decode_chunks(chunks: Vec<&[u8]>) -> Vec<Result<TtyChunk>> {
+        let chunks = chunks
+            .into_iter()
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect::<Vec<_>>();
+        decode(stream::iter(chunks)).collect().await
+    }
+
+    #[tokio::test]
+    async fn decode_frames_split_anywhere() {
+        let output = [frame(1, b"out\n"), frame(2, b""), frame(2, b"err\n")].concat();
+
+        for first in 0..=output.len() {
+            for second in first..=output.len() {
+                let chunks = decode_chunks(vec![
+                    &output[..first],
+                    &output[first..second],
+                    &output[second..],
+                ])
+                .await;
+                assert!(
+                    matches!(&chunks[..], [Ok(TtyChunk::StdOut(out)), Ok(TtyChunk::StdErr(empty)), Ok(TtyChunk::StdErr(err))]
+                        if &out[..] == b"out\n" && empty.is_empty() && &err[..] == b"err\n"),
+                    "split at {} and {}",
+                    first,
+                    second
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn read_raw_output_in_chunks() {
+        let output = vec![b'x'; READ_SIZE + 10];
+        let chunks = decode_raw_reader(&output[..])
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        let lengths = chunks.iter().map(|chunk| chunk.len()).collect::<Vec<_>>();
+        assert_eq!(lengths, vec![READ_SIZE, 10]);
+    }
+
+    #[tokio::test]
+    async fn report_malformed_frames() {
+        let output = [frame(1, b"ok"), frame(7, b"bad"), frame(1, b"lost")].concat();
+        let chunks = decode_reader(&output[..]).collect::<Vec<_>>().await;
+        assert!(matches!(
+            &chunks[..],
+            [
+                Ok(TtyChunk::StdOut(_)),
+                Err(Error::Tty(TtyError::UnknownStream(7)))
+            ]
+        ));
+
+        let output = frame(1, b"truncated");
+        let chunks = decode_chunks(vec![&output[..12]]).await;
+        assert!(matches!(
+            &chunks[..],
+            [Err(Error::Tty(TtyError::Truncated {
+                expected: 9,
+                received: 4
+            }))]
+        ));
+        let chunks = decode_chunks(vec![&output[..3]]).await;
+        assert!(matches!(
+            &chunks[..],
+            [Err(Error::Tty(TtyError::Truncated {
+                expected: 8,
+                received: 3
+            }))]
+        ));
+
+        let output = [frame(3, b"exec failed"), frame(1, b"after")].concat();
+        let chunks = decode_reader(&output[..]).collect::<Vec<_>>().await;
+        assert!(matches!(
+            &chunks[..],
+            [Err(Error::Tty(TtyError::Daemon(message))), Ok(TtyChunk::StdOut(_))]
+                if message == "exec failed"
+        ));
+    }
+}
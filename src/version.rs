@@ -0,0 +1,128 @@
+//! Engine API versions
+
+use std::{error::Error as StdError, fmt, str::FromStr};
+
+/// A version of the docker Engine API, e.g. `1.41`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ApiVersion {
+    major: u32,
+    minor: u32,
+}
+
+impl ApiVersion {
+    /// The most recent API version this crate knows about. Negotiation never settles on a
+    /// version newer than this.
+    pub const LATEST: ApiVersion = ApiVersion::new(1, 41);
+
+    pub const fn new(
+        major: u32,
+        minor: u32,
+    ) -> ApiVersion {
+        ApiVersion { major, minor }
+    }
+
+    pub fn major(&self) -> u32 {
+        self.major
+    }
+
+    pub fn minor(&self) -> u32 {
+        self.minor
+    }
+
+    /// Returns the minimum API version the daemon must speak to serve `endpoint`, if the
+    /// endpoint is newer than the versions every supported daemon understands
+    pub(crate) fn required_for(endpoint: &str) -> Option<ApiVersion> {
+        let path = endpoint.split('?').next().unwrap_or_default();
+        let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        match segments.as_slice() {
+            ["services", ..] | ["tasks", ..] | ["nodes", ..] | ["swarm", ..] => {
+                Some(ApiVersion::new(1, 24))
+            }
+            ["networks", ..] | ["volumes", ..] => Some(ApiVersion::new(1, 21)),
+            ["containers", _, "archive"] => Some(ApiVersion::new(1, 20)),
+            ["session"] => Some(ApiVersion::new(1, 39)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ApiVersion {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl FromStr for ApiVersion {
+    type Err = InvalidApiVersion;
+
+    /// Parses versions of the form `1.41`, with an optional leading `v`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidApiVersion(s.to_owned());
+        let version = s.trim().trim_start_matches('v');
+        let mut parts = version.splitn(2, '.');
+        let major = parts
+            .next()
+            .and_then(|m| m.parse().ok())
+            .ok_or_else(invalid)?;
+        let minor = parts
+            .next()
+            .and_then(|m| m.parse().ok())
+            .ok_or_else(invalid)?;
+        Ok(ApiVersion::new(major, minor))
+    }
+}
+
+/// The error returned when parsing a malformed [`ApiVersion`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidApiVersion(String);
+
+impl fmt::Display for InvalidApiVersion {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        write!(f, "invalid API version: {:?}", self.0)
+    }
+}
+
+impl StdError for InvalidApiVersion {}
+
+#[cfg(test)]
+mod tests {
+    use super::ApiVersion;
+
+    #[test]
+    fn parse_api_version() {
+        assert_eq!("1.41".parse(), Ok(ApiVersion::new(1, 41)));
+        assert_eq!("v1.24".parse(), Ok(ApiVersion::new(1, 24)));
+        assert!("1".parse::<ApiVersion>().is_err());
+        assert!("one.two".parse::<ApiVersion>().is_err());
+    }
+
+    #[test]
+    fn order_api_versions() {
+        assert!(ApiVersion::new(1, 9) < ApiVersion::new(1, 24));
+        assert!(ApiVersion::new(2, 0) > ApiVersion::LATEST);
+        assert_eq!(ApiVersion::new(1, 41).to_string(), "1.41");
+    }
+
+    #[test]
+    fn required_versions() {
+        assert_eq!(
+            ApiVersion::required_for("/services?filters=x"),
+            Some(ApiVersion::new(1, 24))
+        );
+        assert_eq!(
+            ApiVersion::required_for("/containers/abc/archive?path=/"),
+            Some(ApiVersion::new(1, 20))
+        );
+        assert_eq!(
+            ApiVersion::required_for("/session"),
+            Some(ApiVersion::new(1, 39))
+        );
+        assert_eq!(ApiVersion::required_for("/containers/json"), None);
+    }
+}
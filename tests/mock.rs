@@ -0,0 +1,951 @@
+use futures::{AsyncWriteExt, StreamExt, TryStreamExt};
+use hyper::{Method, StatusCode};
+use shiplift::{
+    errors::ErrorKind,
+    mock::MockDaemon,
+    rep::{BuildEvent, PullEvent, PullProgress},
+    tty::{StreamMode, TtyChunk},
+    version::ApiVersion,
+    BuildContext, BuildOptions, Bytes, ContainerListOptions, ContainerOptions, Docker,
+    EventsOptions, Exec, ExecContainerOptions, LogsOptions, NetworkCreateOptions, PullOptions,
+    PushOptions, RmContainerOptions, VolumeCreateOptions,
+};
+use std::path::Path;
+
+async fn daemon() -> MockDaemon {
+    let daemon = MockDaemon::tcp().await.unwrap();
+    daemon.add_image("busybox:latest");
+    daemon
+}
+
+#[tokio::test]
+async fn serves_system_endpoints() {
+    let daemon = daemon().await;
+    let docker = daemon.docker();
+
+    let ping = docker.ping().await.unwrap();
+    assert_eq!(ping.api_version, Some(ApiVersion::LATEST));
+    assert_eq!(ping.os_type.as_deref(), Some("linux"));
+    assert!(!ping.experimental);
+    assert_eq!(ping.swarm.as_deref(), Some("inactive"));
+    assert_eq!(docker.ping_head().await.unwrap(), ping);
+    assert_eq!(docker.version().await.unwrap().api_version, "1.41");
+    assert_eq!(docker.info().await.unwrap().images, 1);
+}
+
+#[cfg(feature = "unix-socket")]
+#[tokio::test]
+async fn serves_over_unix_socket() {
+    let daemon = MockDaemon::unix().await.unwrap();
+    assert!(daemon.host().starts_with("unix://"));
+
+    daemon.docker().ping().await.unwrap();
+}
+
+#[cfg(feature = "unix-socket")]
+#[tokio::test]
+async fn reuses_unix_connections() {
+    let daemon = MockDaemon::unix().await.unwrap();
+    daemon.add_image("busybox:latest");
+    let docker = daemon.docker();
+    let info = docker
+        .containers()
+        .create(
+            &ContainerOptions::builder("busybox")
+                .attach_stdin(true)
+                .build(),
+        )
+        .await
+        .unwrap();
+    let container = docker.containers().get(&info.id);
+    container.start().await.unwrap();
+
+    // an attached container and an open event stream each hold on to their connection
+    let (mut reader, mut writer) = container.attach().await.unwrap().split();
+    let mut events = docker.events(&EventsOptions::builder().since(&0).build());
+    events.next().await.unwrap().unwrap();
+    let connections = daemon.connections();
+
+    for _ in 0..10 {
+        container.inspect().await.unwrap();
+    }
+    assert!(daemon.connections() <= connections + 1);
+
+    writer.write_all(b"still attached\n").await.unwrap();
+    match reader.next().await {
+        Some(Ok(TtyChunk::StdOut(bytes))) => assert_eq!(&bytes[..], b"still attached\n"),
+        other => panic!(
+            "unexpected chunk {:?}",
+            other.map(|c| c.map(|c| c.to_vec()))
+        ),
+    }
+    container.stop(None).await.unwrap();
+    assert!(events.next().await.is_some());
+
+    // abandoning a stream must not leave a half read connection in the pool
+    drop(events);
+    container.inspect().await.unwrap();
+}
+
+#[tokio::test]
+async fn container_lifecycle() {
+    let daemon = daemon().await;
+    let docker = daemon.docker();
+    let containers = docker.containers();
+
+    let info = containers
+        .create(&ContainerOptions::builder("busybox").name("web").build())
+        .await
+        .unwrap();
+    let container = containers.get(&info.id);
+    container.start().await.unwrap();
+
+    let details = container.inspect().await.unwrap();
+    assert_eq!(details.name, "/web");
+    assert!(details.state.running);
+
+    let listed = containers.list(&Default::default()).await.unwrap();
+    assert_eq!(listed.len(), 1);
+
+    let err = containers
+        .create(&ContainerOptions::builder("busybox").name("web").build())
+        .await
+        .unwrap_err();
+    assert!(matches!(err, shiplift::Error::Fault { code, .. } if code == 409));
+
+    let err = container.delete().await.unwrap_err();
+    assert!(matches!(err, shiplift::Error::Fault { code, .. } if code == 409));
+
+    container.stop(None).await.unwrap();
+    assert!(containers
+        .list(&ContainerListOptions::default())
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        containers
+            .list(&ContainerListOptions::builder().all().build())
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+
+    container
+        .remove(RmContainerOptions::builder().build())
+        .await
+        .unwrap();
+    let err = container.inspect().await.unwrap_err();
+    assert!(matches!(err, shiplift::Error::Fault { code, .. } if code == 404));
+}
+
+#[tokio::test]
+async fn create_requires_image() {
+    let daemon = daemon().await;
+    let err = daemon
+        .docker()
+        .containers()
+        .create(&ContainerOptions::builder("alpine").build())
+        .await
+        .unwrap_err();
+    assert!(matches!(err, shiplift::Error::Fault { code, .. } if code == 404));
+}
+
+#[tokio::test]
+async fn classifies_errors() {
+    let daemon = daemon().await;
+    let docker = daemon.docker();
+    let containers = docker.containers();
+
+    let err = containers.get("nope").inspect().await.unwrap_err();
+    assert!(err.is_not_found());
+    assert_eq!(err.request(), Some((&Method::GET, "/containers/nope/json")));
+    let message = err.to_string();
+    assert!(
+        message.contains("GET /containers/nope/json") && message.contains("No such container"),
+        "{}",
+        message
+    );
+
+    let info = containers
+        .create(&ContainerOptions::builder("busybox").name("web").build())
+        .await
+        .unwrap();
+    let err = containers
+        .create(&ContainerOptions::builder("busybox").name("web").build())
+        .await
+        .unwrap_err();
+    assert!(err.is_conflict() && !err.is_not_found());
+    let container = containers.get(&info.id);
+    container.start().await.unwrap();
+    assert!(container.start().await.unwrap_err().is_not_modified());
+
+    let unauthorized = shiplift::Error::Fault {
+        code: StatusCode::UNAUTHORIZED,
+        message: "authentication required".to_owned(),
+        method: Method::POST,
+        endpoint: "/images/create".to_owned(),
+    };
+    assert_eq!(unauthorized.kind(), ErrorKind::Unauthorized);
+
+    let addr = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    };
+    let err = Docker::host(format!("http://{}", addr).parse().unwrap())
+        .info()
+        .await
+        .unwrap_err();
+    assert!(err.is_daemon_unavailable(), "{:?}", err);
+    assert_eq!(err.request(), Some((&Method::GET, "/info")));
+    assert!(std::error::Error::source(&err).is_some());
+}
+
+#[tokio::test]
+async fn pull_streams_progress() {
+    let daemon = MockDaemon::tcp().await.unwrap();
+    let docker = daemon.docker();
+
+    let events: Vec<_> = docker
+        .images()
+        .pull(&PullOptions::builder().image("alpine").tag("3.13").build())
+        .try_collect()
+        .await
+        .unwrap();
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, PullEvent::Layer { status, progress, .. }
+            if status == "Downloading" && progress.total == Some(1024))));
+    assert!(matches!(
+        events.last().unwrap(),
+        PullEvent::Status { id: None, status }
+            if status == "Status: Downloaded newer image for alpine:3.13"
+    ));
+
+    let progress = PullProgress::track(
+        docker
+            .images()
+            .pull(&PullOptions::builder().image("alpine").tag("3.14").build()),
+    )
+    .try_collect::<Vec<_>>()
+    .await
+    .unwrap();
+    let done = progress.last().unwrap();
+    assert_eq!(done.layers.len(), 2);
+    assert!(done.is_complete());
+    assert_eq!((done.current(), done.total()), (2048, 2048));
+    assert!(progress
+        .iter()
+        .any(|p| p.current() == 512 && !p.is_complete()));
+
+    let details = docker.images().get("alpine:3.13").inspect().await.unwrap();
+    assert_eq!(details.repo_tags, Some(vec!["alpine:3.13".to_owned()]));
+}
+
+#[tokio::test]
+async fn uses_stored_registry_credentials() {
+    let dir = std::env::temp_dir().join(format!("shiplift-mock-config-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("config.json"),
+        r#"{"auths":{"https://index.docker.io/v1/":{"auth":"dXNlcjpzM2NyZXQ="}}}"#,
+    )
+    .unwrap();
+
+    let daemon = daemon().await;
+    daemon.require_auth("user", "s3cret");
+    let docker = daemon.docker();
+
+    let err = docker
+        .images()
+        .pull(
+            &PullOptions::builder()
+                .image("alpine")
+                .skip_stored_auth()
+                .build(),
+        )
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap_err();
+    assert!(matches!(err, shiplift::Error::Fault { code, .. } if code == 500));
+    docker
+        .images()
+        .pull(
+            &PullOptions::builder()
+                .image("alpine")
+                .auth_from_config_dir(&dir)
+                .build(),
+        )
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+
+    let image = docker.images().get("busybox");
+    let err = image
+        .push(&PushOptions::builder().skip_stored_auth().build())
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap_err();
+    assert!(matches!(err, shiplift::Error::Stream { code: None, .. }));
+    let events: Vec<_> = image
+        .push(&PushOptions::builder().auth_from_config_dir(&dir).build())
+        .try_collect()
+        .await
+        .unwrap();
+    assert!(matches!(
+        events.last().unwrap(),
+        PullEvent::Pushed { tag, digest, .. } if tag == "latest" && digest.starts_with("sha256:")
+    ));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn logs_are_multiplexed() {
+    let daemon = daemon().await;
+    let docker = daemon.docker();
+    let info = docker
+        .containers()
+        .create(&ContainerOptions::builder("busybox").build())
+        .await
+        .unwrap();
+    daemon.write_log(&info.id, TtyChunk::StdOut(Bytes::from_static(b"out\n")));
+    daemon.write_log(&info.id, TtyChunk::StdErr(Bytes::from_static(b"err\n")));
+
+    let chunks: Vec<_> = docker
+        .containers()
+        .get(&info.id)
+        .logs(&LogsOptions::builder().stdout(true).stderr(true).build())
+        .try_collect()
+        .await
+        .unwrap();
+    assert!(
+        matches!(&chunks[..], [TtyChunk::StdOut(out), TtyChunk::StdErr(err)]
+        if &out[..] == b"out\n" && &err[..] == b"err\n")
+    );
+}
+
+#[tokio::test]
+async fn attach_echoes_stdin() {
+    let daemon = daemon().await;
+    let docker = daemon.docker();
+    let info = docker
+        .containers()
+        .create(
+            &ContainerOptions::builder("busybox")
+                .attach_stdin(true)
+                .build(),
+        )
+        .await
+        .unwrap();
+    let container = docker.containers().get(&info.id);
+    container.start().await.unwrap();
+
+    let (mut reader, mut writer) = container.attach().await.unwrap().split();
+    writer.write_all(b"hello\n").await.unwrap();
+
+    match reader.next().await {
+        Some(Ok(TtyChunk::StdOut(bytes))) => assert_eq!(&bytes[..], b"hello\n"),
+        other => panic!(
+            "unexpected chunk {:?}",
+            other.map(|c| c.map(|c| c.to_vec()))
+        ),
+    }
+}
+
+#[tokio::test]
+async fn exec_prints_command() {
+    let daemon = daemon().await;
+    let docker = daemon.docker();
+    let info = docker
+        .containers()
+        .create(&ContainerOptions::builder("busybox").build())
+        .await
+        .unwrap();
+    let container = docker.containers().get(&info.id);
+    container.start().await.unwrap();
+
+    let chunks: Vec<_> = container
+        .exec(
+            &ExecContainerOptions::builder()
+                .cmd(vec!["echo", "hi"])
+                .attach_stdout(true)
+                .build(),
+        )
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(chunks.len(), 1);
+    assert_eq!(&chunks[0][..], b"echo hi\n");
+}
+
+#[tokio::test]
+async fn tty_output_is_raw() {
+    let daemon = daemon().await;
+    let docker = daemon.docker();
+    let info = docker
+        .containers()
+        .create(
+            &ContainerOptions::builder("busybox")
+                .tty(true)
+                .attach_stdin(true)
+                .build(),
+        )
+        .await
+        .unwrap();
+    let container = docker.containers().get(&info.id);
+    daemon.write_log(&info.id, TtyChunk::StdOut(Bytes::from_static(b"out\n")));
+    daemon.write_log(&info.id, TtyChunk::StdErr(Bytes::from_static(b"err\n")));
+
+    let chunks: Vec<_> = container
+        .logs(&LogsOptions::builder().stdout(true).stderr(true).build())
+        .try_collect()
+        .await
+        .unwrap();
+    assert!(chunks.iter().all(|c| matches!(c, TtyChunk::StdOut(_))));
+    assert_eq!(
+        chunks.into_iter().flat_map(Vec::from).collect::<Vec<_>>(),
+        b"out\nerr\n"
+    );
+
+    let chunks: Vec<_> = container
+        .logs(
+            &LogsOptions::builder()
+                .stdout(true)
+                .stream_mode(StreamMode::Raw)
+                .build(),
+        )
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(
+        chunks.into_iter().flat_map(Vec::from).collect::<Vec<_>>(),
+        b"out\n"
+    );
+
+    container.start().await.unwrap();
+    let (mut reader, mut writer) = container.attach().await.unwrap().split();
+    writer.write_all(b"hello\n").await.unwrap();
+    match reader.next().await {
+        Some(Ok(TtyChunk::StdOut(bytes))) => assert_eq!(&bytes[..], b"hello\n"),
+        other => panic!(
+            "unexpected chunk {:?}",
+            other.map(|c| c.map(|c| c.to_vec()))
+        ),
+    }
+
+    let opts = ExecContainerOptions::builder()
+        .cmd(vec!["echo", "hi"])
+        .attach_stdout(true)
+        .tty(true)
+        .build();
+    let chunks: Vec<_> = container.exec(&opts).try_collect().await.unwrap();
+    assert!(matches!(&chunks[..], [TtyChunk::StdOut(out)] if &out[..] == b"echo hi\n"));
+
+    // the daemon frames exec output unless the start request asks for a TTY, so a start
+    // without `"Tty": true` would leave the frame headers in the raw output
+    let exec = Exec::create(&docker, &info.id, &opts).await.unwrap();
+    let chunks: Vec<_> = exec.start().try_collect().await.unwrap();
+    assert!(matches!(&chunks[..], [TtyChunk::StdOut(out)] if &out[..] == b"echo hi\n"));
+
+    let exec = Exec::create(&docker, &info.id, &opts).await.unwrap();
+    let chunks: Vec<_> = exec
+        .start_with_mode(StreamMode::Multiplexed)
+        .try_collect()
+        .await
+        .unwrap();
+    assert!(matches!(&chunks[..], [TtyChunk::StdOut(out)] if &out[..] == b"echo hi\n"));
+}
+
+#[tokio::test]
+async fn copies_files_into_and_out_of_containers() {
+    let daemon = daemon().await;
+    let docker = daemon.docker();
+    let info = docker
+        .containers()
+        .create(&ContainerOptions::builder("busybox").build())
+        .await
+        .unwrap();
+    let container = docker.containers().get(&info.id);
+
+    container
+        .copy_file_into("/etc/motd", b"welcome")
+        .await
+        .unwrap();
+
+    let bytes: Vec<u8> = container
+        .copy_from(Path::new("/etc/motd"))
+        .try_fold(Vec::new(), |mut bytes, chunk| async move {
+            bytes.extend_from_slice(&chunk);
+            Ok(bytes)
+        })
+        .await
+        .unwrap();
+    let mut archive = tar::Archive::new(&bytes[..]);
+    let entry = archive.entries().unwrap().next().unwrap().unwrap();
+    assert_eq!(entry.path().unwrap(), Path::new("motd"));
+}
+
+#[tokio::test]
+async fn builds_images() {
+    let daemon = daemon().await;
+    let docker = daemon.docker();
+
+    let dir = std::env::temp_dir().join(format!("shiplift-mock-build-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("Dockerfile"),
+        "FROM busybox\nRUN echo building\nCMD [\"true\"]\n",
+    )
+    .unwrap();
+
+    let events: Vec<_> = docker
+        .images()
+        .build(
+            &BuildOptions::builder(dir.to_string_lossy())
+                .tag("built:1")
+                .tag("built:latest")
+                .build(),
+        )
+        .try_collect()
+        .await
+        .unwrap();
+
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, BuildEvent::Output(output) if output == "building\n")));
+    assert!(events.iter().any(|e| matches!(e, BuildEvent::ImageId(_))));
+    docker.images().get("built:1").inspect().await.unwrap();
+    docker.images().get("built:latest").inspect().await.unwrap();
+
+    std::fs::write(dir.join("Dockerfile"), "FROM busybox\nRUN exit 3\n").unwrap();
+    let err = docker
+        .images()
+        .build(&BuildOptions::builder(dir.to_string_lossy()).build())
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap_err();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(matches!(err, shiplift::Error::Stream { code: Some(3), .. }));
+}
+
+#[tokio::test]
+async fn builds_images_from_memory() {
+    let daemon = daemon().await;
+    let docker = daemon.docker();
+
+    let context = BuildContext::new()
+        .file(
+            "Dockerfile",
+            &b"FROM busybox\nRUN echo on disk\n"[..],
+            0o644,
+        )
+        .file("app/config.json", &b"{}"[..], 0o644)
+        .dockerfile("FROM busybox\nRUN echo generated\n");
+    let events: Vec<_> = docker
+        .images()
+        .build_from_context(
+            context,
+            &BuildOptions::builder("").tag("generated:1").build(),
+        )
+        .try_collect()
+        .await
+        .unwrap();
+
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, BuildEvent::Output(output) if output == "generated\n")));
+    docker.images().get("generated:1").inspect().await.unwrap();
+
+    let err = docker
+        .images()
+        .build_from_context(
+            BuildContext::new().path("", "/nonexistent/shiplift/context"),
+            &BuildOptions::builder("").build(),
+        )
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap_err();
+    assert!(matches!(err, shiplift::Error::IO(_)));
+}
+
+#[tokio::test]
+async fn waits_for_builds() {
+    let daemon = daemon().await;
+    let docker = daemon.docker();
+
+    let dir = std::env::temp_dir().join(format!("shiplift-mock-wait-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("Dockerfile"), "FROM busybox\nRUN echo waiting\n").unwrap();
+
+    let result = docker
+        .images()
+        .build_and_wait(
+            &BuildOptions::builder(dir.to_string_lossy())
+                .tag("waited:1")
+                .tag("waited:latest")
+                .build(),
+        )
+        .await
+        .unwrap();
+    assert!(result.id.starts_with("sha256:"));
+    assert_eq!(result.tags, vec!["waited:1", "waited:latest"]);
+    assert!(result.log.contains("waiting\n"));
+    let image = docker.images().get("waited:1").inspect().await.unwrap();
+    assert_eq!(image.id, result.id);
+
+    std::fs::write(
+        dir.join("Dockerfile"),
+        "FROM busybox\nRUN echo almost\nRUN exit 3\n",
+    )
+    .unwrap();
+    let err = docker
+        .images()
+        .build_and_wait(&BuildOptions::builder(dir.to_string_lossy()).build())
+        .await
+        .unwrap_err();
+    std::fs::remove_dir_all(&dir).unwrap();
+    match err {
+        shiplift::Error::Build {
+            step, code, log, ..
+        } => {
+            assert_eq!(step.as_deref(), Some("Step 3/3 : RUN exit 3"));
+            assert_eq!(code, Some(3));
+            assert!(log.contains("almost\n"));
+        }
+        err => panic!("unexpected error {}", err),
+    }
+}
+
+#[cfg(all(feature = "buildkit", unix))]
+#[tokio::test]
+async fn builds_with_buildkit() {
+    use shiplift::{rep::BuildResult, SessionOptions};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let daemon = daemon().await;
+    let docker = daemon.docker();
+
+    let dir = std::env::temp_dir().join(format!("shiplift-mock-buildkit-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("context/src")).unwrap();
+    let context = dir.join("context");
+    std::fs::write(
+        context.join("Dockerfile"),
+        "FROM busybox\n\
+         COPY . /app\n\
+         RUN --mount=type=secret,id=token cat /run/secrets/token\n\
+         RUN --mount=type=ssh ssh-add -l\n",
+    )
+    .unwrap();
+    std::fs::write(context.join(".dockerignore"), "*.log\n").unwrap();
+    std::fs::write(context.join("src/main.rs"), "fn main() {}\n").unwrap();
+    std::fs::write(context.join("debug.log"), "noise\n").unwrap();
+
+    // an SSH agent without any keys
+    let agent = tokio::net::UnixListener::bind(dir.join("agent.sock")).unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut conn, _)) = agent.accept().await {
+            let mut request = [0; 5];
+            conn.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, [0, 0, 0, 1, 11]);
+            conn.write_all(&[0, 0, 0, 5, 12, 0, 0, 0, 0]).await.unwrap();
+        }
+    });
+
+    let session = SessionOptions::builder()
+        .secret("token", "s3cr3t\n")
+        .ssh("default", dir.join("agent.sock"))
+        .build();
+    let events: Vec<_> = docker
+        .images()
+        .build_with_buildkit(
+            &BuildOptions::builder(context.to_string_lossy())
+                .tag("kit:1")
+                .build(),
+            &session,
+        )
+        .try_collect()
+        .await
+        .unwrap();
+
+    let mut log = String::new();
+    let mut vertexes = Vec::new();
+    for event in &events {
+        if let BuildEvent::Trace(status) = event {
+            for entry in &status.logs {
+                log.push_str(&String::from_utf8_lossy(&entry.data));
+            }
+            vertexes.extend(status.vertexes.iter().filter(|v| v.completed.is_some()));
+        }
+    }
+    assert_eq!(
+        log,
+        "copied .dockerignore\ncopied Dockerfile\ncopied src/main.rs\n\
+         s3cr3t\n\
+         The agent has no identities.\n"
+    );
+    assert_eq!(vertexes.len(), 5);
+    assert_eq!(vertexes[1].name, "[2/4] COPY . /app");
+    assert_eq!(vertexes[4].name, "exporting to image");
+    assert!(vertexes.iter().all(|v| v.error.is_none()));
+    let id = events
+        .iter()
+        .find_map(|e| match e {
+            BuildEvent::ImageId(id) => Some(id.clone()),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(docker.images().get("kit:1").inspect().await.unwrap().id, id);
+    let result = BuildResult::collect(futures::stream::iter(events.into_iter().map(Ok)))
+        .await
+        .unwrap();
+    assert_eq!(result.tags, ["kit:1"]);
+
+    std::fs::write(
+        context.join("Dockerfile"),
+        "FROM busybox\nRUN --mount=type=secret,id=missing cat /run/secrets/missing\n",
+    )
+    .unwrap();
+    let err = BuildResult::collect(docker.images().build_with_buildkit(
+        &BuildOptions::builder(context.to_string_lossy()).build(),
+        &session,
+    ))
+    .await
+    .unwrap_err();
+    std::fs::remove_dir_all(&dir).unwrap();
+    match err {
+        shiplift::Error::Build { step, message, .. } => {
+            assert_eq!(
+                step.as_deref(),
+                Some("[2/2] RUN --mount=type=secret,id=missing cat /run/secrets/missing")
+            );
+            assert_eq!(message, "secret missing not found");
+        }
+        err => panic!("unexpected error {}", err),
+    }
+}
+
+#[tokio::test]
+async fn manages_networks_and_volumes() {
+    let daemon = daemon().await;
+    let docker = daemon.docker();
+
+    let network = docker
+        .networks()
+        .create(&NetworkCreateOptions::builder("backend").build())
+        .await
+        .unwrap();
+    let info = docker
+        .containers()
+        .create(&ContainerOptions::builder("busybox").build())
+        .await
+        .unwrap();
+    docker
+        .networks()
+        .get(&network.id)
+        .connect(&shiplift::ContainerConnectionOptions::builder(&info.id).build())
+        .await
+        .unwrap();
+    let details = docker.networks().get("backend").inspect().await.unwrap();
+    assert!(details.containers.contains_key(&info.id));
+
+    docker
+        .volumes()
+        .create(&VolumeCreateOptions::builder().name("data").build())
+        .await
+        .unwrap();
+    assert_eq!(docker.volumes().list().await.unwrap().len(), 1);
+    docker.volumes().get("data").delete().await.unwrap();
+    assert!(docker.volumes().list().await.unwrap().is_empty());
+}
+
+/// Connects to a fixed address regardless of the request uri
+#[derive(Clone)]
+struct FixedConnector(std::net::SocketAddr);
+
+impl hyper::service::Service<shiplift::Uri> for FixedConnector {
+    type Response = tokio::net::TcpStream;
+    type Error = std::io::Error;
+    type Future = std::pin::Pin<
+        Box<dyn std::future::Future<Output = std::io::Result<Self::Response>> + Send>,
+    >;
+
+    fn poll_ready(
+        &mut self,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn call(
+        &mut self,
+        _: shiplift::Uri,
+    ) -> Self::Future {
+        Box::pin(tokio::net::TcpStream::connect(self.0))
+    }
+}
+
+#[tokio::test]
+async fn runs_over_custom_connector() {
+    let daemon = daemon().await;
+    let addr = daemon.host().trim_start_matches("http://").parse().unwrap();
+    let docker =
+        shiplift::Docker::with_connector(FixedConnector(addr), "http://docker".parse().unwrap());
+
+    docker.ping().await.unwrap();
+    let info = docker
+        .containers()
+        .create(&ContainerOptions::builder("busybox").build())
+        .await
+        .unwrap();
+    let container = docker.containers().get(&info.id);
+    container.start().await.unwrap();
+
+    let (mut reader, mut writer) = container.attach().await.unwrap().split();
+    writer.write_all(b"over custom\n").await.unwrap();
+    match reader.next().await {
+        Some(Ok(TtyChunk::StdOut(bytes))) => assert_eq!(&bytes[..], b"over custom\n"),
+        other => panic!(
+            "unexpected chunk {:?}",
+            other.map(|c| c.map(|c| c.to_vec()))
+        ),
+    }
+}
+
+#[cfg(all(unix, feature = "ssh"))]
+#[tokio::test]
+async fn runs_over_ssh_command() {
+    let daemon = daemon().await;
+    let port = daemon.host().rsplit(':').next().unwrap().to_owned();
+    // stands in for `ssh host docker system dial-stdio` by relaying stdio to the mock daemon
+    let relay = format!("exec 3<>/dev/tcp/127.0.0.1/{}; cat <&3 & cat >&3", port);
+    let docker = shiplift::Docker::ssh(shiplift::ssh::SshConnector::with_command(
+        "bash",
+        vec!["-c", &relay],
+    ));
+
+    docker.ping().await.unwrap();
+    let info = docker
+        .containers()
+        .create(&ContainerOptions::builder("busybox").build())
+        .await
+        .unwrap();
+    let container = docker.containers().get(&info.id);
+    container.start().await.unwrap();
+
+    let (mut reader, mut writer) = container.attach().await.unwrap().split();
+    writer.write_all(b"over ssh\n").await.unwrap();
+    match reader.next().await {
+        Some(Ok(TtyChunk::StdOut(bytes))) => assert_eq!(&bytes[..], b"over ssh\n"),
+        other => panic!(
+            "unexpected chunk {:?}",
+            other.map(|c| c.map(|c| c.to_vec()))
+        ),
+    }
+}
+
+#[tokio::test]
+async fn builder_configures_requests() {
+    let daemon = daemon().await;
+    let docker = shiplift::Docker::builder()
+        .host(daemon.host())
+        .api_version(ApiVersion::new(1, 40))
+        .user_agent("deployer/1.0")
+        .header("X-Trace", "abc")
+        .timeout(std::time::Duration::from_millis(200))
+        .build()
+        .unwrap();
+
+    docker.version().await.unwrap();
+    assert_eq!(daemon.requests(), vec!["GET /v1.40/version"]);
+    let headers = &daemon.request_headers()[0];
+    assert_eq!(headers["user-agent"], "deployer/1.0");
+    assert_eq!(headers["x-trace"], "abc");
+
+    // waiting on a running container never completes
+    let info = docker
+        .containers()
+        .create(&ContainerOptions::builder("busybox").build())
+        .await
+        .unwrap();
+    let container = docker.containers().get(&info.id);
+    container.start().await.unwrap();
+    match container.wait().await.unwrap_err() {
+        shiplift::Error::IO(e) => assert_eq!(e.kind(), std::io::ErrorKind::TimedOut),
+        e => panic!("unexpected error {}", e),
+    }
+
+    daemon.docker().ping().await.unwrap();
+    let headers = daemon.request_headers().pop().unwrap();
+    assert!(headers["user-agent"]
+        .to_str()
+        .unwrap()
+        .starts_with("shiplift/"));
+}
+
+#[tokio::test]
+async fn negotiates_api_version() {
+    let daemon = daemon().await;
+    daemon.set_api_version(ApiVersion::new(1, 30));
+    let docker = daemon.docker().with_negotiated_api_version();
+
+    docker.containers().list(&Default::default()).await.unwrap();
+    assert_eq!(
+        docker.api_version().await.unwrap(),
+        Some(ApiVersion::new(1, 30))
+    );
+    assert_eq!(
+        daemon.requests(),
+        vec!["GET /_ping", "GET /v1.30/containers/json"]
+    );
+
+    // requests sent before the version is known wait for a single ping
+    let docker = daemon.docker().with_negotiated_api_version();
+    let containers = docker.containers();
+    let opts = Default::default();
+    let (first, second, third) = futures::join!(
+        containers.list(&opts),
+        containers.list(&opts),
+        docker.version(),
+    );
+    first.and(second).and(third).unwrap();
+    let requests = &daemon.requests()[2..];
+    assert_eq!(requests[0], "GET /_ping");
+    assert_eq!(requests.len(), 4);
+    assert!(requests[1..].iter().all(|r| r.starts_with("GET /v1.30/")));
+}
+
+#[tokio::test]
+async fn pinned_api_version_prefixes_requests() {
+    let daemon = daemon().await;
+    let docker = daemon.docker().with_api_version(ApiVersion::new(1, 25));
+
+    docker.version().await.unwrap();
+    assert_eq!(daemon.requests(), vec!["GET /v1.25/version"]);
+
+    let err = daemon
+        .docker()
+        .with_api_version(ApiVersion::new(1, 99))
+        .version()
+        .await
+        .unwrap_err();
+    assert!(matches!(err, shiplift::Error::Fault { code, .. } if code == 400));
+}
+
+#[tokio::test]
+async fn pinned_api_version_rejects_newer_endpoints() {
+    let daemon = daemon().await;
+    let docker = daemon.docker().with_api_version(ApiVersion::new(1, 23));
+
+    let err = docker
+        .services()
+        .list(&Default::default())
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        shiplift::Error::UnsupportedApiVersion { required, current, .. }
+            if required == ApiVersion::new(1, 24) && current == ApiVersion::new(1, 23)
+    ));
+    assert!(daemon.requests().is_empty());
+}